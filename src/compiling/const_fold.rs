@@ -0,0 +1,153 @@
+
+use crate::eval::data::*;
+use crate::eval::vm::{ Vm, GcConfig };
+
+/// Propagates literal locals through straight-line code and replaces pure ops on known values
+/// with their result.  A branch on a known Bool becomes a jump or a nop.  An op that would fail
+/// is left alone so that it still fails at runtime with a stack trace.
+pub fn fold(proc : &mut Proc) {
+    let instrs = &mut proc.instrs;
+
+    // Note:  Anything that can be jumped to might be reached with other values, so what is known
+    // about the locals is forgotten there.
+    let mut targets = vec![false; instrs.len() + 1];
+    for op in instrs.iter() {
+        if let Op::Jump(label) | Op::BranchTrue { label, .. } | Op::Try { label, .. } = op && *label < targets.len() {
+            targets[*label] = true;
+        }
+    }
+
+    let mut known : Vec<Option<RuntimeData>> = vec![None; proc.stack_size];
+    for ip in 0..instrs.len() {
+        if targets[ip] {
+            known.fill(None);
+        }
+
+        match &instrs[ip] {
+            Op::SetLocalVar { src, dest } if known[*src].is_some() => {
+                instrs[ip] = Op::SetLocalData(*dest, known[*src].clone().unwrap());
+            },
+            Op::BranchTrue { label, local } => match known[*local] {
+                Some(RuntimeData::Bool(true)) => { instrs[ip] = Op::Jump(*label); },
+                Some(RuntimeData::Bool(false)) => { instrs[ip] = Op::Nop; },
+                _ => { },
+            },
+            op if !targets[ip + 1] => {
+                if let Some(Op::SetLocalReturn(dest)) = instrs.get(ip + 1) && let Some(value) = eval(op, &known) {
+                    instrs[ip + 1] = Op::SetLocalData(*dest, value);
+                    instrs[ip] = Op::Nop;
+                }
+            },
+            _ => { },
+        }
+
+        match &instrs[ip] {
+            Op::SetLocalData(local, value) => { known[*local] = Some(value.clone()); },
+            Op::Jump(_) | Op::ReturnLocal(_) | Op::Break | Op::Throw(_) | Op::TailCall(..) | Op::DynTailCall(..) => { known.fill(None); },
+            op => if let Some(local) = op.writes() {
+                known[local] = None;
+            },
+        }
+    }
+}
+
+/// Runs a pure op on known values with the vm, so that folding gives exactly what the op would
+/// have given at runtime.  None when a value is unknown or the op fails.
+fn eval(op : &Op, known : &[Option<RuntimeData>]) -> Option<RuntimeData> {
+    let pure = matches!(op, Op::Add(..) | Op::Sub(..) | Op::Mul(..) | Op::Div(..) | Op::Mod(..) | Op::Neg(_)
+        | Op::AddWrapping(..) | Op::SubWrapping(..) | Op::MulWrapping(..) | Op::DivWrapping(..) | Op::ModWrapping(..) | Op::NegWrapping(_)
+        | Op::Eq(..) | Op::Gt(..) | Op::Lt(..) | Op::Not(_) | Op::And(..) | Op::Or(..) | Op::Xor(..) | Op::IsNil(_)
+        | Op::ToString(_) | Op::Concat(..) | Op::StrLength(_) | Op::Substring { .. } | Op::CharAt(..) | Op::IndexOf(..)
+        | Op::Upper(_) | Op::Lower(_) | Op::Trim(_) | Op::ParseInt(_) | Op::ParseFloat(_));
+    if !pure {
+        return None;
+    }
+
+    let args = op.locals().into_iter().map(|x| known[x].clone()).collect::<Option<Vec<_>>>()?;
+    let arity = args.len();
+
+    let mut op = op.clone();
+    for (i, local) in op.locals_mut().into_iter().enumerate() {
+        *local = i;
+    }
+
+    let proc = Proc { name: "fold".into(), instrs: vec![op, Op::SetLocalReturn(arity), Op::ReturnLocal(arity)], stack_size: arity + 1, debug: DebugInfo::default() };
+    Vm::new(vec![proc], vec![], GcConfig::default()).call(0, args).ok().flatten()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn proc(instrs : Vec<Op>) -> Proc {
+        Proc { name: "main".into(), instrs, stack_size: 3, debug: DebugInfo::default() }
+    }
+
+    #[test]
+    fn should_fold_arithmetic_on_literals() {
+        let mut input = proc(vec![
+            Op::SetLocalData(0, RuntimeData::Int(3)),
+            Op::SetLocalData(1, RuntimeData::Int(4)),
+            Op::Mul(0, 1),
+            Op::SetLocalReturn(2),
+            Op::Add(2, 2),
+            Op::SetLocalReturn(2),
+            Op::ReturnLocal(2),
+        ]);
+        fold(&mut input);
+        assert!(matches!(input.instrs[..], [_, _, Op::Nop, _, Op::Nop, Op::SetLocalData(2, RuntimeData::Int(24)), Op::ReturnLocal(2)]));
+    }
+
+    #[test]
+    fn should_propagate_through_copies() {
+        let mut input = proc(vec![
+            Op::SetLocalData(0, RuntimeData::Bool(true)),
+            Op::SetLocalVar { src: 0, dest: 1 },
+            Op::Not(1),
+            Op::SetLocalReturn(2),
+            Op::ReturnLocal(2),
+        ]);
+        fold(&mut input);
+        assert!(matches!(input.instrs[..], [_, Op::SetLocalData(1, RuntimeData::Bool(true)), Op::Nop, Op::SetLocalData(2, RuntimeData::Bool(false)), _]));
+    }
+
+    #[test]
+    fn should_not_fold_division_by_zero() {
+        let mut input = proc(vec![
+            Op::SetLocalData(0, RuntimeData::Int(3)),
+            Op::SetLocalData(1, RuntimeData::Int(0)),
+            Op::Div(0, 1),
+            Op::SetLocalReturn(2),
+            Op::ReturnLocal(2),
+        ]);
+        fold(&mut input);
+        assert!(matches!(input.instrs[..], [_, _, Op::Div(0, 1), Op::SetLocalReturn(2), _]));
+    }
+
+    #[test]
+    fn should_fold_branches_on_known_bools() {
+        let mut input = proc(vec![
+            Op::SetLocalData(0, RuntimeData::Bool(true)),
+            Op::SetLocalData(1, RuntimeData::Bool(false)),
+            Op::BranchTrue { label: 4, local: 1 },
+            Op::BranchTrue { label: 4, local: 0 },
+            Op::ReturnLocal(0),
+        ]);
+        fold(&mut input);
+        assert!(matches!(input.instrs[..], [_, _, Op::Nop, Op::Jump(4), _]));
+    }
+
+    #[test]
+    fn should_forget_values_at_jump_targets() {
+        let mut input = proc(vec![
+            Op::SetLocalData(0, RuntimeData::Int(1)),
+            Op::Nop,
+            Op::Neg(0),
+            Op::SetLocalReturn(0),
+            Op::BranchTrue { label: 1, local: 1 },
+            Op::ReturnLocal(0),
+        ]);
+        fold(&mut input);
+        assert!(matches!(input.instrs[2], Op::Neg(0)));
+    }
+}
@@ -4,8 +4,11 @@ use std::collections::{ HashSet, HashMap };
 
 use crate::parsing::ir_parser::{Lit, Expr, Type, Stmt, Proc as PProc};
 use crate::eval::data::*;
+use super::ir_verifier;
 
-type ProcMap<'a> = HashMap<Rc<str>, (&'a PProc, usize)>;
+/// Procs that are not pub are keyed by their file as well as their name, so that each file can
+/// have its own private helpers.
+type ProcMap<'a> = HashMap<(Option<Rc<str>>, Rc<str>), (&'a PProc, usize)>;
 type LMap = HashMap<Rc<str>, (Type, usize)>;
 type LabelMap = HashMap<Rc<str>, usize>;
 
@@ -17,6 +20,14 @@ pub enum CompileError {
     ProcCallArityMismatch { caller_proc: Rc<str>, callee_proc: Rc<str> },
     TypeMismatch { proc: Rc<str>, expected: Rc<str>, found : Rc<str> },
     ReuseParamName { proc: Rc<str>, param_name: Rc<str> },
+    DuplicateLabel { proc: Rc<str>, label: Rc<str> },
+    MissingReturn { proc: Rc<str> },
+    UninitialisedLocal { proc: Rc<str>, local: Rc<str>, instr: usize },
+    UnreachableInstr { proc: Rc<str>, instr: usize },
+    DuplicateProc { proc: Rc<str> },
+    PrivateProc { caller_proc: Rc<str>, callee_proc: Rc<str> },
+    NamespaceNotImported { proc: Rc<str>, namespace: Rc<str> },
+    CoroutineOnlyProc { caller_proc: Rc<str>, callee_proc: Rc<str> },
 }
 
 impl std::fmt::Display for CompileError {
@@ -34,6 +45,22 @@ impl std::fmt::Display for CompileError {
                 write!(f, "Type mismatch in proc {proc}:  Expected {expected}, but found {found}"),
             CompileError::ReuseParamName { proc, param_name } =>
                 write!(f, "Reuse param name {param_name} in proc {proc}"),
+            CompileError::DuplicateLabel { proc, label } =>
+                write!(f, "Duplicate label {label} in proc {proc}"),
+            CompileError::MissingReturn { proc } =>
+                write!(f, "Proc {proc} can reach its end without a return, break or throw"),
+            CompileError::UninitialisedLocal { proc, local, instr } =>
+                write!(f, "Local {local} may be read before it is set at instr {instr} in proc {proc}"),
+            CompileError::UnreachableInstr { proc, instr } =>
+                write!(f, "Unreachable instr {instr} in proc {proc}"),
+            CompileError::DuplicateProc { proc } =>
+                write!(f, "Duplicate proc {proc}"),
+            CompileError::PrivateProc { caller_proc, callee_proc } =>
+                write!(f, "Proc {callee_proc} is not pub, so it cannot be called from proc {caller_proc}"),
+            CompileError::NamespaceNotImported { proc, namespace } =>
+                write!(f, "Namespace {namespace} is used in proc {proc} without being imported"),
+            CompileError::CoroutineOnlyProc { caller_proc, callee_proc } =>
+                write!(f, "Proc {callee_proc} can only be started with coroutine, but proc {caller_proc} uses it another way"),
         }
     }
 }
@@ -41,17 +68,79 @@ impl std::fmt::Display for CompileError {
 impl std::error::Error for CompileError { }
 
 
-pub fn compile(procs : &[PProc]) -> Result<Vec<Proc>, CompileError> {
+/// Natives are signatures only.  Each one is given a wrapper proc that dispatches to the
+/// native function with the same index in the vm's native table.
+pub fn compile(procs : &[PProc], natives : &[PProc]) -> Result<Vec<Proc>, CompileError> {
     let (op_sigs, mut op_code) = primitive_ops();
 
-    let proc_map = HashMap::from_iter(op_sigs.iter().chain(procs.iter()).enumerate().map(|(v, k)| (Rc::clone(&k.name), (k, v))));
-    let mut compiled = procs.into_iter().map(|x| compile_proc(x, &proc_map)).collect::<Result<Vec<_>, _>>()?;
+    let mut proc_map : ProcMap = HashMap::new();
+    let sigs = op_sigs.iter().chain(natives.iter()).chain(procs.iter()).enumerate();
+    // Note:  Public procs go in first so that a private proc with the same name as one of them is
+    // a duplicate whichever order the files were loaded in.
+    for (index, sig) in sigs.clone().filter(|(_, x)| x.public).chain(sigs.filter(|(_, x)| !x.public)) {
+        let public = (None, Rc::clone(&sig.name));
+        if proc_map.contains_key(&public) || proc_map.insert((scope(sig), Rc::clone(&sig.name)), (sig, index)).is_some() {
+            return Err(CompileError::DuplicateProc { proc: Rc::clone(&sig.name) });
+        }
+    }
+    let mut native_code = natives.iter().enumerate().map(|(i, x)| native_proc(i, x)).collect::<Vec<_>>();
+    let mut compiled = procs.iter().map(|x| compile_proc(x, &proc_map)).collect::<Result<Vec<_>, _>>()?;
+
+    for code in &mut compiled {
+        inline_primitives(code, &op_code);
+    }
+
+    for (proc, code) in procs.iter().zip(&compiled) {
+        ir_verifier::verify(code, proc.params.len())?;
+    }
     
+    op_code.append(&mut native_code);
     op_code.append(&mut compiled);
     
     Ok(op_code)
 }
 
+fn scope(proc : &PProc) -> Option<Rc<str>> {
+    if proc.public { None } else { Some(file(proc)) }
+}
+
+fn file(proc : &PProc) -> Rc<str> {
+    proc.source.as_ref().map_or_else(|| "".into(), |x| Rc::clone(&x.file))
+}
+
+/// Replaces calls to primitive procs with the primitive's op run directly against the caller's
+/// locals.  The set and return that follow the call are left as they are.
+fn inline_primitives(proc : &mut Proc, primitives : &[Proc]) {
+    for (ip, op) in proc.instrs.iter_mut().enumerate() {
+        let (proc_id, args, tail) = match op {
+            Op::Call(proc_id, args) => (*proc_id, args, false),
+            Op::TailCall(proc_id, args) => (*proc_id, args, true),
+            _ => { continue; },
+        };
+
+        let Some(mut inlined) = primitives.get(proc_id).and_then(|x| inline_body(x, args.len())) else { continue; };
+        for local in inlined.locals_mut() {
+            *local = args[*local];
+        }
+        *op = inlined;
+        proc.debug.inlined.push(Inlined { instr: ip, proc_id, tail });
+    }
+}
+
+/// The single op of a primitive built by uni, bin or tri.
+fn inline_body(proc : &Proc, arity : usize) -> Option<Op> {
+    match &proc.instrs[..] {
+        [op, Op::SetLocalReturn(a), Op::ReturnLocal(b)] if *a == arity && *b == arity && op.locals().iter().all(|x| *x < arity) => Some(op.clone()),
+        _ => None,
+    }
+}
+
+fn native_proc(native_id : usize, sig : &PProc) -> Proc {
+    let arity = sig.params.len();
+    let instrs = vec![Op::CallNative(native_id, (0..arity).collect()), Op::SetLocalReturn(arity), Op::ReturnLocal(arity)];
+    Proc { name: Rc::clone(&sig.name), instrs, stack_size: arity + 1, debug: DebugInfo::default() }
+}
+
 fn compile_proc(proc : &PProc, proc_map : &ProcMap) -> Result<Proc, CompileError> {
 
     let mut l_map : LMap = {
@@ -82,6 +171,13 @@ fn compile_proc(proc : &PProc, proc_map : &ProcMap) -> Result<Proc, CompileError
             .map(|(i, (name, ttype))| (Rc::clone(&name), (ttype, i))))
     };
 
+    let mut labels = HashSet::new();
+    for stmt in &proc.body {
+        if let Stmt::Label(label) = stmt && !labels.insert(Rc::clone(label)) {
+            return Err(CompileError::DuplicateLabel { proc: Rc::clone(&proc.name), label: Rc::clone(label) });
+        }
+    }
+
     let mut stmts = vec![];
     for stmt in &proc.body {
         stmts.push(compile_stmt(proc, stmt, proc_map, &mut l_map)?);
@@ -92,7 +188,9 @@ fn compile_proc(proc : &PProc, proc_map : &ProcMap) -> Result<Proc, CompileError
         _ => None,
     }));
 
-    let instrs = stmts.into_iter().flatten().map(|op| match op {
+    let spans = stmts.iter().zip(proc.spans.iter()).flat_map(|(ops, span)| ops.iter().map(|_| *span)).collect::<Vec<_>>();
+
+    let mut instrs = stmts.into_iter().flatten().map(|op| match op {
         LOp::Op(x) => Ok(x),
         LOp::Label(_) => Ok(Op::Nop),
         LOp::Branch { label, var } if label_map.contains_key(&label) => {
@@ -102,10 +200,78 @@ fn compile_proc(proc : &PProc, proc_map : &ProcMap) -> Result<Proc, CompileError
         LOp::Branch { label, .. } => Err(CompileError::AccessMissingLabel { proc: Rc::clone(&proc.name), label }),
         LOp::Jump(x) if label_map.contains_key(&x) => Ok(Op::Jump(*label_map.get(&x).unwrap())),
         LOp::Jump(x) => Err(CompileError::AccessMissingLabel { proc: Rc::clone(&proc.name), label: x}),
+        LOp::Try { label, var } if label_map.contains_key(&label) => {
+            let local = any_access(&l_map, &var, &proc.name)?;
+            Ok(Op::Try { local, label: *label_map.get(&label).unwrap() })
+        },
+        LOp::Try { label, .. } => Err(CompileError::AccessMissingLabel { proc: Rc::clone(&proc.name), label }),
     }).collect::<Result<Vec<_>, CompileError>>()?;
 
+    // Note:  Procs that yield keep their calls as is.  A yield inside of a tail called proc would
+    // leave the coroutine instead of returning to it.
+    if !proc.body.iter().any(|x| matches!(x, Stmt::Yield(_) | Stmt::Break)) {
+        tail_calls(&mut instrs);
+    }
+
     let stack_size = l_map.values().map(|(_, x)| *x + 1).max().unwrap_or(0);
-    Ok(Proc { name: Rc::clone(&proc.name), instrs, stack_size })
+
+    let mut locals = vec![Rc::from(""); stack_size];
+    for (name, (_, index)) in &l_map {
+        locals[*index] = Rc::clone(name);
+    }
+
+    let mut labels = label_map.into_iter().collect::<Vec<_>>();
+    labels.sort_by_key(|(_, index)| *index);
+
+    let debug = DebugInfo { source: proc.source.clone(), spans, locals, labels, inlined: vec![] };
+    Ok(Proc { name: Rc::clone(&proc.name), instrs, stack_size, debug })
+}
+
+/// Turns calls whose result is immediately returned into tail calls.  The set and return ops are
+/// left in place so that instr indices and spans do not move.  Calls made while a try handler
+/// may be active are kept so that the handler is still on the stack when the callee throws.
+fn tail_calls(instrs : &mut [Op]) {
+    let depths = handler_depths(instrs);
+    for ip in 0..instrs.len().saturating_sub(2) {
+        if let [Op::SetLocalReturn(dest), Op::ReturnLocal(ret)] = instrs[ip + 1..ip + 3] && dest == ret && depths[ip] == Some(0) {
+            instrs[ip] = match std::mem::replace(&mut instrs[ip], Op::Nop) {
+                Op::Call(proc_id, params) => Op::TailCall(proc_id, params),
+                Op::DynCall(local, params) => Op::DynTailCall(local, params),
+                x => x,
+            };
+        }
+    }
+}
+
+/// How many try handlers may be active at each instr, or None when the instr is unreachable.
+/// A depth that can keep growing, like a try in a loop without an end_try, becomes usize::MAX.
+fn handler_depths(instrs : &[Op]) -> Vec<Option<usize>> {
+    let limit = instrs.iter().filter(|x| matches!(x, Op::Try { .. })).count();
+    let mut depths = vec![None; instrs.len()];
+    let mut work = vec![];
+    if !instrs.is_empty() {
+        depths[0] = Some(0);
+        work.push(0);
+    }
+
+    while let Some(ip) = work.pop() {
+        let depth = depths[ip].unwrap();
+        for (target, set) in ir_verifier::successors(ip, &instrs[ip]) {
+            // Note:  The handler is popped when a throw reaches it, so only the fall through
+            // from a try is inside of the region.
+            let next = match (&instrs[ip], set) {
+                (Op::Try { .. }, None) if depth >= limit => usize::MAX,
+                (Op::Try { .. }, None) => depth + 1,
+                (Op::EndTry, _) if depth != usize::MAX => depth.saturating_sub(1),
+                _ => depth,
+            };
+            if let Some(d) = depths.get_mut(target) && d.is_none_or(|x| x < next) {
+                *d = Some(next);
+                work.push(target);
+            }
+        }
+    }
+    depths
 }
 
 enum LOp {
@@ -113,6 +279,7 @@ enum LOp {
     Label(Rc<str>),
     Branch { label: Rc<str>, var: Rc<str> },
     Jump(Rc<str>),
+    Try { label: Rc<str>, var: Rc<str> },
 }
 
 fn access(l_map: &LMap, local: &Rc<str>, proc_name: &Rc<str>, expected_type: &Type) -> Result<usize, CompileError> {
@@ -138,10 +305,38 @@ fn compile_stmt(proc: &PProc, stmt : &Stmt, proc_map : &ProcMap, l_map : &mut LM
     
     fn s(x : Op) -> Result<Vec<LOp>, CompileError> { Ok(vec![LOp::Op(x)]) }
 
-    fn c<'a, 'b>(proc_map: &'b ProcMap<'a>, caller_proc_name: &Rc<str>, callee_proc_name: &Rc<str>) -> Result<&'b (&'a PProc, usize), CompileError> {
-        match proc_map.get(callee_proc_name) {
-            Some(t) => Ok(t),
-            None => Err(CompileError::AccessMissingProc { caller_proc: Rc::clone(caller_proc_name), callee_proc: Rc::clone(callee_proc_name) }),
+    // Note:  map_keys yields and breaks, which only works in a coroutine frame.  Rejecting it as
+    // a closure also keeps it away from dyn_call.
+    fn not_coroutine_only(caller_proc : &PProc, callee_proc : &PProc) -> Result<(), CompileError> {
+        if &*callee_proc.name == "map_keys" {
+            return Err(CompileError::CoroutineOnlyProc { caller_proc: Rc::clone(&caller_proc.name), callee_proc: Rc::clone(&callee_proc.name) });
+        }
+        Ok(())
+    }
+
+    fn c<'a, 'b>(proc_map: &'b ProcMap<'a>, caller_proc: &PProc, callee_proc_name: &Rc<str>) -> Result<&'b (&'a PProc, usize), CompileError> {
+        let namespace = caller_proc.name.split_once("::").map(|(x, _)| x);
+        let names : Vec<Rc<str>> = match callee_proc_name.split_once("::") {
+            Some((x, _)) if Some(x) != namespace && !caller_proc.imports.iter().any(|y| **y == *x) => {
+                return Err(CompileError::NamespaceNotImported { proc: Rc::clone(&caller_proc.name), namespace: x.into() });
+            },
+            Some(_) => vec![Rc::clone(callee_proc_name)],
+            // Note:  An unqualified name is looked for in the caller's own namespace first, and
+            // then with the root procs, primitives and natives.
+            None => namespace.map(|x| format!("{x}::{callee_proc_name}").into()).into_iter().chain([Rc::clone(callee_proc_name)]).collect(),
+        };
+
+        let caller_file = file(caller_proc);
+        let found = names.iter().find_map(|x| proc_map.get(&(Some(Rc::clone(&caller_file)), Rc::clone(x)))
+                                                .or_else(|| proc_map.get(&(None, Rc::clone(x)))));
+        if let Some(t) = found {
+            return Ok(t);
+        }
+
+        match proc_map.iter().find(|((_, name), _)| names.contains(name)) {
+            Some((_, (callee_proc, _))) =>
+                Err(CompileError::PrivateProc { caller_proc: Rc::clone(&caller_proc.name), callee_proc: Rc::clone(&callee_proc.name) }),
+            None => Err(CompileError::AccessMissingProc { caller_proc: Rc::clone(&caller_proc.name), callee_proc: Rc::clone(callee_proc_name) }),
         }
     }
 
@@ -149,6 +344,9 @@ fn compile_stmt(proc: &PProc, stmt : &Stmt, proc_map : &ProcMap, l_map : &mut LM
         Stmt::Jump(x) => Ok(vec![LOp::Jump(Rc::clone(x))]),
         Stmt::BranchTrue { label, var } => Ok(vec![LOp::Branch { label: Rc::clone(label), var: Rc::clone(var) }]),
         Stmt::Label(x) => Ok(vec![LOp::Label(Rc::clone(x))]),
+        Stmt::Try { label, var } => Ok(vec![LOp::Try { label: Rc::clone(label), var: Rc::clone(var) }]),
+        Stmt::EndTry => s(Op::EndTry),
+        Stmt::Throw(local) => s(Op::Throw(any_access(l_map, local, &proc.name)?)),
         Stmt::Return(local) => s(Op::ReturnLocal(access(l_map, local, &proc.name, &proc.return_type)?)),
         Stmt::Set { var, val: Expr::Lit(Lit::Int(x)), .. } => s(Op::SetLocalData(access(l_map, &var, &proc.name, &Type::Int)?, RuntimeData::Int(*x))),
         Stmt::Set { var, val: Expr::Lit(Lit::Float(x)), .. } => s(Op::SetLocalData(access(l_map, &var, &proc.name, &Type::Float)?, RuntimeData::Float(*x))),
@@ -164,7 +362,8 @@ fn compile_stmt(proc: &PProc, stmt : &Stmt, proc_map : &ProcMap, l_map : &mut LM
                     ] )
         },
         Stmt::Set { var, val: Expr::Call { name, params }, .. } => {
-            let (callee_proc, callee_index) = c(proc_map, &proc.name, name)?;
+            let (callee_proc, callee_index) = c(proc_map, proc, name)?;
+            not_coroutine_only(proc, callee_proc)?;
             let local_index = access(l_map, &var, &proc.name, &callee_proc.return_type)?;
 
             if params.len() != callee_proc.params.len() {
@@ -190,7 +389,8 @@ fn compile_stmt(proc: &PProc, stmt : &Stmt, proc_map : &ProcMap, l_map : &mut LM
                     ])
         },
         Stmt::Set { var, val: Expr::Closure { name, env }, .. } => {
-            let (callee_proc, callee_index) = c(proc_map, &proc.name, name)?;
+            let (callee_proc, callee_index) = c(proc_map, proc, name)?;
+            not_coroutine_only(proc, callee_proc)?;
             let dest = access(l_map, &var, &proc.name, &Type::Closure)?;
 
             if env.len() > callee_proc.params.len() {
@@ -219,6 +419,14 @@ fn compile_stmt(proc: &PProc, stmt : &Stmt, proc_map : &ProcMap, l_map : &mut LM
             Ok(vec![LOp::Op(Op::GetSlot { local: src, index: *index }),
                     LOp::Op(Op::SetLocalReturn(dest))])
         },
+        Stmt::Set { var: dest, val: Expr::DynSlot { var: src, index }, .. } => {
+            let src = access(l_map, src, &proc.name, &Type::Ref)?; 
+            let index = access(l_map, index, &proc.name, &Type::Int)?; 
+            let dest = any_access(l_map, dest, &proc.name)?;
+
+            Ok(vec![LOp::Op(Op::DynGetSlot { local: src, index }),
+                    LOp::Op(Op::SetLocalReturn(dest))])
+        },
         Stmt::Set { var: dest, val: Expr::Length(src), .. } => {
             let src = access(l_map, &src, &proc.name, &Type::Ref)?; 
             let dest = access(l_map, &dest, &proc.name, &Type::Int)?;
@@ -234,7 +442,7 @@ fn compile_stmt(proc: &PProc, stmt : &Stmt, proc_map : &ProcMap, l_map : &mut LM
                     LOp::Op(Op::SetLocalReturn(dest))])
         },
         Stmt::Set { var: dest, val: Expr::Coroutine { name, params }, .. } => {
-            let (callee_proc, callee_index) = c(proc_map, &proc.name, name)?;
+            let (callee_proc, callee_index) = c(proc_map, proc, name)?;
             let dest = access(l_map, &dest, &proc.name, &Type::Coroutine)?;
 
             if params.len() != callee_proc.params.len() {
@@ -297,76 +505,255 @@ fn compile_stmt(proc: &PProc, stmt : &Stmt, proc_map : &ProcMap, l_map : &mut LM
 
             s(Op::RemoveSlot { local, index: *index })
         },
+        Stmt::DynSlotInsert { var, input, index } => {
+            let src = any_access(l_map, input, &proc.name)?;
+            let index = access(l_map, index, &proc.name, &Type::Int)?;
+            let dest = access(l_map, var, &proc.name, &Type::Ref)?;
+
+            s(Op::DynInsertSlot { dest, src, index })
+        },
+        Stmt::DynSlotRemove { var, index } => {
+            let index = access(l_map, index, &proc.name, &Type::Int)?;
+            let local = access(l_map, var, &proc.name, &Type::Ref)?;
+
+            s(Op::DynRemoveSlot { local, index })
+        },
+        Stmt::Set { var: dest, val: Expr::Array(params), .. } => {
+            let params = params.iter().map(|x| any_access(l_map, x, &proc.name)).collect::<Result<Vec<_>, _>>()?;
+            let dest = access(l_map, dest, &proc.name, &Type::Ref)?;
+
+            Ok(vec![LOp::Op(Op::NewArray(params)),
+                    LOp::Op(Op::SetLocalReturn(dest))])
+        },
+        Stmt::Set { var: dest, val: Expr::ArrayPop(local), .. } => {
+            let local = access(l_map, local, &proc.name, &Type::Ref)?;
+            let dest = any_access(l_map, dest, &proc.name)?;
+
+            Ok(vec![LOp::Op(Op::ArrayPop(local)),
+                    LOp::Op(Op::SetLocalReturn(dest))])
+        },
+        Stmt::Set { var: dest, val: Expr::ArrayGet { var, index }, .. } => {
+            let local = access(l_map, var, &proc.name, &Type::Ref)?;
+            let index = access(l_map, index, &proc.name, &Type::Int)?;
+            let dest = any_access(l_map, dest, &proc.name)?;
+
+            Ok(vec![LOp::Op(Op::ArrayGet { local, index }),
+                    LOp::Op(Op::SetLocalReturn(dest))])
+        },
+        Stmt::ArrayPush { var, input } => {
+            let src = any_access(l_map, input, &proc.name)?;
+            let dest = access(l_map, var, &proc.name, &Type::Ref)?;
+
+            s(Op::ArrayPush { dest, src })
+        },
+        Stmt::ArraySet { var, index, input } => {
+            let src = any_access(l_map, input, &proc.name)?;
+            let index = access(l_map, index, &proc.name, &Type::Int)?;
+            let dest = access(l_map, var, &proc.name, &Type::Ref)?;
+
+            s(Op::ArraySet { dest, index, src })
+        },
+        Stmt::Set { var: dest, val: Expr::MapNew, .. } => {
+            let dest = access(l_map, dest, &proc.name, &Type::Ref)?;
+
+            Ok(vec![LOp::Op(Op::NewMap),
+                    LOp::Op(Op::SetLocalReturn(dest))])
+        },
+        Stmt::Set { var: dest, val: Expr::MapGet { var, key }, .. } => {
+            let local = access(l_map, var, &proc.name, &Type::Ref)?;
+            let key = any_access(l_map, key, &proc.name)?;
+            let dest = any_access(l_map, dest, &proc.name)?;
+
+            Ok(vec![LOp::Op(Op::MapGet { local, key }),
+                    LOp::Op(Op::SetLocalReturn(dest))])
+        },
+        Stmt::Set { var: dest, val: Expr::MapContains { var, key }, .. } => {
+            let local = access(l_map, var, &proc.name, &Type::Ref)?;
+            let key = any_access(l_map, key, &proc.name)?;
+            let dest = access(l_map, dest, &proc.name, &Type::Bool)?;
+
+            Ok(vec![LOp::Op(Op::MapContains { local, key }),
+                    LOp::Op(Op::SetLocalReturn(dest))])
+        },
+        Stmt::MapInsert { var, key, input } => {
+            let src = any_access(l_map, input, &proc.name)?;
+            let key = any_access(l_map, key, &proc.name)?;
+            let dest = access(l_map, var, &proc.name, &Type::Ref)?;
+
+            s(Op::MapInsert { dest, key, src })
+        },
+        Stmt::MapRemove { var, key } => {
+            let key = any_access(l_map, key, &proc.name)?;
+            let local = access(l_map, var, &proc.name, &Type::Ref)?;
+
+            s(Op::MapRemove { local, key })
+        },
         Stmt::Delete(local) => s(Op::Delete(access(l_map, local, &proc.name, &Type::Ref)?)),
         Stmt::Break => s(Op::Break),
         Stmt::Yield(local) => s(Op::Yield(access(l_map, local, &proc.name, &proc.return_type)?)),
     }
 }
 
+/// Body of the map_keys coroutine.  The keys are copied into an array on the first resume, so 
+/// changing the map while iterating does not change what is yielded.
+fn map_keys() -> Vec<Op> {
+    // Note:  locals are 0 map, 1 keys, 2 index, 3 length, 4 done, 5 one, 6 key
+    vec![
+        Op::MapKeys(0),
+        Op::SetLocalReturn(1),
+        Op::SetLocalData(2, RuntimeData::Int(0)),
+        Op::SetLocalData(5, RuntimeData::Int(1)),
+        Op::GetLength(1),
+        Op::SetLocalReturn(3),
+        Op::Eq(2, 3),
+        Op::SetLocalReturn(4),
+        Op::BranchTrue { label: 15, local: 4 },
+        Op::ArrayGet { local: 1, index: 2 },
+        Op::SetLocalReturn(6),
+        Op::Yield(6),
+        Op::Add(2, 5),
+        Op::SetLocalReturn(2),
+        Op::Jump(6),
+        Op::Break,
+    ]
+}
+
+/// Number of primitive procs at the front of every compiled program.
+pub fn primitive_count() -> usize {
+    primitive_ops().1.len()
+}
+
 fn primitive_ops() -> (Vec<PProc>, Vec<Proc>) {
     fn bin(input : Op) -> Vec<Op> { vec![input, Op::SetLocalReturn(2), Op::ReturnLocal(2)] }
     fn uni(input : Op) -> Vec<Op> { vec![input, Op::SetLocalReturn(1), Op::ReturnLocal(1)] }
+    fn tri(input : Op) -> Vec<Op> { vec![input, Op::SetLocalReturn(3), Op::ReturnLocal(3)] }
+    fn primitive(name : &str, params : &[(&str, Type)], return_type : Type) -> PProc {
+        let params = params.iter().map(|(x, t)| (Rc::from(*x), *t)).collect();
+        PProc { name: name.into(), params, return_type, body: vec![], spans: vec![], source: None, public: true, imports: vec![] }
+    }
 
     let sigs = vec![ 
-        PProc { name: "add_float".into(), params: vec![("a".into(), Type::Float), ("b".into(), Type::Float)], return_type: Type::Float, body: vec![] },
-        PProc { name: "add_int".into(), params: vec![("a".into(), Type::Int), ("b".into(), Type::Int)], return_type: Type::Int, body: vec![] },
-        PProc { name: "sub_float".into(), params: vec![("a".into(), Type::Float), ("b".into(), Type::Float)], return_type: Type::Float, body: vec![] },
-        PProc { name: "sub_int".into(), params: vec![("a".into(), Type::Int), ("b".into(), Type::Int)], return_type: Type::Int, body: vec![] },
-        PProc { name: "mul_float".into(), params: vec![("a".into(), Type::Float), ("b".into(), Type::Float)], return_type: Type::Float, body: vec![] },
-        PProc { name: "mul_int".into(), params: vec![("a".into(), Type::Int), ("b".into(), Type::Int)], return_type: Type::Int, body: vec![] },
-        PProc { name: "div_float".into(), params: vec![("a".into(), Type::Float), ("b".into(), Type::Float)], return_type: Type::Float, body: vec![] },
-        PProc { name: "div_int".into(), params: vec![("a".into(), Type::Int), ("b".into(), Type::Int)], return_type: Type::Int, body: vec![] },
-        PProc { name: "mod_float".into(), params: vec![("a".into(), Type::Float), ("b".into(), Type::Float)], return_type: Type::Float, body: vec![] },
-        PProc { name: "mod_int".into(), params: vec![("a".into(), Type::Int), ("b".into(), Type::Int)], return_type: Type::Int, body: vec![] },
-        PProc { name: "neg_float".into(), params: vec![("a".into(), Type::Float)], return_type: Type::Float, body: vec![] },
-        PProc { name: "neg_int".into(), params: vec![("a".into(), Type::Int)], return_type: Type::Int, body: vec![] },
-
-        PProc { name: "and".into(), params: vec![("a".into(), Type::Bool), ("b".into(), Type::Bool)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "or".into(), params: vec![("a".into(), Type::Bool), ("b".into(), Type::Bool)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "xor".into(), params: vec![("a".into(), Type::Bool), ("b".into(), Type::Bool)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "not".into(), params: vec![("a".into(), Type::Bool)], return_type: Type::Bool, body: vec![] },
-
-        PProc { name: "gt_float".into(), params: vec![("a".into(), Type::Float), ("b".into(), Type::Float)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "gt_int".into(), params: vec![("a".into(), Type::Int), ("b".into(), Type::Int)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "lt_float".into(), params: vec![("a".into(), Type::Float), ("b".into(), Type::Float)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "lt_int".into(), params: vec![("a".into(), Type::Int), ("b".into(), Type::Int)], return_type: Type::Bool, body: vec![] },
-
-        PProc { name: "eq_float".into(), params: vec![("a".into(), Type::Float), ("b".into(), Type::Float)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "eq_int".into(), params: vec![("a".into(), Type::Int), ("b".into(), Type::Int)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "eq_bool".into(), params: vec![("a".into(), Type::Bool), ("b".into(), Type::Bool)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "eq_symbol".into(), params: vec![("a".into(), Type::Symbol), ("b".into(), Type::Symbol)], return_type: Type::Bool, body: vec![] },
-        PProc { name: "eq_ref".into(), params: vec![("a".into(), Type::Ref), ("b".into(), Type::Ref)], return_type: Type::Bool, body: vec![] },
+        primitive("add_float", &[("a", Type::Float), ("b", Type::Float)], Type::Float),
+        primitive("add_int", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("sub_float", &[("a", Type::Float), ("b", Type::Float)], Type::Float),
+        primitive("sub_int", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("mul_float", &[("a", Type::Float), ("b", Type::Float)], Type::Float),
+        primitive("mul_int", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("div_float", &[("a", Type::Float), ("b", Type::Float)], Type::Float),
+        primitive("div_int", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("mod_float", &[("a", Type::Float), ("b", Type::Float)], Type::Float),
+        primitive("mod_int", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("neg_float", &[("a", Type::Float)], Type::Float),
+        primitive("neg_int", &[("a", Type::Int)], Type::Int),
+
+        primitive("add_int_wrapping", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("sub_int_wrapping", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("mul_int_wrapping", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("div_int_wrapping", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("mod_int_wrapping", &[("a", Type::Int), ("b", Type::Int)], Type::Int),
+        primitive("neg_int_wrapping", &[("a", Type::Int)], Type::Int),
+
+        primitive("and", &[("a", Type::Bool), ("b", Type::Bool)], Type::Bool),
+        primitive("or", &[("a", Type::Bool), ("b", Type::Bool)], Type::Bool),
+        primitive("xor", &[("a", Type::Bool), ("b", Type::Bool)], Type::Bool),
+        primitive("not", &[("a", Type::Bool)], Type::Bool),
+
+        primitive("gt_float", &[("a", Type::Float), ("b", Type::Float)], Type::Bool),
+        primitive("gt_int", &[("a", Type::Int), ("b", Type::Int)], Type::Bool),
+        primitive("lt_float", &[("a", Type::Float), ("b", Type::Float)], Type::Bool),
+        primitive("lt_int", &[("a", Type::Int), ("b", Type::Int)], Type::Bool),
+
+        primitive("eq_float", &[("a", Type::Float), ("b", Type::Float)], Type::Bool),
+        primitive("eq_int", &[("a", Type::Int), ("b", Type::Int)], Type::Bool),
+        primitive("eq_bool", &[("a", Type::Bool), ("b", Type::Bool)], Type::Bool),
+        primitive("eq_symbol", &[("a", Type::Symbol), ("b", Type::Symbol)], Type::Bool),
+        primitive("eq_ref", &[("a", Type::Ref), ("b", Type::Ref)], Type::Bool),
+        primitive("eq_string", &[("a", Type::String), ("b", Type::String)], Type::Bool),
+        primitive("gt_string", &[("a", Type::String), ("b", Type::String)], Type::Bool),
+        primitive("lt_string", &[("a", Type::String), ("b", Type::String)], Type::Bool),
+
+        primitive("length_string", &[("s", Type::String)], Type::Int),
+        primitive("substring", &[("s", Type::String), ("start", Type::Int), ("end", Type::Int)], Type::String),
+        primitive("char_at", &[("s", Type::String), ("index", Type::Int)], Type::String),
+        primitive("index_of", &[("s", Type::String), ("needle", Type::String)], Type::Int),
+        primitive("split", &[("s", Type::String), ("sep", Type::String)], Type::Ref),
+        primitive("upper", &[("s", Type::String)], Type::String),
+        primitive("lower", &[("s", Type::String)], Type::String),
+        primitive("trim", &[("s", Type::String)], Type::String),
+        primitive("parse_int", &[("s", Type::String)], Type::Int),
+        primitive("parse_float", &[("s", Type::String)], Type::Float),
+
+        primitive("print", &[("s", Type::String)], Type::Int),
+        primitive("print_line", &[("s", Type::String)], Type::Int),
+        primitive("read_line", &[], Type::String),
+        primitive("read_file", &[("path", Type::String)], Type::String),
+        primitive("write_file", &[("path", Type::String), ("contents", Type::String)], Type::Int),
+
+        // Note:  The keys come out of resume, which is untyped, so the only thing map_keys gives
+        // its caller directly is the coroutine.
+        primitive("map_keys", &[("m", Type::Ref)], Type::Coroutine),
     ];
 
     let code = vec![ 
-        Proc { name: "add_float".into(), instrs: bin(Op::Add(0, 1)), stack_size: 3 },
-        Proc { name: "add_int".into(), instrs: bin(Op::Add(0, 1)), stack_size: 3 },
-        Proc { name: "sub_float".into(), instrs: bin(Op::Sub(0, 1)), stack_size: 3 },
-        Proc { name: "sub_int".into(), instrs: bin(Op::Sub(0, 1)), stack_size: 3 },
-        Proc { name: "mul_float".into(), instrs: bin(Op::Mul(0, 1)), stack_size: 3 },
-        Proc { name: "mul_int".into(), instrs: bin(Op::Mul(0, 1)), stack_size: 3 },
-        Proc { name: "div_float".into(), instrs: bin(Op::Div(0, 1)), stack_size: 3 },
-        Proc { name: "div_int".into(), instrs: bin(Op::Div(0, 1)), stack_size: 3 },
-        Proc { name: "mod_float".into(), instrs: bin(Op::Mod(0, 1)), stack_size: 3 },
-        Proc { name: "mod_int".into(), instrs: bin(Op::Mod(0, 1)), stack_size: 3 },
-        Proc { name: "neg_float".into(), instrs: uni(Op::Neg(0)), stack_size: 2 },
-        Proc { name: "neg_int".into(), instrs: uni(Op::Neg(0)), stack_size: 2 },
-
-        Proc { name: "and".into(), instrs: bin(Op::And(0, 1)), stack_size: 3 },
-        Proc { name: "or".into(), instrs: bin(Op::Or(0, 1)), stack_size: 3 },
-        Proc { name: "xor".into(), instrs: bin(Op::Xor(0, 1)), stack_size: 3 },
-        Proc { name: "not".into(), instrs: uni(Op::Not(0)), stack_size: 2 },
-
-        Proc { name: "gt_float".into(), instrs: bin(Op::Gt(0, 1)), stack_size: 3 },
-        Proc { name: "gt_int".into(), instrs: bin(Op::Gt(0, 1)), stack_size: 3 },
-        Proc { name: "lt_float".into(), instrs: bin(Op::Lt(0, 1)), stack_size: 3 },
-        Proc { name: "lt_int".into(), instrs: bin(Op::Lt(0, 1)), stack_size: 3 },
-
-        Proc { name: "eq_float".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3 },
-        Proc { name: "eq_int".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3 },
-        Proc { name: "eq_bool".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3 },
-        Proc { name: "eq_symbol".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3 },
-        Proc { name: "eq_ref".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3 },
+        Proc { name: "add_float".into(), instrs: bin(Op::Add(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "add_int".into(), instrs: bin(Op::Add(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "sub_float".into(), instrs: bin(Op::Sub(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "sub_int".into(), instrs: bin(Op::Sub(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "mul_float".into(), instrs: bin(Op::Mul(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "mul_int".into(), instrs: bin(Op::Mul(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "div_float".into(), instrs: bin(Op::Div(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "div_int".into(), instrs: bin(Op::Div(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "mod_float".into(), instrs: bin(Op::Mod(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "mod_int".into(), instrs: bin(Op::Mod(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "neg_float".into(), instrs: uni(Op::Neg(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "neg_int".into(), instrs: uni(Op::Neg(0)), stack_size: 2, debug: DebugInfo::default() },
+
+        Proc { name: "add_int_wrapping".into(), instrs: bin(Op::AddWrapping(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "sub_int_wrapping".into(), instrs: bin(Op::SubWrapping(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "mul_int_wrapping".into(), instrs: bin(Op::MulWrapping(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "div_int_wrapping".into(), instrs: bin(Op::DivWrapping(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "mod_int_wrapping".into(), instrs: bin(Op::ModWrapping(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "neg_int_wrapping".into(), instrs: uni(Op::NegWrapping(0)), stack_size: 2, debug: DebugInfo::default() },
+
+        Proc { name: "and".into(), instrs: bin(Op::And(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "or".into(), instrs: bin(Op::Or(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "xor".into(), instrs: bin(Op::Xor(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "not".into(), instrs: uni(Op::Not(0)), stack_size: 2, debug: DebugInfo::default() },
+
+        Proc { name: "gt_float".into(), instrs: bin(Op::Gt(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "gt_int".into(), instrs: bin(Op::Gt(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "lt_float".into(), instrs: bin(Op::Lt(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "lt_int".into(), instrs: bin(Op::Lt(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+
+        Proc { name: "eq_float".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "eq_int".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "eq_bool".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "eq_symbol".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "eq_ref".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "eq_string".into(), instrs: bin(Op::Eq(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "gt_string".into(), instrs: bin(Op::Gt(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "lt_string".into(), instrs: bin(Op::Lt(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+
+        Proc { name: "length_string".into(), instrs: uni(Op::StrLength(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "substring".into(), instrs: tri(Op::Substring { local: 0, start: 1, end: 2 }), stack_size: 4, debug: DebugInfo::default() },
+        Proc { name: "char_at".into(), instrs: bin(Op::CharAt(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "index_of".into(), instrs: bin(Op::IndexOf(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "split".into(), instrs: bin(Op::Split(0, 1)), stack_size: 3, debug: DebugInfo::default() },
+        Proc { name: "upper".into(), instrs: uni(Op::Upper(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "lower".into(), instrs: uni(Op::Lower(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "trim".into(), instrs: uni(Op::Trim(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "parse_int".into(), instrs: uni(Op::ParseInt(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "parse_float".into(), instrs: uni(Op::ParseFloat(0)), stack_size: 2, debug: DebugInfo::default() },
+
+        // Note:  print, print_line and write_file return the number of bytes written.  read_line
+        // returns nil at the end of input.
+        Proc { name: "print".into(), instrs: uni(Op::Print(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "print_line".into(), instrs: uni(Op::PrintLine(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "read_line".into(), instrs: vec![Op::ReadLine, Op::SetLocalReturn(0), Op::ReturnLocal(0)], stack_size: 1, debug: DebugInfo::default() },
+        Proc { name: "read_file".into(), instrs: uni(Op::ReadFile(0)), stack_size: 2, debug: DebugInfo::default() },
+        Proc { name: "write_file".into(), instrs: bin(Op::WriteFile { path: 0, contents: 1 }), stack_size: 3, debug: DebugInfo::default() },
+
+        Proc { name: "map_keys".into(), instrs: map_keys(), stack_size: 7, debug: DebugInfo::default() },
     ];
 
     (sigs, code)
@@ -377,7 +764,7 @@ mod test {
     use super::*;
     fn proc(params: Vec<(Rc<str>, Type)>, sets: Vec<(Rc<str>, Type, Lit)>) -> PProc {
         let body = sets.into_iter().map(|(n, t, v)| Stmt::Set { var: n, ttype: t, val: Expr::Lit(v) }).collect::<Vec<_>>();
-        PProc { name: "a".into(), params, body, return_type: Type::Int }
+        PProc { name: "a".into(), params, body, return_type: Type::Int, spans: vec![], source: None, public: true, imports: vec![] }
     }
     
     #[test]
@@ -449,5 +836,64 @@ mod test {
         let output = compile_proc(&input, &HashMap::from([])); 
         assert!(matches!(output, Err(CompileError::TypeMismatch { .. })));
     }
+
+    fn compile_ir(input : &str) -> Result<Vec<Proc>, CompileError> {
+        let procs = crate::parsing::ir_parser::parse(input).unwrap();
+        compile(&procs, &[])
+    }
+
+    #[test]
+    fn should_error_with_duplicate_label() {
+        let output = compile_ir("proc main(x : Int) -> Int { label a; label a; return x; }");
+        assert!(matches!(output, Err(CompileError::DuplicateLabel { .. })));
+    }
+
+    #[test]
+    fn should_error_with_missing_return() {
+        let output = compile_ir("proc main(x : Bool) -> Bool { branch_true end x; return x; label end; }");
+        assert!(matches!(output, Err(CompileError::MissingReturn { .. })));
+    }
+
+    #[test]
+    fn should_error_with_uninitialised_local() {
+        let output = compile_ir("proc main(x : Bool) -> Int { branch_true end x; set y : Int = 1; label end; return y; }");
+        assert!(matches!(output, Err(CompileError::UninitialisedLocal { ref local, .. }) if &**local == "y"));
+    }
+
+    #[test]
+    fn should_error_with_unreachable_stmt() {
+        let output = compile_ir("proc main(x : Int) -> Int { return x; set y : Int = 1; return y; }");
+        assert!(matches!(output, Err(CompileError::UnreachableInstr { .. })));
+    }
+
+    #[test]
+    fn should_inline_primitive_calls() {
+        let output = compile_ir("proc main(a : Int, b : Int) -> Int { set c : Int = call sub_int(b, a); set d : Int = c; return d; }").unwrap();
+        let main = output.last().unwrap();
+        assert!(matches!(main.instrs[..], [Op::Sub(1, 0), Op::SetLocalReturn(2), ..]));
+        assert!(matches!(main.debug.inlined[..], [Inlined { instr: 0, tail: false, .. }]));
+    }
+
+    #[test]
+    fn should_not_inline_calls_to_ir_procs() {
+        let output = compile_ir("proc id(a : Int) -> Int { return a; } proc main(a : Int) -> Int { set b : Int = call id(a); set c : Int = b; return c; }").unwrap();
+        let main = output.last().unwrap();
+        assert!(matches!(main.instrs[0], Op::Call(..)));
+        assert!(main.debug.inlined.is_empty());
+    }
+
+    #[test]
+    fn should_find_handler_depths() {
+        let input = vec![
+            Op::Try { label: 4, local: 0 },
+            Op::Nop,
+            Op::EndTry,
+            Op::ReturnLocal(0),
+            Op::Try { label: 3, local: 0 },
+            Op::Jump(4),
+        ];
+        let output = handler_depths(&input);
+        assert_eq!(output, [Some(0), Some(1), Some(1), Some(usize::MAX), Some(usize::MAX), Some(usize::MAX)]);
+    }
 }
 
@@ -0,0 +1,158 @@
+
+use std::rc::Rc;
+
+use crate::eval::data::*;
+use super::ir_compiler::CompileError;
+
+/// Checks the control flow of a compiled proc.  Every path has to end in a return, break or
+/// throw, every local has to be set on all paths before it is read, and every instr other than
+/// a label has to be reachable.  The first params locals are set by the caller.
+pub fn verify(proc : &Proc, params : usize) -> Result<(), CompileError> {
+    let instrs = &proc.instrs;
+    let states = init_states(instrs, params, proc.stack_size.max(params));
+
+    for (ip, op) in instrs.iter().enumerate() {
+        let state = match &states[ip] {
+            Some(x) => x,
+            None if matches!(op, Op::Nop) => { continue; },
+            None => { return Err(CompileError::UnreachableInstr { proc: Rc::clone(&proc.name), instr: ip }); },
+        };
+
+        if let Some(local) = op.reads().into_iter().find(|x| !state[*x]) {
+            let local = proc.debug.locals.get(local).cloned().unwrap_or_else(|| local.to_string().into());
+            return Err(CompileError::UninitialisedLocal { proc: Rc::clone(&proc.name), local, instr: ip });
+        }
+
+        if successors(ip, op).iter().any(|(x, _)| *x >= instrs.len()) {
+            return Err(CompileError::MissingReturn { proc: Rc::clone(&proc.name) });
+        }
+    }
+
+    Ok(())
+}
+
+/// Locals that are definitely set before each instr, or None when the instr is unreachable.
+fn init_states(instrs : &[Op], params : usize, stack_size : usize) -> Vec<Option<Vec<bool>>> {
+    let mut states : Vec<Option<Vec<bool>>> = vec![None; instrs.len()];
+    if instrs.is_empty() {
+        return states;
+    }
+
+    states[0] = Some((0..stack_size).map(|x| x < params).collect());
+
+    let mut work = vec![0];
+    while let Some(ip) = work.pop() {
+        let mut out = states[ip].clone().unwrap();
+        if let Some(local) = instrs[ip].writes() {
+            out[local] = true;
+        }
+
+        for (target, set) in successors(ip, &instrs[ip]) {
+            if target >= instrs.len() {
+                continue;
+            }
+
+            let mut incoming = out.clone();
+            if let Some(local) = set {
+                incoming[local] = true;
+            }
+
+            let changed = match &mut states[target] {
+                Some(state) => {
+                    let mut changed = false;
+                    for (a, b) in state.iter_mut().zip(incoming) {
+                        if *a && !b {
+                            *a = false;
+                            changed = true;
+                        }
+                    }
+                    changed
+                },
+                state @ None => { *state = Some(incoming); true },
+            };
+
+            if changed {
+                work.push(target);
+            }
+        }
+    }
+
+    states
+}
+
+/// Next instrs along with a local that is set on the way there.  An index past the last instr
+/// means the proc falls off the end.
+pub(super) fn successors(ip : usize, op : &Op) -> Vec<(usize, Option<usize>)> {
+    match op {
+        Op::Jump(label) => vec![(*label, None)],
+        Op::BranchTrue { label, .. } => vec![(ip + 1, None), (*label, None)],
+        // Note:  Anything in the try region can throw, but only the state at the try is
+        // certain to hold when the handler is reached.
+        Op::Try { label, local } => vec![(ip + 1, None), (*label, Some(*local))],
+        Op::ReturnLocal(_) | Op::Break | Op::Throw(_) => vec![],
+        // Note:  tail_calls leaves the original set and return after a tail call, so they
+        // are treated like normal calls here.
+        _ => vec![(ip + 1, None)],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn proc(instrs : Vec<Op>) -> Proc {
+        Proc { name: "main".into(), instrs, stack_size: 3, debug: DebugInfo::default() }
+    }
+
+    #[test]
+    fn should_accept_set_on_both_branches() {
+        let input = proc(vec![
+            Op::BranchTrue { label: 3, local: 0 },
+            Op::SetLocalData(1, RuntimeData::Int(1)),
+            Op::Jump(4),
+            Op::SetLocalData(1, RuntimeData::Int(2)),
+            Op::ReturnLocal(1),
+        ]);
+        assert!(verify(&input, 1).is_ok());
+    }
+
+    #[test]
+    fn should_reject_set_on_one_branch() {
+        let input = proc(vec![
+            Op::BranchTrue { label: 2, local: 0 },
+            Op::SetLocalData(1, RuntimeData::Int(1)),
+            Op::ReturnLocal(1),
+        ]);
+        assert!(matches!(verify(&input, 1), Err(CompileError::UninitialisedLocal { instr: 2, .. })));
+    }
+
+    #[test]
+    fn should_reject_falling_off_end() {
+        let input = proc(vec![
+            Op::BranchTrue { label: 2, local: 0 },
+            Op::ReturnLocal(0),
+            Op::Nop,
+        ]);
+        assert!(matches!(verify(&input, 1), Err(CompileError::MissingReturn { .. })));
+    }
+
+    #[test]
+    fn should_reject_unreachable_instr() {
+        let input = proc(vec![
+            Op::ReturnLocal(0),
+            Op::SetLocalData(1, RuntimeData::Int(1)),
+            Op::ReturnLocal(1),
+        ]);
+        assert!(matches!(verify(&input, 1), Err(CompileError::UnreachableInstr { instr: 1, .. })));
+    }
+
+    #[test]
+    fn should_set_handler_local_at_handler() {
+        let input = proc(vec![
+            Op::Try { label: 2, local: 1 },
+            Op::Throw(0),
+            Op::ReturnLocal(1),
+        ]);
+        assert!(verify(&input, 1).is_ok());
+    }
+}
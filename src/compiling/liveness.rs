@@ -0,0 +1,167 @@
+
+use std::rc::Rc;
+
+use crate::eval::data::*;
+use super::ir_verifier::successors;
+
+/// Renumbers the locals of a verified proc so that locals which are never live at the same time
+/// share a slot, and shrinks stack_size to match.  The first params slots keep their positions
+/// because callers, and DynCall with a closure env, fill them in by position.
+pub fn share_slots(proc : &mut Proc, params : usize) {
+    let stack_size = proc.stack_size.max(params);
+    let (live_in, live_out) = liveness(&proc.instrs, stack_size);
+
+    // Note:  A throw can reach the handler from anywhere in the try region, so anything the
+    // handler needs is kept out of the way of every other local.
+    let mut pinned = vec![false; stack_size];
+    for op in &proc.instrs {
+        if let Op::Try { label, local } = op {
+            pinned[*local] = true;
+            if let Some(live) = live_in.get(*label) {
+                pinned.iter_mut().zip(live).for_each(|(p, l)| *p |= *l);
+            }
+        }
+    }
+
+    let mut interferes = vec![vec![false; stack_size]; stack_size];
+    for (ip, op) in proc.instrs.iter().enumerate() {
+        if let Some(dest) = op.writes() {
+            for local in (0..stack_size).filter(|x| live_out[ip][*x] && *x != dest) {
+                interferes[dest][local] = true;
+                interferes[local][dest] = true;
+            }
+        }
+    }
+    for local in (0..stack_size).filter(|x| pinned[*x]) {
+        interferes[local].fill(true);
+        interferes.iter_mut().for_each(|x| x[local] = true);
+    }
+
+    let mut used = vec![false; stack_size];
+    for local in proc.instrs.iter().flat_map(|x| x.locals()) {
+        used[local] = true;
+    }
+
+    let mut slots : Vec<Option<usize>> = (0..stack_size).map(|x| if x < params { Some(x) } else { None }).collect();
+    for local in (params..stack_size).filter(|x| used[*x]) {
+        let slot = (params..).find(|slot| (0..stack_size).all(|other| slots[other] != Some(*slot) || !interferes[local][other])).unwrap();
+        slots[local] = Some(slot);
+    }
+
+    for op in &mut proc.instrs {
+        for local in op.locals_mut() {
+            *local = slots[*local].unwrap();
+        }
+    }
+
+    let new_size = slots.iter().flatten().map(|x| x + 1).max().unwrap_or(0).max(params);
+    if proc.debug.locals.len() == stack_size {
+        let names = (0..new_size).map(|slot| {
+            let names = (0..stack_size).filter(|x| slots[*x] == Some(slot)).map(|x| proc.debug.locals[x].to_string()).collect::<Vec<_>>();
+            Rc::from(names.join("/"))
+        }).collect();
+        proc.debug.locals = names;
+    }
+    proc.stack_size = new_size;
+}
+
+/// Locals that are live going into and coming out of each instr.
+fn liveness(instrs : &[Op], stack_size : usize) -> (Vec<Vec<bool>>, Vec<Vec<bool>>) {
+    let mut live_in = vec![vec![false; stack_size]; instrs.len()];
+    let mut live_out = vec![vec![false; stack_size]; instrs.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for ip in (0..instrs.len()).rev() {
+            let mut out = vec![false; stack_size];
+            for (target, set) in successors(ip, &instrs[ip]) {
+                if let Some(live) = live_in.get(target) {
+                    for local in (0..stack_size).filter(|x| live[*x] && Some(*x) != set) {
+                        out[local] = true;
+                    }
+                }
+            }
+
+            let mut input = out.clone();
+            if let Some(local) = instrs[ip].writes() {
+                input[local] = false;
+            }
+            for local in instrs[ip].reads() {
+                input[local] = true;
+            }
+
+            if out != live_out[ip] || input != live_in[ip] {
+                live_out[ip] = out;
+                live_in[ip] = input;
+                changed = true;
+            }
+        }
+    }
+
+    (live_in, live_out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn proc(instrs : Vec<Op>, stack_size : usize) -> Proc {
+        Proc { name: "main".into(), instrs, stack_size, debug: DebugInfo::default() }
+    }
+
+    #[test]
+    fn should_share_slot_between_disjoint_locals() {
+        let mut input = proc(vec![
+            Op::SetLocalData(1, RuntimeData::Int(1)),
+            Op::Add(0, 1),
+            Op::SetLocalReturn(2),
+            Op::Add(0, 2),
+            Op::SetLocalReturn(3),
+            Op::ReturnLocal(3),
+        ], 4);
+        share_slots(&mut input, 1);
+        assert_eq!(input.stack_size, 2);
+        assert!(matches!(input.instrs[..], [_, Op::Add(0, 1), Op::SetLocalReturn(1), Op::Add(0, 1), Op::SetLocalReturn(1), Op::ReturnLocal(1)]));
+    }
+
+    #[test]
+    fn should_not_share_slot_between_overlapping_locals() {
+        let mut input = proc(vec![
+            Op::SetLocalData(1, RuntimeData::Int(1)),
+            Op::SetLocalData(2, RuntimeData::Int(2)),
+            Op::Add(1, 2),
+            Op::SetLocalReturn(0),
+            Op::ReturnLocal(0),
+        ], 3);
+        share_slots(&mut input, 1);
+        assert_eq!(input.stack_size, 3);
+    }
+
+    #[test]
+    fn should_keep_params_in_place() {
+        let mut input = proc(vec![
+            Op::SetLocalData(2, RuntimeData::Int(1)),
+            Op::ReturnLocal(2),
+        ], 3);
+        share_slots(&mut input, 2);
+        assert_eq!(input.stack_size, 3);
+        assert!(matches!(input.instrs[..], [Op::SetLocalData(2, _), Op::ReturnLocal(2)]));
+    }
+
+    #[test]
+    fn should_not_share_slots_needed_by_handler() {
+        let mut input = proc(vec![
+            Op::SetLocalData(1, RuntimeData::Int(1)),
+            Op::Try { label: 5, local: 3 },
+            Op::SetLocalData(2, RuntimeData::Int(2)),
+            Op::Throw(2),
+            Op::Nop,
+            Op::Add(1, 3),
+            Op::SetLocalReturn(0),
+            Op::ReturnLocal(0),
+        ], 4);
+        share_slots(&mut input, 1);
+        assert_eq!(input.stack_size, 4);
+    }
+}
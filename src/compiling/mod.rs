@@ -1,4 +1,8 @@
 pub mod ir_compiler;
+mod ir_verifier;
+pub mod const_fold;
+pub mod peephole;
+pub mod liveness;
 mod unifier;
 pub mod dne_static_analysis;
 pub mod dne_compiler;
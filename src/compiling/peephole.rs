@@ -0,0 +1,177 @@
+
+use crate::eval::data::*;
+
+/// Shrinks a compiled proc without changing what it does.  Jump chains are threaded,
+/// unreachable instrs are dropped along with label nops and jumps to the next instr, and every
+/// target, span, debug label and inlined call is renumbered to match.
+pub fn optimise(proc : &mut Proc) {
+    loop {
+        thread_jumps(&mut proc.instrs);
+
+        let reachable = reachable(&proc.instrs);
+        let keep = proc.instrs.iter().enumerate().map(|(ip, op)| reachable[ip] && match op {
+            Op::Nop => false,
+            Op::Jump(label) => *label != ip + 1,
+            _ => true,
+        }).collect::<Vec<_>>();
+
+        if keep.iter().all(|x| *x) {
+            return;
+        }
+
+        remove(proc, &keep);
+    }
+}
+
+fn targets(op : &mut Op) -> Option<&mut usize> {
+    match op {
+        Op::Jump(label) | Op::BranchTrue { label, .. } | Op::Try { label, .. } => Some(label),
+        _ => None,
+    }
+}
+
+fn thread_jumps(instrs : &mut [Op]) {
+    let resolve = |instrs : &[Op], start : usize| {
+        let mut target = start;
+        // Note:  A loop that only jumps never settles, so give up after visiting every instr.
+        for _ in 0..=instrs.len() {
+            match instrs.get(target) {
+                Some(Op::Nop) => { target += 1; },
+                Some(Op::Jump(x)) => { target = *x; },
+                _ => { return target; },
+            }
+        }
+        start
+    };
+
+    for ip in 0..instrs.len() {
+        let target = match &instrs[ip] {
+            Op::Jump(x) | Op::BranchTrue { label: x, .. } | Op::Try { label: x, .. } => resolve(instrs, *x),
+            _ => { continue; },
+        };
+        *targets(&mut instrs[ip]).unwrap() = target;
+    }
+}
+
+fn reachable(instrs : &[Op]) -> Vec<bool> {
+    let mut reachable = vec![false; instrs.len()];
+    let mut work = vec![0];
+    while let Some(ip) = work.pop() {
+        if ip >= instrs.len() || reachable[ip] {
+            continue;
+        }
+        reachable[ip] = true;
+
+        match &instrs[ip] {
+            Op::Jump(label) => { work.push(*label); },
+            Op::BranchTrue { label, .. } | Op::Try { label, .. } => { work.push(*label); work.push(ip + 1); },
+            Op::ReturnLocal(_) | Op::Break | Op::Throw(_) | Op::TailCall(..) | Op::DynTailCall(..) => { },
+            _ => { work.push(ip + 1); },
+        }
+    }
+    reachable
+}
+
+/// Drops the instrs that are not kept.  Anything that pointed at a dropped instr now points at
+/// the next kept one.
+fn remove(proc : &mut Proc, keep : &[bool]) {
+    let mut new_index = Vec::with_capacity(keep.len() + 1);
+    let mut count = 0;
+    for k in keep {
+        new_index.push(count);
+        if *k {
+            count += 1;
+        }
+    }
+    new_index.push(count);
+
+    let instrs = std::mem::take(&mut proc.instrs);
+    proc.instrs = instrs.into_iter().zip(keep).filter(|(_, k)| **k).map(|(mut op, _)| {
+        if let Some(label) = targets(&mut op) {
+            *label = new_index[*label];
+        }
+        op
+    }).collect();
+
+    if proc.debug.spans.len() == keep.len() {
+        let spans = std::mem::take(&mut proc.debug.spans);
+        proc.debug.spans = spans.into_iter().zip(keep).filter(|(_, k)| **k).map(|(x, _)| x).collect();
+    }
+
+    for (_, index) in &mut proc.debug.labels {
+        *index = new_index[(*index).min(keep.len())];
+    }
+
+    proc.debug.inlined.retain(|x| keep[x.instr]);
+    for inlined in &mut proc.debug.inlined {
+        inlined.instr = new_index[inlined.instr];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn proc(instrs : Vec<Op>) -> Proc {
+        Proc { name: "main".into(), instrs, stack_size: 2, debug: DebugInfo::default() }
+    }
+
+    #[test]
+    fn should_remove_nops_and_renumber_branches() {
+        let mut input = proc(vec![
+            Op::Nop,
+            Op::BranchTrue { label: 3, local: 0 },
+            Op::ReturnLocal(1),
+            Op::Nop,
+            Op::ReturnLocal(0),
+        ]);
+        optimise(&mut input);
+        assert!(matches!(input.instrs[..], [Op::BranchTrue { label: 2, local: 0 }, Op::ReturnLocal(1), Op::ReturnLocal(0)]));
+    }
+
+    #[test]
+    fn should_thread_jump_chains() {
+        let mut input = proc(vec![
+            Op::BranchTrue { label: 3, local: 0 },
+            Op::ReturnLocal(0),
+            Op::Jump(5),
+            Op::Jump(2),
+            Op::ReturnLocal(1),
+            Op::ReturnLocal(0),
+        ]);
+        optimise(&mut input);
+        assert!(matches!(input.instrs[..], [Op::BranchTrue { label: 2, local: 0 }, Op::ReturnLocal(0), Op::ReturnLocal(0)]));
+    }
+
+    #[test]
+    fn should_remove_jump_to_next_instr() {
+        let mut input = proc(vec![
+            Op::SetLocalData(0, RuntimeData::Int(1)),
+            Op::Jump(2),
+            Op::ReturnLocal(0),
+        ]);
+        optimise(&mut input);
+        assert!(matches!(input.instrs[..], [Op::SetLocalData(0, _), Op::ReturnLocal(0)]));
+    }
+
+    #[test]
+    fn should_remove_dead_code_after_return() {
+        let mut input = proc(vec![
+            Op::TailCall(0, vec![]),
+            Op::SetLocalReturn(0),
+            Op::ReturnLocal(0),
+        ]);
+        optimise(&mut input);
+        assert!(matches!(input.instrs[..], [Op::TailCall(0, _)]));
+    }
+
+    #[test]
+    fn should_keep_jump_only_loop() {
+        let mut input = proc(vec![
+            Op::Nop,
+            Op::Jump(0),
+        ]);
+        optimise(&mut input);
+        assert!(matches!(input.instrs[..], [Op::Jump(0)]));
+    }
+}
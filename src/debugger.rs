@@ -0,0 +1,300 @@
+
+use std::io::{ BufRead, Write };
+
+use crate::util::{ Span, line_col };
+use crate::eval::vm::{ Vm, Status };
+use crate::runtime::{ Program, Error };
+
+const HELP : &str = "\
+break <proc> [label]   stop when execution reaches the proc or the label inside of it
+continue | c           run until a breakpoint or the end of the program
+stepi | si             execute one op
+step | s               execute until the next statement, stepping into calls
+next | n               execute until the next statement, stepping over calls and resumes
+finish                 execute until the current proc returns or yields
+where                  show the next op to be executed
+locals                 show the locals of the current proc
+stack | bt             show the call stack
+heap                   show every live heap cell
+quit | q               exit the debugger";
+
+/// Drives a program one op at a time in response to text commands.
+pub struct Debugger {
+    program: Program,
+    breakpoints: Vec<(usize, usize)>,
+}
+
+impl Debugger {
+    pub fn new(program : Program) -> Self {
+        Debugger { program, breakpoints: vec![] }
+    }
+
+    /// Pauses the program before the first op of the named proc.
+    pub fn start(&mut self, name : &str) -> Result<(), Error> {
+        self.program.start(name, vec![])
+    }
+
+    pub fn command(&mut self, line : &str) -> String {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["break" | "b", proc] => self.set_breakpoint(proc, None),
+            ["break" | "b", proc, label] => self.set_breakpoint(proc, Some(label)),
+            ["continue" | "c"] => self.run_until(|_| false),
+            ["stepi" | "si"] => self.run_until(|_| true),
+            ["step" | "s"] => {
+                let start = statement(self.program.vm());
+                self.run_until(move |vm| statement(vm) != start)
+            },
+            ["next" | "n"] => {
+                let start = statement(self.program.vm());
+                self.run_until(move |vm| vm.frames().len() <= start.0 && statement(vm) != start)
+            },
+            ["finish"] => {
+                let depth = self.program.vm().frames().len();
+                self.run_until(move |vm| vm.frames().len() < depth)
+            },
+            ["where"] => self.location(),
+            ["locals"] => self.locals(),
+            ["stack" | "bt"] => self.stack(),
+            ["heap"] => self.heap(),
+            ["help"] => HELP.to_string(),
+            _ => format!("unknown command: {line}\n{HELP}"),
+        }
+    }
+
+    /// Reads commands until quit or the end of input.
+    pub fn repl(&mut self, input : impl BufRead, mut output : impl Write) -> std::io::Result<()> {
+        write!(output, "{}\n(dne) ", self.location())?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            match line.trim() {
+                "quit" | "q" => { break; },
+                "" => { },
+                x => { writeln!(output, "{}", self.command(x))?; },
+            }
+            write!(output, "(dne) ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn set_breakpoint(&mut self, proc : &str, label : Option<&str>) -> String {
+        let procs = self.program.vm().procs();
+        let proc_id = match procs.iter().position(|x| *x.name == *proc) {
+            Some(x) => x,
+            None => { return format!("cannot find proc {proc}"); },
+        };
+
+        let ip = match label {
+            None => 0,
+            Some(label) => match procs[proc_id].debug.labels.iter().find(|(x, _)| **x == *label) {
+                Some((_, ip)) => *ip,
+                None => { return format!("cannot find label {label} in proc {proc}"); },
+            },
+        };
+
+        if !self.breakpoints.contains(&(proc_id, ip)) {
+            self.breakpoints.push((proc_id, ip));
+        }
+        format!("breakpoint set at {}", describe(self.program.vm(), proc_id, ip))
+    }
+
+    /// Steps at least once, and then until stop returns true, a breakpoint is reached or the
+    /// program ends.
+    fn run_until(&mut self, stop : impl Fn(&Vm) -> bool) -> String {
+        loop {
+            match self.program.vm_mut().step() {
+                Err(e) => { return format!("error: {e}"); },
+                Ok(Status::Finished(x)) => { return format!("finished with {:?}", x); },
+                Ok(Status::Running) => {
+                    let vm = self.program.vm();
+                    let current = vm.current();
+                    if stop(vm) || self.breakpoints.contains(&(current.proc_id, current.ip)) {
+                        return self.location();
+                    }
+                },
+            }
+        }
+    }
+
+    fn location(&self) -> String {
+        let vm = self.program.vm();
+        if !vm.is_running() {
+            return "not running".to_string();
+        }
+        let current = vm.current();
+        let proc = &vm.procs()[current.proc_id];
+        let mut output = describe(vm, current.proc_id, current.ip);
+        if let Some(op) = proc.instrs.get(current.ip) {
+            output.push_str(&format!("\n    {:?}", op));
+        }
+        if let (Some(source), Some(span)) = (&proc.debug.source, proc.debug.spans.get(current.ip)) {
+            output.push_str(&format!("\n    {}", source.text.get(span.start..=span.end).unwrap_or("")));
+        }
+        output
+    }
+
+    fn locals(&self) -> String {
+        let vm = self.program.vm();
+        let current = vm.current();
+        let names = &vm.procs()[current.proc_id].debug.locals;
+        current.locals.iter().enumerate().map(|(i, value)| match names.get(i) {
+            Some(name) => format!("{name} = {:?}", value),
+            None => format!("local {i} = {:?}", value),
+        }).collect::<Vec<_>>().join("\n")
+    }
+
+    fn stack(&self) -> String {
+        let vm = self.program.vm();
+        let current = vm.current();
+        // Note:  Caller frames hold the return address, so the call is the op before it.
+        std::iter::once((current.proc_id, current.ip))
+            .chain(vm.frames().iter().rev().map(|x| (x.proc_id, x.ip - 1)))
+            .enumerate()
+            .map(|(i, (proc_id, ip))| format!("#{i} {}", describe(vm, proc_id, ip)))
+            .collect::<Vec<_>>().join("\n")
+    }
+
+    fn heap(&self) -> String {
+        let cells = self.program.vm().heap_cells().map(|(r, cell)| format!("{}.{} = {:?}", r.addr, r.generation, cell)).collect::<Vec<_>>();
+        if cells.is_empty() {
+            "heap is empty".to_string()
+        }
+        else {
+            cells.join("\n")
+        }
+    }
+}
+
+/// Identifies the statement that the next op belongs to.  Procs without spans treat every op as
+/// its own statement.
+fn statement(vm : &Vm) -> (usize, usize, Option<Span>, Option<usize>) {
+    let current = vm.current();
+    match vm.procs()[current.proc_id].debug.spans.get(current.ip) {
+        Some(span) => (vm.frames().len(), current.proc_id, Some(*span), None),
+        None => (vm.frames().len(), current.proc_id, None, Some(current.ip)),
+    }
+}
+
+fn describe(vm : &Vm, proc_id : usize, ip : usize) -> String {
+    let proc = &vm.procs()[proc_id];
+    match (&proc.debug.source, proc.debug.spans.get(ip)) {
+        (Some(source), Some(span)) => {
+            let (line, col) = line_col(&source.text, span.start);
+            format!("{} instr {ip} at {}:{line}:{col}", proc.name, source.file)
+        },
+        _ => format!("{} instr {ip}", proc.name),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::Runtime;
+
+    fn start(input : &str) -> Debugger {
+        let mut runtime = Runtime::new();
+        runtime.load("test.ir", input).unwrap();
+        let mut debugger = Debugger::new(runtime.compile().unwrap());
+        debugger.start("main").unwrap();
+        debugger
+    }
+
+    const INPUT : &str = r"
+proc double(x : Int) -> Int {
+    set y : Int = call add_int(x, x);
+    return y;
+}
+
+proc main() -> Int {
+    set a : Int = 1;
+    set a : Int = call double(a);
+    label end;
+    set b : Int = 3;
+    return a;
+}
+";
+
+    #[test]
+    fn should_stop_at_breakpoints() {
+        let mut debugger = start(INPUT);
+        assert!(debugger.command("break double").starts_with("breakpoint set at double instr 0 at test.ir:3:5"));
+        assert!(debugger.command("break main end").starts_with("breakpoint set at main instr 3 at test.ir:10:5"));
+        assert!(debugger.command("break main missing").starts_with("cannot find label"));
+        assert!(debugger.command("c").starts_with("double instr 0"));
+        assert!(debugger.command("c").starts_with("main instr 3"));
+        assert_eq!(debugger.command("c"), "finished with Some(Int(2))");
+        assert_eq!(debugger.command("c"), "error: Attempting to step a vm that is not running");
+    }
+
+    #[test]
+    fn should_step_into_and_over_calls() {
+        let mut debugger = start(INPUT);
+        assert!(debugger.command("s").starts_with("main instr 1 at test.ir:9:5"));
+        assert!(debugger.command("s").starts_with("double instr 0 at test.ir:3:5"));
+        assert!(debugger.command("finish").starts_with("main instr 2 at test.ir:9:5"));
+        assert!(debugger.command("n").starts_with("main instr 3 at test.ir:10:5"));
+
+        let mut debugger = start(INPUT);
+        assert!(debugger.command("n").starts_with("main instr 1"));
+        assert!(debugger.command("n").starts_with("main instr 3"));
+    }
+
+    #[test]
+    fn should_step_over_and_into_resumes() {
+        let input = r"
+proc target() -> Int {
+    set x : Int = 7;
+    yield x;
+    yield x;
+    break;
+}
+proc main() -> Int {
+    set co : Coroutine = coroutine target();
+    set a : Int = resume co;
+    set b : Int = resume co;
+    return b;
+}
+";
+        let mut debugger = start(input);
+        debugger.command("n");
+        assert!(debugger.command("n").starts_with("main instr 4 at test.ir:11:5"));
+        assert!(debugger.command("s").starts_with("target instr 2 at test.ir:5:5"));
+        assert!(debugger.command("finish").starts_with("main instr 5 at test.ir:11:5"));
+        assert_eq!(debugger.command("c"), "finished with Some(Int(7))");
+    }
+
+    #[test]
+    fn should_step_single_ops() {
+        let mut debugger = start(INPUT);
+        debugger.command("s");
+        debugger.command("s");
+        assert!(debugger.command("si").starts_with("double instr 1 at test.ir:3:5"));
+        assert_eq!(debugger.command("stack"), "#0 double instr 1 at test.ir:3:5\n#1 main instr 1 at test.ir:9:5");
+    }
+
+    #[test]
+    fn should_show_locals_by_name() {
+        let mut debugger = start(INPUT);
+        debugger.command("s");
+        assert_eq!(debugger.command("locals"), "a = Int(1)\nb = Nil");
+    }
+
+    #[test]
+    fn should_show_heap_cells() {
+        let mut debugger = start(r"
+proc main() -> Ref {
+    set s : Symbol = ~pair;
+    set a : Int = 1;
+    set r : Ref = cons s (a);
+    return r;
+}
+");
+        assert_eq!(debugger.command("heap"), "heap is empty");
+        debugger.command("n");
+        debugger.command("n");
+        debugger.command("n");
+        assert_eq!(debugger.command("heap"), r#"0.0 = Cons { name: "pair", params: [Int(1)] }"#);
+    }
+}
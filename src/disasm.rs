@@ -0,0 +1,113 @@
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::compiling::ir_compiler;
+use crate::eval::data::{ Op, Proc };
+
+/// Lists every op of a compiled program.  Jump targets are shown as labels, using the ir label
+/// names where the proc still has them, and locals are shown with their ir names.  The
+/// primitive procs at the front of every program are left out unless primitives is set.
+pub fn disassemble(procs : &[Proc], primitives : bool) -> String {
+    let skip = if primitives { 0 } else { ir_compiler::primitive_count().min(procs.len()) };
+    procs[skip..].iter().map(|x| disassemble_proc(procs, x)).collect::<Vec<_>>().join("\n")
+}
+
+fn disassemble_proc(procs : &[Proc], proc : &Proc) -> String {
+    let mut labels : HashMap<usize, String> = HashMap::new();
+    for (name, index) in &proc.debug.labels {
+        labels.entry(*index).or_insert_with(|| name.to_string());
+    }
+    for op in &proc.instrs {
+        if let Op::Jump(label) | Op::BranchTrue { label, .. } | Op::Try { label, .. } = op {
+            labels.entry(*label).or_insert_with(|| format!("L{label}"));
+        }
+    }
+
+    let local = |x : &usize| match proc.debug.locals.get(*x) {
+        Some(name) => format!("%{x}({name})"),
+        None => format!("%{x}"),
+    };
+    let locals = |xs : &[usize]| xs.iter().map(local).collect::<Vec<_>>().join(", ");
+    let label = |x : &usize| labels.get(x).cloned().unwrap_or_else(|| format!("L{x}"));
+    let callee = |x : &usize| procs.get(*x).map(|p| p.name.to_string()).unwrap_or_else(|| format!("<proc {x}>"));
+
+    let mut output = format!("proc {} (stack_size {})\n", proc.name, proc.stack_size);
+    for (ip, op) in proc.instrs.iter().enumerate() {
+        if let Some(name) = labels.get(&ip) {
+            writeln!(output, "  {name}:").unwrap();
+        }
+
+        let operands = match op {
+            Op::Call(proc_id, args) | Op::TailCall(proc_id, args) => format!("{}({})", callee(proc_id), locals(args)),
+            Op::Closure { proc_id, env: args } | Op::Coroutine { proc_id, params: args } => format!("{}({})", callee(proc_id), locals(args)),
+            Op::CallNative(native_id, args) => format!("native {native_id}({})", locals(args)),
+            Op::Jump(x) => label(x),
+            Op::BranchTrue { label: x, local: y } => format!("{} if {}", label(x), local(y)),
+            Op::Try { label: x, local: y } => format!("{} with {}", label(x), local(y)),
+            Op::SetLocalData(x, data) => format!("{} = {data:?}", local(x)),
+            Op::SetLocalVar { src, dest } => format!("{} = {}", local(dest), local(src)),
+            Op::GetSlot { local: x, index } | Op::RemoveSlot { local: x, index } => format!("{} {index}", local(x)),
+            Op::InsertSlot { dest, src, index } => format!("{} {index} {}", local(dest), local(src)),
+            x => locals(&x.locals()),
+        };
+
+        let name = format!("{op:?}").chars().take_while(|x| x.is_alphanumeric()).collect::<String>();
+        let mut line = format!("{ip:>6}  {name:<16}{operands}");
+        if let Some(inlined) = proc.debug.inlined_at(ip) {
+            write!(line, "    ; inlined {}", callee(&inlined.proc_id)).unwrap();
+        }
+        writeln!(output, "{}", line.trim_end()).unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::Runtime;
+
+    fn listing(input : &str, primitives : bool) -> String {
+        let mut runtime = Runtime::new();
+        runtime.load("test.ir", input).unwrap();
+        let program = runtime.compile().unwrap();
+        disassemble(program.vm().procs(), primitives)
+    }
+
+    #[test]
+    fn should_list_ops_with_labels_and_local_names() {
+        let input = r"
+proc main() -> Int {
+    set x : Int = 3;
+    label top;
+    set done : Bool = call eq_int(x, x);
+    branch_true top done;
+    return x;
+}
+";
+        let output = listing(input, false);
+        assert_eq!(output, "\
+proc main (stack_size 2)
+     0  SetLocalData    %1(x) = Int(3)
+  top:
+     1  Nop
+     2  Eq              %1(x), %1(x)    ; inlined eq_int
+     3  SetLocalReturn  %0(done)
+     4  BranchTrue      top if %0(done)
+     5  ReturnLocal     %1(x)
+");
+    }
+
+    #[test]
+    fn should_hide_primitives_by_default() {
+        let input = "proc main() -> Int { set x : Int = 1; return x; }";
+        assert!(listing(input, false).starts_with("proc main"));
+        assert!(listing(input, true).starts_with("proc add_float"));
+    }
+
+    #[test]
+    fn should_name_calls_and_synthesised_labels() {
+        let procs = vec![Proc { name: "main".into(), instrs: vec![Op::Call(0, vec![0]), Op::Jump(0)], stack_size: 1, debug: Default::default() }];
+        assert_eq!(disassemble(&procs, true), "proc main (stack_size 1)\n  L0:\n     0  Call            main(%0)\n     1  Jump            L0\n");
+    }
+}
@@ -0,0 +1,584 @@
+
+use std::rc::Rc;
+
+use super::data::*;
+
+const MAGIC : &[u8; 4] = b"DNEB";
+const VERSION : u32 = 1;
+// Note:  Locals are allocated up front on every call, so a large stack size from a damaged or
+// hostile file would use a lot of memory on every call before the vm could report anything.
+const MAX_STACK_SIZE : usize = u16::MAX as usize;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    Truncated(usize),
+    TrailingBytes(usize),
+    InvalidTag { offset: usize, tag: u8 },
+    InvalidUtf8(usize),
+    InvalidIndex(usize),
+    InvalidProc { proc: Rc<str>, message: Box<str> },
+    LengthTooLarge(usize),
+    UnserializableConstant { proc: Rc<str>, found: Box<str> },
+}
+
+impl std::fmt::Display for BytecodeError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "Bytecode file does not start with the dne bytecode header"),
+            BytecodeError::UnsupportedVersion(v) => write!(f, "Bytecode version {v} is not supported.  Expected version {VERSION}"),
+            BytecodeError::ChecksumMismatch => write!(f, "Bytecode checksum does not match, the file is corrupted"),
+            BytecodeError::Truncated(offset) => write!(f, "Bytecode ends unexpectedly at byte {offset}"),
+            BytecodeError::TrailingBytes(offset) => write!(f, "Bytecode has unexpected trailing bytes at byte {offset}"),
+            BytecodeError::InvalidTag { offset, tag } => write!(f, "Bytecode has invalid tag {tag} at byte {offset}"),
+            BytecodeError::InvalidUtf8(offset) => write!(f, "Bytecode has invalid utf8 string at byte {offset}"),
+            BytecodeError::InvalidIndex(offset) => write!(f, "Bytecode has index that does not fit this platform at byte {offset}"),
+            BytecodeError::InvalidProc { proc, message } => write!(f, "Bytecode proc {proc} is invalid:  {message}"),
+            BytecodeError::LengthTooLarge(len) => write!(f, "Length {len} is too large to be written as bytecode"),
+            BytecodeError::UnserializableConstant { proc, found } => write!(f, "Proc {proc} has constant {found} that cannot be written as bytecode"),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError { }
+
+/// Compiled procs plus the names of the natives that their CallNative ops index into.
+#[derive(Debug)]
+pub struct Bytecode {
+    pub procs: Vec<Proc>,
+    pub natives: Vec<Rc<str>>,
+}
+
+pub fn write(procs : &[Proc], natives : &[Rc<str>]) -> Result<Vec<u8>, BytecodeError> {
+    let mut w = Writer { bytes: MAGIC.to_vec() };
+    w.u32(VERSION);
+
+    w.len(natives.len())?;
+    for native in natives {
+        w.str(native)?;
+    }
+
+    w.len(procs.len())?;
+    for proc in procs {
+        w.str(&proc.name)?;
+        w.usize(proc.stack_size);
+        w.len(proc.instrs.len())?;
+        for op in &proc.instrs {
+            w.op(&proc.name, op)?;
+        }
+    }
+
+    let checksum = fnv1a(&w.bytes);
+    w.bytes.extend_from_slice(&checksum.to_le_bytes());
+    Ok(w.bytes)
+}
+
+pub fn read(bytes : &[u8]) -> Result<Bytecode, BytecodeError> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(BytecodeError::BadMagic);
+    }
+    if bytes.len() < MAGIC.len() + 4 + 8 {
+        return Err(BytecodeError::Truncated(bytes.len()));
+    }
+
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let (body, checksum) = bytes.split_at(bytes.len() - 8);
+    if fnv1a(body) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(BytecodeError::ChecksumMismatch);
+    }
+
+    let mut r = Reader { bytes: body, offset: 8 };
+
+    let native_count = r.len()?;
+    let mut natives = vec![];
+    for _ in 0..native_count {
+        natives.push(r.str()?);
+    }
+
+    let proc_count = r.len()?;
+    let mut procs = vec![];
+    for _ in 0..proc_count {
+        let name = r.str()?;
+        let stack_size = r.usize()?;
+        let instr_count = r.len()?;
+        let mut instrs = vec![];
+        for _ in 0..instr_count {
+            instrs.push(r.op()?);
+        }
+        procs.push(Proc { name, instrs, stack_size, debug: DebugInfo::default() });
+    }
+
+    if r.offset != body.len() {
+        return Err(BytecodeError::TrailingBytes(r.offset));
+    }
+
+    validate(&procs, natives.len())?;
+
+    Ok(Bytecode { procs, natives })
+}
+
+fn validate(procs : &[Proc], native_count : usize) -> Result<(), BytecodeError> {
+    for proc in procs {
+        let invalid = |message : String| Err(BytecodeError::InvalidProc { proc: Rc::clone(&proc.name), message: message.into() });
+
+        if proc.stack_size > MAX_STACK_SIZE {
+            return invalid(format!("stack size {} is larger than {}", proc.stack_size, MAX_STACK_SIZE));
+        }
+
+        for (index, op) in proc.instrs.iter().enumerate() {
+            if let Some(local) = op.locals().into_iter().find(|x| *x >= proc.stack_size) {
+                return invalid(format!("instr {index} uses local {local} outside of stack size {}", proc.stack_size));
+            }
+            match op {
+                Op::Jump(label) | Op::BranchTrue { label, .. } | Op::Try { label, .. } if *label >= proc.instrs.len() =>
+                    { return invalid(format!("instr {index} jumps to missing instr {label}")); },
+                Op::CallNative(native_id, _) if *native_id >= native_count =>
+                    { return invalid(format!("instr {index} calls missing native {native_id}")); },
+                Op::Call(proc_id, _) | Op::TailCall(proc_id, _) | Op::Closure { proc_id, .. } | Op::Coroutine { proc_id, .. } if *proc_id >= procs.len() =>
+                    { return invalid(format!("instr {index} references missing proc {proc_id}")); },
+                Op::Call(proc_id, params) | Op::TailCall(proc_id, params) | Op::Coroutine { proc_id, params } if params.len() > procs[*proc_id].stack_size =>
+                    { return invalid(format!("instr {index} passes more params than proc {proc_id} has locals")); },
+                Op::Closure { proc_id, env } if env.len() > procs[*proc_id].stack_size =>
+                    { return invalid(format!("instr {index} captures more env than proc {proc_id} has locals")); },
+                _ => { },
+            }
+        }
+    }
+    Ok(())
+}
+
+fn fnv1a(bytes : &[u8]) -> u64 {
+    let mut hash : u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, x : u8) { self.bytes.push(x); }
+    fn u32(&mut self, x : u32) { self.bytes.extend_from_slice(&x.to_le_bytes()); }
+    fn u64(&mut self, x : u64) { self.bytes.extend_from_slice(&x.to_le_bytes()); }
+    fn usize(&mut self, x : usize) { self.u64(x as u64); }
+    fn len(&mut self, x : usize) -> Result<(), BytecodeError> {
+        self.u32(u32::try_from(x).map_err(|_| BytecodeError::LengthTooLarge(x))?);
+        Ok(())
+    }
+    fn str(&mut self, x : &str) -> Result<(), BytecodeError> {
+        self.len(x.len())?;
+        self.bytes.extend_from_slice(x.as_bytes());
+        Ok(())
+    }
+    fn usizes(&mut self, xs : &[usize]) -> Result<(), BytecodeError> {
+        self.len(xs.len())?;
+        for x in xs {
+            self.usize(*x);
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, proc : &Rc<str>, data : &RuntimeData) -> Result<(), BytecodeError> {
+        match data {
+            RuntimeData::Nil => { self.u8(0); },
+            RuntimeData::Bool(x) => { self.u8(1); self.u8(*x as u8); },
+            RuntimeData::Int(x) => { self.u8(2); self.u64(*x as u64); },
+            RuntimeData::Float(x) => { self.u8(3); self.u64(x.to_bits()); },
+            RuntimeData::Symbol(x) => { self.u8(4); self.str(x)?; },
+            RuntimeData::String(x) => { self.u8(5); self.str(x)?; },
+            x => { return Err(BytecodeError::UnserializableConstant { proc: Rc::clone(proc), found: format!("{:?}", x).into() }); },
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, proc : &Rc<str>, op : &Op) -> Result<(), BytecodeError> {
+        match op {
+            Op::Call(a, b) => { self.u8(0); self.usize(*a); self.usizes(b)?; },
+            Op::CallNative(a, b) => { self.u8(1); self.usize(*a); self.usizes(b)?; },
+            Op::DynCall(a, b) => { self.u8(2); self.usize(*a); self.usizes(b)?; },
+            Op::Resume(a) => { self.u8(3); self.usize(*a); },
+            Op::ReturnLocal(a) => { self.u8(4); self.usize(*a); },
+            Op::Jump(a) => { self.u8(5); self.usize(*a); },
+            Op::BranchTrue { label, local } => { self.u8(6); self.usize(*label); self.usize(*local); },
+            Op::SetLocalData(a, data) => { self.u8(7); self.usize(*a); self.data(proc, data)?; },
+            Op::SetLocalReturn(a) => { self.u8(8); self.usize(*a); },
+            Op::SetLocalVar { src, dest } => { self.u8(9); self.usize(*src); self.usize(*dest); },
+            Op::GetLength(a) => { self.u8(10); self.usize(*a); },
+            Op::GetType(a) => { self.u8(11); self.usize(*a); },
+            Op::GetSlot { local, index } => { self.u8(12); self.usize(*local); self.usize(*index); },
+            Op::Closure { proc_id, env } => { self.u8(13); self.usize(*proc_id); self.usizes(env)?; },
+            Op::Cons { sym_var, params } => { self.u8(14); self.usize(*sym_var); self.usizes(params)?; },
+            Op::Coroutine { proc_id, params } => { self.u8(15); self.usize(*proc_id); self.usizes(params)?; },
+            Op::DynCoroutine { local, params } => { self.u8(16); self.usize(*local); self.usizes(params)?; },
+            Op::Yield(a) => { self.u8(17); self.usize(*a); },
+            Op::Break => { self.u8(18); },
+            Op::InsertSlot { dest, src, index } => { self.u8(19); self.usize(*dest); self.usize(*src); self.usize(*index); },
+            Op::RemoveSlot { local, index } => { self.u8(20); self.usize(*local); self.usize(*index); },
+            Op::Delete(a) => { self.u8(21); self.usize(*a); },
+            Op::Nop => { self.u8(22); },
+            Op::Add(a, b) => { self.u8(23); self.usize(*a); self.usize(*b); },
+            Op::Sub(a, b) => { self.u8(24); self.usize(*a); self.usize(*b); },
+            Op::Mul(a, b) => { self.u8(25); self.usize(*a); self.usize(*b); },
+            Op::Div(a, b) => { self.u8(26); self.usize(*a); self.usize(*b); },
+            Op::Mod(a, b) => { self.u8(27); self.usize(*a); self.usize(*b); },
+            Op::Neg(a) => { self.u8(28); self.usize(*a); },
+            Op::Eq(a, b) => { self.u8(29); self.usize(*a); self.usize(*b); },
+            Op::Gt(a, b) => { self.u8(30); self.usize(*a); self.usize(*b); },
+            Op::Lt(a, b) => { self.u8(31); self.usize(*a); self.usize(*b); },
+            Op::Not(a) => { self.u8(32); self.usize(*a); },
+            Op::And(a, b) => { self.u8(33); self.usize(*a); self.usize(*b); },
+            Op::Or(a, b) => { self.u8(34); self.usize(*a); self.usize(*b); },
+            Op::Xor(a, b) => { self.u8(35); self.usize(*a); self.usize(*b); },
+            Op::IsNil(a) => { self.u8(36); self.usize(*a); },
+            Op::ToString(a) => { self.u8(37); self.usize(*a); },
+            Op::Concat(a, b) => { self.u8(38); self.usize(*a); self.usize(*b); },
+            Op::TailCall(a, b) => { self.u8(39); self.usize(*a); self.usizes(b)?; },
+            Op::DynTailCall(a, b) => { self.u8(40); self.usize(*a); self.usizes(b)?; },
+            Op::AddWrapping(a, b) => { self.u8(41); self.usize(*a); self.usize(*b); },
+            Op::SubWrapping(a, b) => { self.u8(42); self.usize(*a); self.usize(*b); },
+            Op::MulWrapping(a, b) => { self.u8(43); self.usize(*a); self.usize(*b); },
+            Op::DivWrapping(a, b) => { self.u8(44); self.usize(*a); self.usize(*b); },
+            Op::ModWrapping(a, b) => { self.u8(45); self.usize(*a); self.usize(*b); },
+            Op::NegWrapping(a) => { self.u8(46); self.usize(*a); },
+            Op::StrLength(a) => { self.u8(47); self.usize(*a); },
+            Op::Substring { local, start, end } => { self.u8(48); self.usize(*local); self.usize(*start); self.usize(*end); },
+            Op::CharAt(a, b) => { self.u8(49); self.usize(*a); self.usize(*b); },
+            Op::IndexOf(a, b) => { self.u8(50); self.usize(*a); self.usize(*b); },
+            Op::Split(a, b) => { self.u8(51); self.usize(*a); self.usize(*b); },
+            Op::Upper(a) => { self.u8(52); self.usize(*a); },
+            Op::Lower(a) => { self.u8(53); self.usize(*a); },
+            Op::Trim(a) => { self.u8(54); self.usize(*a); },
+            Op::ParseInt(a) => { self.u8(55); self.usize(*a); },
+            Op::ParseFloat(a) => { self.u8(56); self.usize(*a); },
+            Op::Print(a) => { self.u8(57); self.usize(*a); },
+            Op::PrintLine(a) => { self.u8(58); self.usize(*a); },
+            Op::ReadLine => { self.u8(59); },
+            Op::ReadFile(a) => { self.u8(60); self.usize(*a); },
+            Op::WriteFile { path, contents } => { self.u8(61); self.usize(*path); self.usize(*contents); },
+            Op::NewArray(a) => { self.u8(62); self.usizes(a)?; },
+            Op::ArrayPush { dest, src } => { self.u8(63); self.usize(*dest); self.usize(*src); },
+            Op::ArrayPop(a) => { self.u8(64); self.usize(*a); },
+            Op::ArrayGet { local, index } => { self.u8(65); self.usize(*local); self.usize(*index); },
+            Op::ArraySet { dest, index, src } => { self.u8(66); self.usize(*dest); self.usize(*index); self.usize(*src); },
+            Op::NewMap => { self.u8(67); },
+            Op::MapGet { local, key } => { self.u8(68); self.usize(*local); self.usize(*key); },
+            Op::MapInsert { dest, key, src } => { self.u8(69); self.usize(*dest); self.usize(*key); self.usize(*src); },
+            Op::MapRemove { local, key } => { self.u8(70); self.usize(*local); self.usize(*key); },
+            Op::MapContains { local, key } => { self.u8(71); self.usize(*local); self.usize(*key); },
+            Op::MapKeys(a) => { self.u8(72); self.usize(*a); },
+            Op::DynGetSlot { local, index } => { self.u8(73); self.usize(*local); self.usize(*index); },
+            Op::DynInsertSlot { dest, src, index } => { self.u8(74); self.usize(*dest); self.usize(*src); self.usize(*index); },
+            Op::DynRemoveSlot { local, index } => { self.u8(75); self.usize(*local); self.usize(*index); },
+            Op::Try { label, local } => { self.u8(76); self.usize(*label); self.usize(*local); },
+            Op::EndTry => { self.u8(77); },
+            Op::Throw(a) => { self.u8(78); self.usize(*a); },
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n : usize) -> Result<&'a [u8], BytecodeError> {
+        match self.offset.checked_add(n) {
+            Some(end) if end <= self.bytes.len() => {
+                let ret = &self.bytes[self.offset..end];
+                self.offset = end;
+                Ok(ret)
+            },
+            _ => Err(BytecodeError::Truncated(self.offset)),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> { Ok(self.take(1)?[0]) }
+    fn u32(&mut self) -> Result<u32, BytecodeError> { Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap())) }
+    fn u64(&mut self) -> Result<u64, BytecodeError> { Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap())) }
+    fn len(&mut self) -> Result<usize, BytecodeError> { Ok(self.u32()? as usize) }
+
+    fn usize(&mut self) -> Result<usize, BytecodeError> {
+        let offset = self.offset;
+        let x = self.u64()?;
+        usize::try_from(x).map_err(|_| BytecodeError::InvalidIndex(offset))
+    }
+
+    fn str(&mut self) -> Result<Rc<str>, BytecodeError> {
+        let len = self.len()?;
+        let offset = self.offset;
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(x) => Ok(x.into()),
+            Err(_) => Err(BytecodeError::InvalidUtf8(offset)),
+        }
+    }
+
+    fn usizes(&mut self) -> Result<Vec<usize>, BytecodeError> {
+        let len = self.len()?;
+        // Note:  Growing as items are read means a damaged length can not force a huge allocation.
+        let mut ret = vec![];
+        for _ in 0..len {
+            ret.push(self.usize()?);
+        }
+        Ok(ret)
+    }
+
+    fn data(&mut self) -> Result<RuntimeData, BytecodeError> {
+        let offset = self.offset;
+        match self.u8()? {
+            0 => Ok(RuntimeData::Nil),
+            1 => match self.u8()? {
+                0 => Ok(RuntimeData::Bool(false)),
+                1 => Ok(RuntimeData::Bool(true)),
+                tag => Err(BytecodeError::InvalidTag { offset: offset + 1, tag }),
+            },
+            2 => Ok(RuntimeData::Int(self.u64()? as i64)),
+            3 => Ok(RuntimeData::Float(f64::from_bits(self.u64()?))),
+            4 => Ok(RuntimeData::Symbol(self.str()?)),
+            5 => Ok(RuntimeData::String(self.str()?)),
+            tag => Err(BytecodeError::InvalidTag { offset, tag }),
+        }
+    }
+
+    fn op(&mut self) -> Result<Op, BytecodeError> {
+        let offset = self.offset;
+        Ok(match self.u8()? {
+            0 => Op::Call(self.usize()?, self.usizes()?),
+            1 => Op::CallNative(self.usize()?, self.usizes()?),
+            2 => Op::DynCall(self.usize()?, self.usizes()?),
+            3 => Op::Resume(self.usize()?),
+            4 => Op::ReturnLocal(self.usize()?),
+            5 => Op::Jump(self.usize()?),
+            6 => Op::BranchTrue { label: self.usize()?, local: self.usize()? },
+            7 => Op::SetLocalData(self.usize()?, self.data()?),
+            8 => Op::SetLocalReturn(self.usize()?),
+            9 => Op::SetLocalVar { src: self.usize()?, dest: self.usize()? },
+            10 => Op::GetLength(self.usize()?),
+            11 => Op::GetType(self.usize()?),
+            12 => Op::GetSlot { local: self.usize()?, index: self.usize()? },
+            13 => Op::Closure { proc_id: self.usize()?, env: self.usizes()? },
+            14 => Op::Cons { sym_var: self.usize()?, params: self.usizes()? },
+            15 => Op::Coroutine { proc_id: self.usize()?, params: self.usizes()? },
+            16 => Op::DynCoroutine { local: self.usize()?, params: self.usizes()? },
+            17 => Op::Yield(self.usize()?),
+            18 => Op::Break,
+            19 => Op::InsertSlot { dest: self.usize()?, src: self.usize()?, index: self.usize()? },
+            20 => Op::RemoveSlot { local: self.usize()?, index: self.usize()? },
+            21 => Op::Delete(self.usize()?),
+            22 => Op::Nop,
+            23 => Op::Add(self.usize()?, self.usize()?),
+            24 => Op::Sub(self.usize()?, self.usize()?),
+            25 => Op::Mul(self.usize()?, self.usize()?),
+            26 => Op::Div(self.usize()?, self.usize()?),
+            27 => Op::Mod(self.usize()?, self.usize()?),
+            28 => Op::Neg(self.usize()?),
+            29 => Op::Eq(self.usize()?, self.usize()?),
+            30 => Op::Gt(self.usize()?, self.usize()?),
+            31 => Op::Lt(self.usize()?, self.usize()?),
+            32 => Op::Not(self.usize()?),
+            33 => Op::And(self.usize()?, self.usize()?),
+            34 => Op::Or(self.usize()?, self.usize()?),
+            35 => Op::Xor(self.usize()?, self.usize()?),
+            36 => Op::IsNil(self.usize()?),
+            37 => Op::ToString(self.usize()?),
+            38 => Op::Concat(self.usize()?, self.usize()?),
+            39 => Op::TailCall(self.usize()?, self.usizes()?),
+            40 => Op::DynTailCall(self.usize()?, self.usizes()?),
+            41 => Op::AddWrapping(self.usize()?, self.usize()?),
+            42 => Op::SubWrapping(self.usize()?, self.usize()?),
+            43 => Op::MulWrapping(self.usize()?, self.usize()?),
+            44 => Op::DivWrapping(self.usize()?, self.usize()?),
+            45 => Op::ModWrapping(self.usize()?, self.usize()?),
+            46 => Op::NegWrapping(self.usize()?),
+            47 => Op::StrLength(self.usize()?),
+            48 => Op::Substring { local: self.usize()?, start: self.usize()?, end: self.usize()? },
+            49 => Op::CharAt(self.usize()?, self.usize()?),
+            50 => Op::IndexOf(self.usize()?, self.usize()?),
+            51 => Op::Split(self.usize()?, self.usize()?),
+            52 => Op::Upper(self.usize()?),
+            53 => Op::Lower(self.usize()?),
+            54 => Op::Trim(self.usize()?),
+            55 => Op::ParseInt(self.usize()?),
+            56 => Op::ParseFloat(self.usize()?),
+            57 => Op::Print(self.usize()?),
+            58 => Op::PrintLine(self.usize()?),
+            59 => Op::ReadLine,
+            60 => Op::ReadFile(self.usize()?),
+            61 => Op::WriteFile { path: self.usize()?, contents: self.usize()? },
+            62 => Op::NewArray(self.usizes()?),
+            63 => Op::ArrayPush { dest: self.usize()?, src: self.usize()? },
+            64 => Op::ArrayPop(self.usize()?),
+            65 => Op::ArrayGet { local: self.usize()?, index: self.usize()? },
+            66 => Op::ArraySet { dest: self.usize()?, index: self.usize()?, src: self.usize()? },
+            67 => Op::NewMap,
+            68 => Op::MapGet { local: self.usize()?, key: self.usize()? },
+            69 => Op::MapInsert { dest: self.usize()?, key: self.usize()?, src: self.usize()? },
+            70 => Op::MapRemove { local: self.usize()?, key: self.usize()? },
+            71 => Op::MapContains { local: self.usize()?, key: self.usize()? },
+            72 => Op::MapKeys(self.usize()?),
+            73 => Op::DynGetSlot { local: self.usize()?, index: self.usize()? },
+            74 => Op::DynInsertSlot { dest: self.usize()?, src: self.usize()?, index: self.usize()? },
+            75 => Op::DynRemoveSlot { local: self.usize()?, index: self.usize()? },
+            76 => Op::Try { label: self.usize()?, local: self.usize()? },
+            77 => Op::EndTry,
+            78 => Op::Throw(self.usize()?),
+            tag => { return Err(BytecodeError::InvalidTag { offset, tag }); },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::proj;
+    use crate::parsing::ir_parser::parse;
+    use crate::compiling::ir_compiler::compile;
+
+    fn procs() -> Vec<Proc> {
+        let input = r#"
+proc main() -> Int {
+    set name : Symbol = ~blah;
+    set s : String = "a string";
+    set f : Float = 1.5;
+    set b : Bool = true;
+    set x : Int = -7;
+    set cell : Ref = cons name (s, f, b, x);
+    set c : Closure = closure other(x);
+    set y : Int = dyn_call c();
+    label end;
+    branch_true end b;
+    return y;
+}
+proc other(x : Int) -> Int {
+    return x;
+}
+"#;
+        compile(&parse(input).unwrap(), &[]).unwrap()
+    }
+
+    #[test]
+    fn should_round_trip() {
+        let input = procs();
+        let natives : Vec<Rc<str>> = vec!["native".into()];
+        let bytes = write(&input, &natives).unwrap();
+        let output = read(&bytes).unwrap();
+        assert_eq!(format!("{:?}", output.procs.iter().map(|x| (&x.name, &x.instrs, x.stack_size)).collect::<Vec<_>>()),
+                   format!("{:?}", input.iter().map(|x| (&x.name, &x.instrs, x.stack_size)).collect::<Vec<_>>()));
+        assert_eq!(output.natives, natives);
+    }
+
+    #[test]
+    fn should_reject_every_truncation() {
+        let bytes = write(&procs(), &[]).unwrap();
+        for len in 0..bytes.len() {
+            assert!(read(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn should_reject_every_corrupted_byte() {
+        let bytes = write(&procs(), &[]).unwrap();
+        for index in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[index] ^= 0x5a;
+            assert!(read(&corrupted).is_err());
+        }
+    }
+
+    #[test]
+    fn should_reject_bad_version() {
+        let mut bytes = write(&procs(), &[]).unwrap();
+        bytes[4] = 99;
+        assert!(matches!(read(&bytes), Err(BytecodeError::UnsupportedVersion(99))));
+    }
+
+    fn encode(f : impl FnOnce(&mut Writer) -> Result<(), BytecodeError>) -> Vec<u8> {
+        let mut w = Writer { bytes: vec![] };
+        f(&mut w).unwrap();
+        w.bytes
+    }
+
+    /// Replaces the only copy of from in the body and then fixes up the checksum, so that the
+    /// change gets past the checksum and on to validate.
+    fn patch(bytes : &[u8], from : &[u8], to : &[u8]) -> Vec<u8> {
+        let body = &bytes[..bytes.len() - 8];
+        let found = (0..body.len()).filter(|x| body[*x..].starts_with(from)).collect::<Vec<_>>();
+        assert_eq!(found.len(), 1);
+        let mut output = [&body[..found[0]], to, &body[found[0] + from.len()..]].concat();
+        let checksum = fnv1a(&output);
+        output.extend_from_slice(&checksum.to_le_bytes());
+        output
+    }
+
+    #[test]
+    fn should_validate_procs_read_from_bytes() {
+        let main = Proc { name: "main".into(), instrs: vec![
+            Op::Call(1, vec![0]),
+            Op::CallNative(0, vec![0]),
+            Op::Closure { proc_id: 1, env: vec![0] },
+            Op::Jump(4),
+            Op::ReturnLocal(1),
+        ], stack_size: 2, debug: DebugInfo::default() };
+        let one = Proc { name: "one".into(), instrs: vec![Op::ReturnLocal(0)], stack_size: 1, debug: DebugInfo::default() };
+        let natives : Vec<Rc<str>> = vec!["native".into()];
+        let bytes = write(&[main, one], &natives).unwrap();
+        assert!(read(&bytes).is_ok());
+
+        let name : Rc<str> = "main".into();
+        let op = |x : Op| encode(|w| w.op(&name, &x));
+        let cases = [
+            (op(Op::Jump(4)), op(Op::Jump(9)), "jumps to missing instr"),
+            (op(Op::ReturnLocal(1)), op(Op::ReturnLocal(2)), "outside of stack size"),
+            (op(Op::CallNative(0, vec![0])), op(Op::CallNative(1, vec![0])), "calls missing native"),
+            (op(Op::Call(1, vec![0])), op(Op::Call(2, vec![0])), "references missing proc"),
+            (op(Op::Call(1, vec![0])), op(Op::Call(1, vec![0, 0])), "more params"),
+            (op(Op::Closure { proc_id: 1, env: vec![0] }), op(Op::Closure { proc_id: 1, env: vec![0, 0] }), "more env"),
+            (encode(|w| { w.str("one")?; w.usize(1); Ok(()) }), encode(|w| { w.str("one")?; w.usize(MAX_STACK_SIZE + 1); Ok(()) }), "is larger than"),
+        ];
+        for (from, to, expected) in cases {
+            let message = proj!(read(&patch(&bytes, &from, &to)), Err(BytecodeError::InvalidProc { message, .. }), message);
+            assert!(message.contains(expected), "{message}");
+        }
+    }
+
+    #[test]
+    fn should_fail_to_write_length_over_u32() {
+        let mut w = Writer { bytes: vec![] };
+        assert!(w.len(u32::MAX as usize).is_ok());
+        assert!(matches!(w.len(u32::MAX as usize + 1), Err(BytecodeError::LengthTooLarge(_))));
+    }
+
+    #[test]
+    fn should_reject_invalid_proc() {
+        let input = vec![Proc { name: "main".into(), instrs: vec![Op::Jump(5)], stack_size: 0, debug: DebugInfo::default() }];
+        let bytes = write(&input, &[]).unwrap();
+        assert!(matches!(read(&bytes), Err(BytecodeError::InvalidProc { .. })));
+
+        let input = vec![Proc { name: "main".into(), instrs: vec![Op::ReturnLocal(1)], stack_size: 1, debug: DebugInfo::default() }];
+        let bytes = write(&input, &[]).unwrap();
+        assert!(matches!(read(&bytes), Err(BytecodeError::InvalidProc { .. })));
+
+        let input = vec![Proc { name: "main".into(), instrs: vec![Op::CallNative(0, vec![])], stack_size: 0, debug: DebugInfo::default() }];
+        let bytes = write(&input, &[]).unwrap();
+        assert!(matches!(read(&bytes), Err(BytecodeError::InvalidProc { .. })));
+
+        let input = vec![Proc { name: "main".into(), instrs: vec![], stack_size: MAX_STACK_SIZE + 1, debug: DebugInfo::default() }];
+        let bytes = write(&input, &[]).unwrap();
+        assert!(matches!(read(&bytes), Err(BytecodeError::InvalidProc { .. })));
+
+        let input = vec![Proc { name: "main".into(), instrs: vec![Op::Closure { proc_id: 0, env: vec![0, 0] }], stack_size: 1, debug: DebugInfo::default() }];
+        let bytes = write(&input, &[]).unwrap();
+        assert!(matches!(read(&bytes), Err(BytecodeError::InvalidProc { .. })));
+    }
+}
@@ -1,6 +1,12 @@
 
 use std::rc::Rc;
 
+use crate::util::{ Span, Source };
+
+use super::error::VmError;
+
+pub type NativeFn = fn(&mut [RuntimeData]) -> Result<RuntimeData, VmError>;
+
 #[derive(Debug, Clone)]
 pub enum Coroutine {
     Active(Frame),
@@ -16,6 +22,12 @@ pub struct Closure {
     pub env: Vec<RuntimeData>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeapRef {
+    pub addr: usize,
+    pub generation: usize,
+}
+
 #[derive(Debug, Clone)]
 pub enum RuntimeData {
     Bool(bool),
@@ -23,20 +35,29 @@ pub enum RuntimeData {
     Float(f64),
     Symbol(Rc<str>),
     String(Rc<str>),
-    Ref(usize),
+    Ref(HeapRef),
     Closure(Closure),
     Coroutine(Coroutine),
     Nil,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Op {
     Call(usize, Vec<usize>),
+    CallNative(usize, Vec<usize>),
     DynCall(usize, Vec<usize>),
+    /// Like Call, but replaces the current frame instead of pushing a new one.
+    TailCall(usize, Vec<usize>),
+    DynTailCall(usize, Vec<usize>),
     Resume(usize),
     ReturnLocal(usize), 
     Jump(usize),
     BranchTrue { label: usize, local: usize },
+    /// Pushes a handler onto the current frame.  A throw jumps to label and stores the thrown
+    /// value in local.
+    Try { label: usize, local: usize },
+    EndTry,
+    Throw(usize),
     SetLocalData(usize, RuntimeData),
     SetLocalReturn(usize),
     SetLocalVar { src: usize, dest: usize },
@@ -51,6 +72,10 @@ pub enum Op {
     Break,
     InsertSlot { dest: usize, src: usize, index: usize },
     RemoveSlot { local: usize, index: usize },
+    /// Like GetSlot, InsertSlot and RemoveSlot, but the index is read from an Int local.
+    DynGetSlot { local: usize, index: usize },
+    DynInsertSlot { dest: usize, src: usize, index: usize },
+    DynRemoveSlot { local: usize, index: usize },
     Delete(usize),
     Nop,
     Add(usize, usize),
@@ -59,6 +84,14 @@ pub enum Op {
     Div(usize, usize),
     Mod(usize, usize),
     Neg(usize),
+    /// Integer only versions of the arithmetic ops that wrap around instead of failing with 
+    /// IntegerOverflow.
+    AddWrapping(usize, usize),
+    SubWrapping(usize, usize),
+    MulWrapping(usize, usize),
+    DivWrapping(usize, usize),
+    ModWrapping(usize, usize),
+    NegWrapping(usize),
     Eq(usize, usize),
     Gt(usize, usize),
     Lt(usize, usize),
@@ -69,6 +102,108 @@ pub enum Op {
     IsNil(usize),
     ToString(usize),
     Concat(usize, usize),
+    StrLength(usize),
+    Substring { local: usize, start: usize, end: usize },
+    CharAt(usize, usize),
+    IndexOf(usize, usize),
+    Split(usize, usize),
+    Upper(usize),
+    Lower(usize),
+    Trim(usize),
+    ParseInt(usize),
+    ParseFloat(usize),
+    Print(usize),
+    PrintLine(usize),
+    ReadLine,
+    ReadFile(usize),
+    WriteFile { path: usize, contents: usize },
+    NewArray(Vec<usize>),
+    ArrayPush { dest: usize, src: usize },
+    ArrayPop(usize),
+    ArrayGet { local: usize, index: usize },
+    ArraySet { dest: usize, index: usize, src: usize },
+    NewMap,
+    MapGet { local: usize, key: usize },
+    MapInsert { dest: usize, key: usize, src: usize },
+    MapRemove { local: usize, key: usize },
+    MapContains { local: usize, key: usize },
+    /// Copies the keys of a map into a new array.
+    MapKeys(usize),
+}
+
+impl Op {
+    /// Every local index the op reads or writes.
+    pub fn locals(&self) -> Vec<usize> {
+        match self {
+            Op::Call(_, params) | Op::CallNative(_, params) | Op::TailCall(_, params) => params.clone(),
+            Op::DynCall(local, params) | Op::DynTailCall(local, params) | Op::Cons { sym_var: local, params } | Op::DynCoroutine { local, params } 
+                => std::iter::once(*local).chain(params.iter().copied()).collect(),
+            Op::Closure { env: params, .. } | Op::Coroutine { params, .. } | Op::NewArray(params) => params.clone(),
+            Op::Resume(a) | Op::ReturnLocal(a) | Op::SetLocalData(a, _) | Op::SetLocalReturn(a) | Op::GetLength(a) 
+            | Op::GetType(a) | Op::GetSlot { local: a, .. } | Op::Yield(a) | Op::RemoveSlot { local: a, .. } | Op::Delete(a) 
+            | Op::Neg(a) | Op::NegWrapping(a) | Op::Not(a) | Op::IsNil(a) | Op::ToString(a) | Op::BranchTrue { local: a, .. } | Op::Try { local: a, .. } | Op::Throw(a)
+            | Op::StrLength(a) | Op::Upper(a) | Op::Lower(a) | Op::Trim(a) | Op::ParseInt(a) | Op::ParseFloat(a) 
+            | Op::Print(a) | Op::PrintLine(a) | Op::ReadFile(a) | Op::ArrayPop(a) | Op::MapKeys(a) => vec![*a],
+            Op::SetLocalVar { src: a, dest: b } | Op::InsertSlot { dest: a, src: b, .. } 
+            | Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) | Op::Div(a, b) | Op::Mod(a, b) | Op::AddWrapping(a, b) 
+            | Op::SubWrapping(a, b) | Op::MulWrapping(a, b) | Op::DivWrapping(a, b) | Op::ModWrapping(a, b) | Op::Eq(a, b) | Op::Gt(a, b) 
+            | Op::Lt(a, b) | Op::And(a, b) | Op::Or(a, b) | Op::Xor(a, b) | Op::Concat(a, b) 
+            | Op::CharAt(a, b) | Op::IndexOf(a, b) | Op::Split(a, b) | Op::WriteFile { path: a, contents: b } 
+            | Op::ArrayPush { dest: a, src: b } | Op::ArrayGet { local: a, index: b }
+            | Op::MapGet { local: a, key: b } | Op::MapRemove { local: a, key: b } | Op::MapContains { local: a, key: b }
+            | Op::DynGetSlot { local: a, index: b } | Op::DynRemoveSlot { local: a, index: b } => vec![*a, *b],
+            Op::MapInsert { dest, key, src } => vec![*dest, *key, *src],
+            Op::DynInsertSlot { dest, src, index } => vec![*dest, *src, *index],
+            Op::ArraySet { dest, index, src } => vec![*dest, *index, *src],
+            Op::Substring { local, start, end } => vec![*local, *start, *end],
+            Op::Jump(_) | Op::Break | Op::Nop | Op::ReadLine | Op::NewMap | Op::EndTry => vec![],
+        }
+    }
+
+    /// Same as locals, but for renumbering them in place.
+    pub fn locals_mut(&mut self) -> Vec<&mut usize> {
+        match self {
+            Op::Call(_, params) | Op::CallNative(_, params) | Op::TailCall(_, params) => params.iter_mut().collect(),
+            Op::DynCall(local, params) | Op::DynTailCall(local, params) | Op::Cons { sym_var: local, params } | Op::DynCoroutine { local, params } 
+                => std::iter::once(local).chain(params.iter_mut()).collect(),
+            Op::Closure { env: params, .. } | Op::Coroutine { params, .. } | Op::NewArray(params) => params.iter_mut().collect(),
+            Op::Resume(a) | Op::ReturnLocal(a) | Op::SetLocalData(a, _) | Op::SetLocalReturn(a) | Op::GetLength(a) 
+            | Op::GetType(a) | Op::GetSlot { local: a, .. } | Op::Yield(a) | Op::RemoveSlot { local: a, .. } | Op::Delete(a) 
+            | Op::Neg(a) | Op::NegWrapping(a) | Op::Not(a) | Op::IsNil(a) | Op::ToString(a) | Op::BranchTrue { local: a, .. } | Op::Try { local: a, .. } | Op::Throw(a)
+            | Op::StrLength(a) | Op::Upper(a) | Op::Lower(a) | Op::Trim(a) | Op::ParseInt(a) | Op::ParseFloat(a) 
+            | Op::Print(a) | Op::PrintLine(a) | Op::ReadFile(a) | Op::ArrayPop(a) | Op::MapKeys(a) => vec![a],
+            Op::SetLocalVar { src: a, dest: b } | Op::InsertSlot { dest: a, src: b, .. } 
+            | Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) | Op::Div(a, b) | Op::Mod(a, b) | Op::AddWrapping(a, b) 
+            | Op::SubWrapping(a, b) | Op::MulWrapping(a, b) | Op::DivWrapping(a, b) | Op::ModWrapping(a, b) | Op::Eq(a, b) | Op::Gt(a, b) 
+            | Op::Lt(a, b) | Op::And(a, b) | Op::Or(a, b) | Op::Xor(a, b) | Op::Concat(a, b) 
+            | Op::CharAt(a, b) | Op::IndexOf(a, b) | Op::Split(a, b) | Op::WriteFile { path: a, contents: b } 
+            | Op::ArrayPush { dest: a, src: b } | Op::ArrayGet { local: a, index: b }
+            | Op::MapGet { local: a, key: b } | Op::MapRemove { local: a, key: b } | Op::MapContains { local: a, key: b }
+            | Op::DynGetSlot { local: a, index: b } | Op::DynRemoveSlot { local: a, index: b } => vec![a, b],
+            Op::MapInsert { dest, key, src } => vec![dest, key, src],
+            Op::DynInsertSlot { dest, src, index } => vec![dest, src, index],
+            Op::ArraySet { dest, index, src } => vec![dest, index, src],
+            Op::Substring { local, start, end } => vec![local, start, end],
+            Op::Jump(_) | Op::Break | Op::Nop | Op::ReadLine | Op::NewMap | Op::EndTry => vec![],
+        }
+    }
+
+    /// The local that the op sets, if any.  Try's local is only set once the handler is reached.
+    pub fn writes(&self) -> Option<usize> {
+        match self {
+            Op::SetLocalData(local, _) | Op::SetLocalReturn(local) | Op::SetLocalVar { dest: local, .. } => Some(*local),
+            _ => None,
+        }
+    }
+
+    /// Every local index the op reads.
+    pub fn reads(&self) -> Vec<usize> {
+        match self {
+            Op::SetLocalData(..) | Op::SetLocalReturn(_) | Op::Try { .. } => vec![],
+            Op::SetLocalVar { src, .. } => vec![*src],
+            x => x.locals(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -76,6 +211,36 @@ pub struct Proc {
     pub name : Rc<str>,
     pub instrs : Vec<Op>,
     pub stack_size : usize,
+    pub debug : DebugInfo,
+}
+
+/// Everything about a proc that is only needed for error messages and the debugger.  Empty when 
+/// the proc was not compiled from ir.
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    pub source : Option<Rc<Source>>,
+    /// Maps each instr index to the statement it was compiled from.
+    pub spans : Vec<Span>,
+    /// Maps each local index to its ir name.
+    pub locals : Vec<Rc<str>>,
+    /// Ir labels with the instr index they mark, in instr order.
+    pub labels : Vec<(Rc<str>, usize)>,
+    /// Calls to primitive procs that were replaced by the primitive's op, in instr order.
+    pub inlined : Vec<Inlined>,
+}
+
+impl DebugInfo {
+    pub fn inlined_at(&self, instr : usize) -> Option<&Inlined> {
+        self.inlined.binary_search_by_key(&instr, |x| x.instr).ok().map(|x| &self.inlined[x])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Inlined {
+    pub instr : usize,
+    pub proc_id : usize,
+    /// The call was a tail call, so the caller's frame would already have been replaced.
+    pub tail : bool,
 }
 
 #[derive(Debug, Clone)]
@@ -83,5 +248,13 @@ pub struct Frame {
     pub proc_id : usize,
     pub ip : usize,
     pub locals : Vec<RuntimeData>,
+    /// Active try handlers, innermost last.
+    pub handlers : Vec<Handler>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Handler {
+    pub label : usize,
+    pub local : usize,
 }
 
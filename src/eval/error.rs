@@ -1,43 +1,102 @@
 
 use std::rc::Rc;
 
-pub type StackTrace = Vec<(Rc<str>, usize)>;
+use crate::util::{ Span, Source, line_col, underline };
+
+use super::data::RuntimeData;
+
+#[derive(Debug)]
+pub struct Location {
+    pub source: Rc<Source>,
+    pub span: Span,
+}
+
+pub type StackTrace = Vec<(Rc<str>, usize, Option<Location>)>;
 
 #[derive(Debug)]
 pub enum VmError {
-    AccessNilHeap(usize, StackTrace),
-    AccessMissingSlotIndex { addr: usize, index: usize, stack_trace: StackTrace },
+    DanglingRef { addr: usize, generation: usize, stack_trace: StackTrace },
+    AccessMissingSlotIndex { addr: usize, index: i64, stack_trace: StackTrace },
+    ArrayIndexOutOfRange { addr: usize, index: i64, length: usize, stack_trace: StackTrace },
+    PopEmptyArray(usize, StackTrace),
+    HeapUnexpectedType { addr: usize, expected: &'static str, stack_trace: StackTrace },
     ProcDoesNotExist(usize, StackTrace),
+    NativeDoesNotExist(usize, StackTrace),
+    /// A closure's env and params together are more than the proc has locals.
+    TooManyArgs(usize, StackTrace),
+    /// Returned by native functions.  The vm replaces the stack trace with the one at the call site.
+    NativeError(Box<str>, StackTrace),
     InstrPointerOutOfRange(usize, StackTrace),
     AccessMissingReturn(StackTrace),
     AccessMissingLocal(usize, StackTrace),
     LocalUnexpectedType{local: usize, stack_trace: StackTrace, expected: &'static str, found: Box<str>},
+    StringIndexOutOfRange { index: i64, length: usize, stack_trace: StackTrace },
+    Io(Box<str>, StackTrace),
+    DivideByZero(StackTrace),
+    IntegerOverflow(StackTrace),
     TopLevelYield(usize),
+    /// A throw with no try handler anywhere on the stack.
+    Uncaught { value: RuntimeData, stack_trace: StackTrace },
+    NotRunning,
+    /// The vm is still running and can be continued with more fuel.
+    OutOfFuel(StackTrace),
 }
 
 impl std::fmt::Display for VmError {
     fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
         fn d(x : &StackTrace) -> String {
-            x.into_iter().map(|(n, i)| format!("    {} at index {}\n", n, i)).collect()
+            x.iter().map(|(n, i, l)| match l {
+                Some(Location { source, span }) => {
+                    let (line, col) = line_col(&source.text, span.start);
+                    format!("    {} at {}:{}:{}\n{}\n", n, source.file, line, col, underline(&source.text, span.start, span.end))
+                },
+                None => format!("    {} at index {}\n", n, i),
+            }).collect()
         }
 
         match self { 
             VmError::AccessMissingSlotIndex { addr, index, stack_trace } => 
                 write!(f, "Access missing slot index {} at address {}:  \n{}", index, addr, d(stack_trace)),
-            VmError::AccessNilHeap(addr, stack_trace) => 
-                write!(f, "Access nil heap at address {}:  \n{}", addr, d(stack_trace)),
+            VmError::ArrayIndexOutOfRange { addr, index, length, stack_trace } => 
+                write!(f, "Array index {} is out of range for array of length {} at address {}:  \n{}", index, length, addr, d(stack_trace)),
+            VmError::PopEmptyArray(addr, stack_trace) => 
+                write!(f, "Pop from empty array at address {}:  \n{}", addr, d(stack_trace)),
+            VmError::HeapUnexpectedType { addr, expected, stack_trace } => 
+                write!(f, "Heap object at address {} was unexpected type.  Expected: {}: \n{}", addr, expected, d(stack_trace)),
+            VmError::DanglingRef { addr, generation, stack_trace } => 
+                write!(f, "Access dangling ref at address {} with generation {}:  \n{}", addr, generation, d(stack_trace)),
             VmError::LocalUnexpectedType{local, stack_trace, expected, found } => 
                 write!(f, "Local {} was unexpected type.  Expected: {}, but found {}: \n{}", local, expected, found, d(stack_trace) ),
             VmError::ProcDoesNotExist(proc_index, trace) => 
                 write!(f, "Proc Index {} does not exist: \n{}", proc_index, d(trace)),
+            VmError::NativeDoesNotExist(native_index, trace) => 
+                write!(f, "Native Index {} does not exist: \n{}", native_index, d(trace)),
+            VmError::TooManyArgs(proc_index, trace) => 
+                write!(f, "Proc Index {} was given more args than it has locals: \n{}", proc_index, d(trace)),
+            VmError::NativeError(message, trace) => 
+                write!(f, "Native function failed:  {}\n{}", message, d(trace)),
             VmError::InstrPointerOutOfRange(instr, trace) => 
                 write!(f, "Instr Index {} does not exist: \n{}", instr, d(trace)),
             VmError::AccessMissingReturn(trace) => 
                 write!(f, "Attempting to access missing return: \n{}", d(trace)),
             VmError::AccessMissingLocal(local, trace) => 
                 write!(f, "Attempting to access missing local {}: \n{}", local, d(trace)),
+            VmError::StringIndexOutOfRange { index, length, stack_trace } => 
+                write!(f, "String index {} is out of range for string of length {}: \n{}", index, length, d(stack_trace)),
+            VmError::Io(message, trace) => 
+                write!(f, "Io failed:  {}\n{}", message, d(trace)),
+            VmError::DivideByZero(trace) => 
+                write!(f, "Attempting to divide by zero: \n{}", d(trace)),
+            VmError::IntegerOverflow(trace) => 
+                write!(f, "Integer overflow: \n{}", d(trace)),
+            VmError::Uncaught { value, stack_trace } =>
+                write!(f, "Uncaught throw of {:?}: \n{}", value, d(stack_trace)),
             VmError::TopLevelYield(ip) =>
                 write!(f, "Top Level Yield no supported at instruction: {}", ip),
+            VmError::NotRunning =>
+                write!(f, "Attempting to step a vm that is not running"),
+            VmError::OutOfFuel(trace) =>
+                write!(f, "Ran out of fuel: \n{}", d(trace)),
         }
     }
 }
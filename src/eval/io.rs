@@ -0,0 +1,97 @@
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{ HashMap, VecDeque };
+use std::io::{ BufRead, Write };
+
+/// Everything that the io primitives can touch outside of the vm.
+pub trait Io {
+    fn print(&mut self, text : &str) -> std::io::Result<()>;
+    /// Returns None at the end of input.  The line ending is not included.
+    fn read_line(&mut self) -> std::io::Result<Option<String>>;
+    fn read_file(&mut self, path : &str) -> std::io::Result<String>;
+    fn write_file(&mut self, path : &str, contents : &str) -> std::io::Result<()>;
+}
+
+/// Real stdin, stdout and file system.
+pub struct StdIo;
+
+impl Io for StdIo {
+    fn print(&mut self, text : &str) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(text.as_bytes())?;
+        stdout.flush()
+    }
+
+    fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = String::new();
+        if std::io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let length = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(length);
+        Ok(Some(line))
+    }
+
+    fn read_file(&mut self, path : &str) -> std::io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn write_file(&mut self, path : &str, contents : &str) -> std::io::Result<()> {
+        std::fs::write(path, contents)
+    }
+}
+
+/// In memory input, output and files.  Clones share the same buffers, so a clone can be kept
+/// to inspect what a program did after the original has been given to the vm.
+#[derive(Clone, Default)]
+pub struct MemoryIo {
+    output: Rc<RefCell<String>>,
+    input: Rc<RefCell<VecDeque<String>>>,
+    files: Rc<RefCell<HashMap<String, String>>>,
+}
+
+impl MemoryIo {
+    pub fn new() -> Self {
+        MemoryIo::default()
+    }
+
+    pub fn push_line(&self, line : &str) {
+        self.input.borrow_mut().push_back(line.to_string());
+    }
+
+    pub fn add_file(&self, path : &str, contents : &str) {
+        self.files.borrow_mut().insert(path.to_string(), contents.to_string());
+    }
+
+    pub fn output(&self) -> String {
+        self.output.borrow().clone()
+    }
+
+    pub fn file(&self, path : &str) -> Option<String> {
+        self.files.borrow().get(path).cloned()
+    }
+}
+
+impl Io for MemoryIo {
+    fn print(&mut self, text : &str) -> std::io::Result<()> {
+        self.output.borrow_mut().push_str(text);
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        Ok(self.input.borrow_mut().pop_front())
+    }
+
+    fn read_file(&mut self, path : &str) -> std::io::Result<String> {
+        match self.files.borrow().get(path) {
+            Some(x) => Ok(x.clone()),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{path} does not exist"))),
+        }
+    }
+
+    fn write_file(&mut self, path : &str, contents : &str) -> std::io::Result<()> {
+        self.files.borrow_mut().insert(path.to_string(), contents.to_string());
+        Ok(())
+    }
+}
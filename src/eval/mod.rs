@@ -2,3 +2,5 @@
 pub mod data;
 pub mod error;
 pub mod vm;
+pub mod bytecode;
+pub mod io;
@@ -0,0 +1,202 @@
+
+use std::rc::Rc;
+use std::collections::HashMap;
+
+use crate::eval::data::{ RuntimeData, HeapRef };
+
+#[derive(Debug)]
+pub enum Heap {
+    Cons { name: Rc<str>, params: Vec<RuntimeData> },
+    Array(Vec<RuntimeData>),
+    Map(HashMap<Key, RuntimeData>),
+}
+
+/// The subset of RuntimeData that can be used as a map key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Int(i64),
+    Bool(bool),
+    Symbol(Rc<str>),
+    String(Rc<str>),
+}
+
+impl Key {
+    pub fn from_data(data : &RuntimeData) -> Option<Key> {
+        match data {
+            RuntimeData::Int(x) => Some(Key::Int(*x)),
+            RuntimeData::Bool(x) => Some(Key::Bool(*x)),
+            RuntimeData::Symbol(x) => Some(Key::Symbol(Rc::clone(x))),
+            RuntimeData::String(x) => Some(Key::String(Rc::clone(x))),
+            _ => None,
+        }
+    }
+}
+
+impl From<Key> for RuntimeData {
+    fn from(key : Key) -> Self {
+        match key {
+            Key::Int(x) => RuntimeData::Int(x),
+            Key::Bool(x) => RuntimeData::Bool(x),
+            Key::Symbol(x) => RuntimeData::Symbol(x),
+            Key::String(x) => RuntimeData::String(x),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cell {
+    // Note:  Bumped every time the cell is freed so that refs to the old object can be detected.
+    generation: usize,
+    /// None once the cell has been freed.
+    value: Option<Heap>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeapStats {
+    pub live: usize,
+    pub free: usize,
+    pub peak: usize,
+}
+
+#[derive(Debug)]
+pub struct Allocator {
+    cells: Vec<Cell>,
+    free: Vec<usize>,
+    live: usize,
+    peak: usize,
+}
+
+impl Allocator {
+    pub fn new() -> Self {
+        Allocator { cells: vec![], free: vec![], live: 0, peak: 0 }
+    }
+
+    pub fn alloc(&mut self, value : Heap) -> HeapRef {
+        self.live += 1;
+        self.peak = self.peak.max(self.live);
+        match self.free.pop() {
+            Some(addr) => {
+                let cell = &mut self.cells[addr];
+                cell.value = Some(value);
+                HeapRef { addr, generation: cell.generation }
+            },
+            None => {
+                self.cells.push(Cell { generation: 0, value: Some(value) });
+                HeapRef { addr: self.cells.len() - 1, generation: 0 }
+            },
+        }
+    }
+
+    /// Frees the cell at addr.  Freeing a cell that is already free does nothing.
+    pub fn free(&mut self, addr : usize) {
+        let cell = &mut self.cells[addr];
+        if cell.value.take().is_some() {
+            cell.generation += 1;
+            self.free.push(addr);
+            self.live -= 1;
+        }
+    }
+
+    /// Returns None when the ref points at a cell that has been freed since it was created.
+    pub fn get(&self, r : HeapRef) -> Option<&Heap> {
+        match self.cells.get(r.addr) {
+            Some(cell) if cell.generation == r.generation => cell.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, r : HeapRef) -> Option<&mut Heap> {
+        match self.cells.get_mut(r.addr) {
+            Some(cell) if cell.generation == r.generation => cell.value.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Number of cells ever created, free or not.  Addresses are always below this.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Every live cell along with a ref to it.
+    pub fn iter(&self) -> impl Iterator<Item = (HeapRef, &Heap)> {
+        self.cells.iter().enumerate()
+            .filter_map(|(addr, cell)| Some((HeapRef { addr, generation: cell.generation }, cell.value.as_ref()?)))
+    }
+
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats { live: self.live, free: self.free.len(), peak: self.peak }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cons() -> Heap {
+        Heap::Cons { name: "x".into(), params: vec![] }
+    }
+
+    #[test]
+    fn should_iterate_live_cells() {
+        let mut heap = Allocator::new();
+        let a = heap.alloc(cons());
+        let b = heap.alloc(cons());
+        heap.free(a.addr);
+        let output = heap.iter().map(|(r, _)| r).collect::<Vec<_>>();
+        assert_eq!(output, vec![b]);
+    }
+
+    #[test]
+    fn should_reuse_freed_cell() {
+        let mut heap = Allocator::new();
+        let a = heap.alloc(cons());
+        let _ = heap.alloc(cons());
+        heap.free(a.addr);
+        let c = heap.alloc(cons());
+        assert_eq!(c.addr, a.addr);
+        assert_eq!(c.generation, a.generation + 1);
+        assert_eq!(heap.len(), 2);
+    }
+
+    #[test]
+    fn should_ignore_double_free() {
+        let mut heap = Allocator::new();
+        let a = heap.alloc(cons());
+        heap.free(a.addr);
+        heap.free(a.addr);
+        assert_eq!(heap.stats(), HeapStats { live: 0, free: 1, peak: 1 });
+    }
+
+    #[test]
+    fn should_reject_stale_ref() {
+        let mut heap = Allocator::new();
+        let a = heap.alloc(cons());
+        heap.free(a.addr);
+        let _ = heap.alloc(cons());
+        assert!(heap.get(a).is_none());
+    }
+
+    #[test]
+    fn should_not_get_freed_cell() {
+        let mut heap = Allocator::new();
+        let a = heap.alloc(cons());
+        heap.free(a.addr);
+        assert!(heap.get(a).is_none());
+        assert!(heap.get_mut(a).is_none());
+    }
+
+    #[test]
+    fn should_track_stats() {
+        let mut heap = Allocator::new();
+        let a = heap.alloc(cons());
+        let b = heap.alloc(cons());
+        let _ = heap.alloc(cons());
+        heap.free(a.addr);
+        heap.free(b.addr);
+        assert_eq!(heap.stats(), HeapStats { live: 1, free: 2, peak: 3 });
+    }
+}
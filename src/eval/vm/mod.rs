@@ -0,0 +1,1432 @@
+
+mod heap;
+
+use std::rc::Rc;
+use std::collections::HashMap;
+
+use crate::util::proj;
+
+use super::data::*;
+use super::error::*;
+use super::io::{ Io, StdIo };
+
+use heap::Allocator;
+pub use heap::{ Heap, HeapStats, Key };
+
+macro_rules! proj_type {
+    ($self:expr, $local:expr, bool) => {{
+        if $local >= $self.current.locals.len() {
+           Err(VmError::AccessMissingLocal($local, $self.stack_trace()))
+        }
+        else if !matches!( $self.current.locals[$local], RuntimeData::Bool(_) ) {
+            $self.local_unexpected_type($local, "bool")
+        }
+        else {
+            Ok(proj!($self.current.locals[$local], RuntimeData::Bool(x), x))
+        }
+    }};
+    ($self:expr, $local:expr, int) => {{
+        if $local >= $self.current.locals.len() {
+           Err(VmError::AccessMissingLocal($local, $self.stack_trace()))
+        }
+        else if !matches!( $self.current.locals[$local], RuntimeData::Int(_) ) {
+            $self.local_unexpected_type($local, "int")
+        }
+        else {
+            Ok(proj!($self.current.locals[$local], RuntimeData::Int(x), x))
+        }
+    }};
+    ($self:expr, $local:expr, ref) => {{
+        if $local >= $self.current.locals.len() {
+           Err(VmError::AccessMissingLocal($local, $self.stack_trace()))
+        }
+        else if !matches!( $self.current.locals[$local], RuntimeData::Ref(_) ) {
+            $self.local_unexpected_type($local, "ref")
+        }
+        else {
+            Ok(proj!($self.current.locals[$local], RuntimeData::Ref(x), x))
+        }
+    }};
+    ($self:expr, $local:expr, closure) => {{
+        if $local >= $self.current.locals.len() {
+           Err(VmError::AccessMissingLocal($local, $self.stack_trace()))
+        }
+        else if !matches!( $self.current.locals[$local], RuntimeData::Closure(_) ) {
+            $self.local_unexpected_type($local, "closure")
+        }
+        else {
+            Ok(proj!($self.current.locals[$local], RuntimeData::Closure(ref x), x))
+        }
+    }};
+    ($self:expr, $local:expr, string) => {{
+        if $local >= $self.current.locals.len() {
+           Err(VmError::AccessMissingLocal($local, $self.stack_trace()))
+        }
+        else if !matches!( $self.current.locals[$local], RuntimeData::String(_) ) {
+            $self.local_unexpected_type($local, "string")
+        }
+        else {
+            Ok(proj!($self.current.locals[$local], RuntimeData::String(ref x), Rc::clone(x)))
+        }
+    }};
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    /// Number of live heap cells that triggers the first collection.
+    pub threshold : usize,
+    /// After a collection the next threshold is the surviving cell count times this factor.
+    pub growth_factor : usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig { threshold: 1024, growth_factor: 2 }
+    }
+}
+
+#[derive(Debug)]
+pub enum Status {
+    Running,
+    Finished(Option<RuntimeData>),
+}
+
+pub struct Vm {
+    procs: Vec<Proc>,
+    natives: Vec<NativeFn>,
+    heap: Allocator,
+    frames : Vec<Frame>,
+    current : Frame,
+    ret : Option<RuntimeData>,
+    running : bool,
+    gc : GcConfig,
+    next_gc : usize,
+    io : Box<dyn Io>,
+}
+
+impl Vm {
+    pub fn new(procs: Vec<Proc>, natives: Vec<NativeFn>, gc : GcConfig) -> Self {
+        let current = Frame { proc_id: 0, ip: 0, locals: vec![], handlers: vec![] };
+        Vm { procs, natives, heap: Allocator::new(), frames: vec![], current, ret: None, running: false, gc, next_gc: gc.threshold, io: Box::new(StdIo) }
+    }
+
+    /// Replaces the stdio that the io primitives use by default.
+    pub fn set_io(&mut self, io : Box<dyn Io>) {
+        self.io = io;
+    }
+
+    pub fn run(&mut self, entry : usize) -> Result<Option<RuntimeData>, VmError> {
+        self.call(entry, vec![])
+    }
+
+    /// Runs the entry proc with args as its first locals.  Arity and types are not checked here.
+    pub fn call(&mut self, entry : usize, args : Vec<RuntimeData>) -> Result<Option<RuntimeData>, VmError> {
+        self.start(entry, args)?;
+        loop {
+            if let Status::Finished(x) = self.step()? {
+                return Ok(x);
+            }
+        }
+    }
+
+    /// Like run, but gives up with OutOfFuel after executing fuel ops.  The vm is left as it
+    /// was, so continue_with_fuel can pick up where it stopped.
+    pub fn run_with_fuel(&mut self, entry : usize, fuel : usize) -> Result<Option<RuntimeData>, VmError> {
+        self.start(entry, vec![])?;
+        self.continue_with_fuel(fuel)
+    }
+
+    pub fn continue_with_fuel(&mut self, fuel : usize) -> Result<Option<RuntimeData>, VmError> {
+        for _ in 0..fuel {
+            if let Status::Finished(x) = self.step()? {
+                return Ok(x);
+            }
+        }
+        Err(VmError::OutOfFuel(self.stack_trace()))
+    }
+
+    /// Sets up the entry proc so that it can be executed one op at a time with step.
+    pub fn start(&mut self, entry : usize, args : Vec<RuntimeData>) -> Result<(), VmError> {
+        if entry >= self.procs.len() {
+            return Err(VmError::ProcDoesNotExist(entry, self.stack_trace()));
+        }
+
+        let mut locals = args;
+        locals.resize(self.procs[entry].stack_size.max(locals.len()), RuntimeData::Nil);
+        self.current = Frame { proc_id: entry, ip: 0, locals, handlers: vec![] };
+        self.frames.clear();
+        self.ret = None;
+        self.running = true;
+        Ok(())
+    }
+
+    /// Executes the next op.  Errors leave the vm where it was, so stepping again repeats the error.
+    pub fn step(&mut self) -> Result<Status, VmError> {
+        if !self.running {
+            return Err(VmError::NotRunning);
+        }
+
+        if self.current.ip >= self.procs[self.current.proc_id].instrs.len() {
+            // Note:  if the current procedure isn't pushed onto the return stack, then the
+            // stack trace will leave out the current procedure where the problem is occurring.
+            return Err(VmError::InstrPointerOutOfRange(self.current.ip, self.stack_trace()));
+        }
+
+        match self.procs[self.current.proc_id].instrs[self.current.ip] {
+            Op::Call(proc_id, _) if proc_id >= self.procs.len() => {
+                return Err(VmError::ProcDoesNotExist(self.current.proc_id, self.stack_trace()));
+            },
+            Op::Call(proc_id, ref params) => {
+                let mut new_locals = self.clone_locals(params)?;
+                self.current.ip += 1;
+                new_locals.append(&mut std::iter::repeat(RuntimeData::Nil).take(self.procs[proc_id].stack_size - params.len()).collect());
+                let current = std::mem::replace(&mut self.current, Frame { proc_id: proc_id, ip: 0, locals: new_locals, handlers: vec![] });
+                self.frames.push(current);
+            },
+            Op::CallNative(native_id, _) if native_id >= self.natives.len() => {
+                return Err(VmError::NativeDoesNotExist(native_id, self.stack_trace()));
+            },
+            Op::CallNative(native_id, ref params) => {
+                let mut args = self.clone_locals(params)?;
+                match (self.natives[native_id])(&mut args) {
+                    Ok(x) => { self.ret = Some(x); },
+                    Err(VmError::NativeError(message, _)) => { return Err(VmError::NativeError(message, self.stack_trace())); },
+                    Err(x) => { return Err(x); },
+                }
+                self.current.ip += 1;
+            },
+            Op::DynCall(local, ref params) => {
+                let Closure { proc_id, env } = proj_type!(self, local, closure)?; 
+                let proc_id = *proc_id;
+                let Some(rest) = self.procs[proc_id].stack_size.checked_sub(env.len() + params.len()) else {
+                    return Err(VmError::TooManyArgs(proc_id, self.stack_trace()));
+                };
+                let mut new_locals = env.clone();
+                let mut params = self.clone_locals(params)?;
+                new_locals.append(&mut params);
+                self.current.ip += 1;
+
+                new_locals.append(&mut std::iter::repeat(RuntimeData::Nil).take(rest).collect());
+                let current = std::mem::replace(&mut self.current, Frame { proc_id: proc_id, ip: 0, locals: new_locals, handlers: vec![] });
+                self.frames.push(current);
+            },
+            Op::TailCall(proc_id, _) if proc_id >= self.procs.len() => {
+                return Err(VmError::ProcDoesNotExist(proc_id, self.stack_trace()));
+            },
+            Op::TailCall(proc_id, ref params) => {
+                let args = self.clone_locals(params)?;
+                self.replace_frame(proc_id, args);
+            },
+            Op::DynTailCall(local, ref params) => {
+                let Closure { proc_id, env } = proj_type!(self, local, closure)?; 
+                let proc_id = *proc_id;
+                if env.len() + params.len() > self.procs[proc_id].stack_size {
+                    return Err(VmError::TooManyArgs(proc_id, self.stack_trace()));
+                }
+                let mut args = env.clone();
+                args.append(&mut self.clone_locals(params)?);
+                self.replace_frame(proc_id, args);
+            },
+            Op::Jump(label) => {
+                self.current.ip = label;
+            },
+            Op::BranchTrue { label, local } => {
+                let test = proj_type!(self, local, bool)?;
+                if test {
+                    self.current.ip = label;
+                }
+                else {
+                    self.current.ip += 1;
+                }
+            },
+            Op::ReturnLocal(local) if local >= self.current.locals.len() => {
+                return Err(VmError::AccessMissingLocal(local, self.stack_trace()));
+            },
+            Op::ReturnLocal(local) => {
+                self.ret = Some(self.current.locals.swap_remove(local));
+
+                match self.frames.pop() {
+                    // Note:  if the stack is empty then all execution is finished
+                    None => {
+                        self.running = false;
+                        return Ok(Status::Finished(self.ret.take()));
+                    },
+                    Some(frame) => {
+                        self.current = frame;
+                    },
+                }
+            },
+            Op::SetLocalData(local, ref data) => {
+                *self.mut_local(local)? = data.clone();
+                self.current.ip += 1;
+            },
+            Op::SetLocalReturn(_) if self.ret.is_none()  => {
+                return Err(VmError::AccessMissingReturn(self.stack_trace()));
+            },
+            Op::SetLocalReturn(local) => {
+                *self.mut_local(local)? = self.ret.take().unwrap();
+                self.current.ip += 1;
+            },
+            Op::SetLocalVar { src, dest } => {
+                *self.mut_local(dest)? = self.get_local(src)?.clone();
+                self.current.ip += 1;
+            },
+
+            Op::Add(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Float(a), RuntimeData::Float(b)) => { self.ret = Some( RuntimeData::Float(a + b) ); },
+                    (RuntimeData::Int(a), RuntimeData::Int(b)) => {
+                        match a.checked_add(*b) {
+                            Some(x) => { self.ret = Some( RuntimeData::Int(x) ); },
+                            None => { return Err(VmError::IntegerOverflow(self.stack_trace())); },
+                        }
+                    },
+                    (RuntimeData::Int(_), _) => { return self.local_unexpected_type(b, "int"); },
+                    (RuntimeData::Float(_), _) => { return self.local_unexpected_type(b, "float"); },
+                    _ => { return self.local_unexpected_type(a, "number"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Sub(a, b) => {
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Float(a), RuntimeData::Float(b)) => { self.ret = Some( RuntimeData::Float(a - b) ); },
+                    (RuntimeData::Int(a), RuntimeData::Int(b)) => {
+                        match a.checked_sub(*b) {
+                            Some(x) => { self.ret = Some( RuntimeData::Int(x) ); },
+                            None => { return Err(VmError::IntegerOverflow(self.stack_trace())); },
+                        }
+                    },
+                    (RuntimeData::Int(_), _) => { return self.local_unexpected_type(b, "int"); },
+                    (RuntimeData::Float(_), _) => { return self.local_unexpected_type(b, "float"); },
+                    _ => { return self.local_unexpected_type(a, "number"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Mul(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Float(a), RuntimeData::Float(b)) => { self.ret = Some( RuntimeData::Float(a * b) ); },
+                    (RuntimeData::Int(a), RuntimeData::Int(b)) => {
+                        match a.checked_mul(*b) {
+                            Some(x) => { self.ret = Some( RuntimeData::Int(x) ); },
+                            None => { return Err(VmError::IntegerOverflow(self.stack_trace())); },
+                        }
+                    },
+                    (RuntimeData::Int(_), _) => { return self.local_unexpected_type(b, "int"); },
+                    (RuntimeData::Float(_), _) => { return self.local_unexpected_type(b, "float"); },
+                    _ => { return self.local_unexpected_type(a, "number"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Div(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Float(a), RuntimeData::Float(b)) => { self.ret = Some( RuntimeData::Float(a / b) ); },
+                    (RuntimeData::Int(_), RuntimeData::Int(0)) => { return Err(VmError::DivideByZero(self.stack_trace())); },
+                    (RuntimeData::Int(a), RuntimeData::Int(b)) => {
+                        match a.checked_div(*b) {
+                            Some(x) => { self.ret = Some( RuntimeData::Int(x) ); },
+                            None => { return Err(VmError::IntegerOverflow(self.stack_trace())); },
+                        }
+                    },
+                    (RuntimeData::Int(_), _) => { return self.local_unexpected_type(b, "int"); },
+                    (RuntimeData::Float(_), _) => { return self.local_unexpected_type(b, "float"); },
+                    _ => { return self.local_unexpected_type(a, "number"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Mod(a, b) => {
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Float(a), RuntimeData::Float(b)) => { self.ret = Some( RuntimeData::Float(a % b) ); },
+                    (RuntimeData::Int(_), RuntimeData::Int(0)) => { return Err(VmError::DivideByZero(self.stack_trace())); },
+                    (RuntimeData::Int(a), RuntimeData::Int(b)) => {
+                        match a.checked_rem(*b) {
+                            Some(x) => { self.ret = Some( RuntimeData::Int(x) ); },
+                            None => { return Err(VmError::IntegerOverflow(self.stack_trace())); },
+                        }
+                    },
+                    (RuntimeData::Int(_), _) => { return self.local_unexpected_type(b, "int"); },
+                    (RuntimeData::Float(_), _) => { return self.local_unexpected_type(b, "float"); },
+                    _ => { return self.local_unexpected_type(a, "number"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Neg(x) => { 
+                match self.get_local(x)? {
+                    RuntimeData::Float(x) => { self.ret = Some( RuntimeData::Float(-x) ); },        
+                    RuntimeData::Int(x) => {
+                        match x.checked_neg() {
+                            Some(x) => { self.ret = Some( RuntimeData::Int(x) ); },
+                            None => { return Err(VmError::IntegerOverflow(self.stack_trace())); },
+                        }
+                    },
+                    _ => { return self.local_unexpected_type(x, "number"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::AddWrapping(a, b) => {
+                let (a, b) = (proj_type!(self, a, int)?, proj_type!(self, b, int)?);
+                self.ret = Some( RuntimeData::Int(a.wrapping_add(b)) );
+                self.current.ip += 1;
+            },
+
+            Op::SubWrapping(a, b) => {
+                let (a, b) = (proj_type!(self, a, int)?, proj_type!(self, b, int)?);
+                self.ret = Some( RuntimeData::Int(a.wrapping_sub(b)) );
+                self.current.ip += 1;
+            },
+
+            Op::MulWrapping(a, b) => {
+                let (a, b) = (proj_type!(self, a, int)?, proj_type!(self, b, int)?);
+                self.ret = Some( RuntimeData::Int(a.wrapping_mul(b)) );
+                self.current.ip += 1;
+            },
+
+            Op::DivWrapping(a, b) => {
+                let (a, b) = (proj_type!(self, a, int)?, proj_type!(self, b, int)?);
+                if b == 0 {
+                    return Err(VmError::DivideByZero(self.stack_trace()));
+                }
+                self.ret = Some( RuntimeData::Int(a.wrapping_div(b)) );
+                self.current.ip += 1;
+            },
+
+            Op::ModWrapping(a, b) => {
+                let (a, b) = (proj_type!(self, a, int)?, proj_type!(self, b, int)?);
+                if b == 0 {
+                    return Err(VmError::DivideByZero(self.stack_trace()));
+                }
+                self.ret = Some( RuntimeData::Int(a.wrapping_rem(b)) );
+                self.current.ip += 1;
+            },
+
+            Op::NegWrapping(x) => {
+                let x = proj_type!(self, x, int)?;
+                self.ret = Some( RuntimeData::Int(x.wrapping_neg()) );
+                self.current.ip += 1;
+            },
+
+            Op::Eq(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Float(a), RuntimeData::Float(b)) => { self.ret = Some( RuntimeData::Bool(a == b) ); },
+                    (RuntimeData::Int(a), RuntimeData::Int(b)) => { self.ret = Some( RuntimeData::Bool(a == b) ); },
+                    (RuntimeData::Bool(a), RuntimeData::Bool(b)) => { self.ret = Some( RuntimeData::Bool(a == b) ); },
+                    (RuntimeData::Symbol(a), RuntimeData::Symbol(b)) => { self.ret = Some( RuntimeData::Bool(a == b) ); },
+                    (RuntimeData::String(a), RuntimeData::String(b)) => { self.ret = Some( RuntimeData::Bool(a == b) ); },
+                    (RuntimeData::Nil, RuntimeData::Nil) => { self.ret = Some( RuntimeData::Bool(true) ); },
+                    (RuntimeData::Ref(a), RuntimeData::Ref(b)) => { self.ret = Some( RuntimeData::Bool(a == b) ); },
+
+                    (RuntimeData::Closure { .. }, RuntimeData::Closure { .. }) => { self.ret = Some( RuntimeData::Bool(false) ); },
+                    (RuntimeData::Coroutine(_), RuntimeData::Coroutine(_)) => { self.ret = Some( RuntimeData::Bool(false) ); },
+
+                    (RuntimeData::Float(_), _) => { return self.local_unexpected_type(b, "float"); },
+                    (RuntimeData::Int(_), _) => { return self.local_unexpected_type(b, "int"); },
+                    (RuntimeData::Bool(_), _) => { return self.local_unexpected_type(b, "bool"); },
+                    (RuntimeData::Symbol(_), _) => { return self.local_unexpected_type(b, "symbol"); },
+                    (RuntimeData::String(_), _) => { return self.local_unexpected_type(b, "string"); },
+                    (RuntimeData::Nil, _) => { return self.local_unexpected_type(b, "nil"); },
+                    (RuntimeData::Ref(_), _) => { return self.local_unexpected_type(b, "ref"); }, 
+                    (RuntimeData::Closure { .. }, _) => { return self.local_unexpected_type(b, "closure"); },
+                    (RuntimeData::Coroutine(_), _) => { return self.local_unexpected_type(b, "coroutine"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Gt(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Float(a), RuntimeData::Float(b)) => { self.ret = Some( RuntimeData::Bool(a > b) ); },
+                    (RuntimeData::Int(a), RuntimeData::Int(b)) => { self.ret = Some( RuntimeData::Bool(a > b) ); },
+                    (RuntimeData::String(a), RuntimeData::String(b)) => { self.ret = Some( RuntimeData::Bool(a > b) ); },
+                    (RuntimeData::Int(_), _) => { return self.local_unexpected_type(b, "int"); },
+                    (RuntimeData::Float(_), _) => { return self.local_unexpected_type(b, "float"); },
+                    (RuntimeData::String(_), _) => { return self.local_unexpected_type(b, "string"); },
+                    _ => { return self.local_unexpected_type(a, "number or string"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Lt(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Float(a), RuntimeData::Float(b)) => { self.ret = Some( RuntimeData::Bool(a < b) ); },
+                    (RuntimeData::Int(a), RuntimeData::Int(b)) => { self.ret = Some( RuntimeData::Bool(a < b) ); },
+                    (RuntimeData::String(a), RuntimeData::String(b)) => { self.ret = Some( RuntimeData::Bool(a < b) ); },
+                    (RuntimeData::Int(_), _) => { return self.local_unexpected_type(b, "int"); },
+                    (RuntimeData::Float(_), _) => { return self.local_unexpected_type(b, "float"); },
+                    (RuntimeData::String(_), _) => { return self.local_unexpected_type(b, "string"); },
+                    _ => { return self.local_unexpected_type(a, "number or string"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Not(x) => { 
+                match self.get_local(x)? {
+                    RuntimeData::Bool(x) => { self.ret = Some( RuntimeData::Bool(!x) ); },        
+                    _ => { return self.local_unexpected_type(x, "bool"); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::And(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Bool(a), RuntimeData::Bool(b)) => { self.ret = Some( RuntimeData::Bool(*a && *b) ); },
+                    (_, RuntimeData::Bool(_)) => { return self.local_unexpected_type(a, "bool");  },
+                    _ => { return self.local_unexpected_type(b, "bool");  },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Or(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Bool(a), RuntimeData::Bool(b)) => { self.ret = Some( RuntimeData::Bool(*a || *b) ); },
+                    (_, RuntimeData::Bool(_)) => { return self.local_unexpected_type(a, "bool");  },
+                    _ => { return self.local_unexpected_type(b, "bool");  },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Xor(a, b) => { 
+                match (self.get_local(a)?, self.get_local(b)?) {
+                    (RuntimeData::Bool(a), RuntimeData::Bool(b)) => { self.ret = Some( RuntimeData::Bool(*a ^ *b) ); },
+                    (_, RuntimeData::Bool(_)) => { return self.local_unexpected_type(a, "bool");  },
+                    _ => { return self.local_unexpected_type(b, "bool");  },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::Cons { sym_var, ref params } => {
+                let params = self.clone_locals(params)?;
+                let name = match self.get_local(sym_var)? {
+                    RuntimeData::Symbol(x) => Rc::clone(x),
+                    _ => { return self.local_unexpected_type(sym_var, "symbol"); },
+                };
+
+                self.ret = Some( RuntimeData::Ref( self.alloc(Heap::Cons { name, params }) ) );
+                self.current.ip += 1;
+            },
+
+            Op::NewArray(ref params) => {
+                let values = self.clone_locals(params)?;
+                self.ret = Some( RuntimeData::Ref( self.alloc(Heap::Array(values)) ) );
+                self.current.ip += 1;
+            },
+
+            Op::ArrayPush { dest, src } => {
+                let r = proj_type!(self, dest, ref)?;
+                let input = self.get_local(src)?.clone();
+                self.array(r)?.push(input);
+                self.current.ip += 1;
+            },
+
+            Op::ArrayPop(local) => {
+                let r = proj_type!(self, local, ref)?;
+                match self.array(r)?.pop() {
+                    Some(x) => { self.ret = Some(x); },
+                    None => { return Err(VmError::PopEmptyArray(r.addr, self.stack_trace())); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::ArrayGet { local, index } => {
+                let r = proj_type!(self, local, ref)?;
+                let index = proj_type!(self, index, int)?;
+                let length = self.array(r)?.len();
+                let index = self.array_index(r.addr, index, length)?;
+                self.ret = Some(self.array(r)?[index].clone());
+                self.current.ip += 1;
+            },
+
+            Op::ArraySet { dest, index, src } => {
+                let r = proj_type!(self, dest, ref)?;
+                let index = proj_type!(self, index, int)?;
+                let input = self.get_local(src)?.clone();
+                let length = self.array(r)?.len();
+                let index = self.array_index(r.addr, index, length)?;
+                self.array(r)?[index] = input;
+                self.current.ip += 1;
+            },
+
+            Op::NewMap => {
+                self.ret = Some( RuntimeData::Ref( self.alloc(Heap::Map(HashMap::new())) ) );
+                self.current.ip += 1;
+            },
+
+            Op::MapGet { local, key } => {
+                let r = proj_type!(self, local, ref)?;
+                let key = self.key(key)?;
+                self.ret = Some(self.map(r)?.get(&key).cloned().unwrap_or(RuntimeData::Nil));
+                self.current.ip += 1;
+            },
+
+            Op::MapInsert { dest, key, src } => {
+                let r = proj_type!(self, dest, ref)?;
+                let key = self.key(key)?;
+                let input = self.get_local(src)?.clone();
+                self.map(r)?.insert(key, input);
+                self.current.ip += 1;
+            },
+
+            Op::MapRemove { local, key } => {
+                let r = proj_type!(self, local, ref)?;
+                let key = self.key(key)?;
+                self.map(r)?.remove(&key);
+                self.current.ip += 1;
+            },
+
+            Op::MapContains { local, key } => {
+                let r = proj_type!(self, local, ref)?;
+                let key = self.key(key)?;
+                self.ret = Some(RuntimeData::Bool(self.map(r)?.contains_key(&key)));
+                self.current.ip += 1;
+            },
+
+            Op::MapKeys(local) => {
+                let r = proj_type!(self, local, ref)?;
+                let keys = self.map(r)?.keys().cloned().map(RuntimeData::from).collect();
+                self.ret = Some( RuntimeData::Ref( self.alloc(Heap::Array(keys)) ) );
+                self.current.ip += 1;
+            },
+
+            Op::Delete(local) => {
+                let r = proj_type!(self, local, ref)?;
+                self.heap_cell(r)?;
+                self.heap.free(r.addr);
+                self.current.ip += 1;
+            },
+
+            Op::InsertSlot { dest, src, index } => {
+                self.insert_slot(dest, src, index as i64)?;
+                self.current.ip += 1;
+            },
+
+            Op::DynInsertSlot { dest, src, index } => {
+                let index = proj_type!(self, index, int)?;
+                self.insert_slot(dest, src, index)?;
+                self.current.ip += 1;
+            },
+            
+            Op::RemoveSlot { local, index } => {
+                self.remove_slot(local, index as i64)?;
+                self.current.ip += 1;
+            },
+
+            Op::DynRemoveSlot { local, index } => {
+                let index = proj_type!(self, index, int)?;
+                self.remove_slot(local, index)?;
+                self.current.ip += 1;
+            },
+
+            Op::GetLength(local) => {
+                let r = proj_type!(self, local, ref)?;
+                match self.heap_cell(r)? { 
+                    Heap::Cons { params, .. } | Heap::Array(params) => {
+                        self.ret = Some(RuntimeData::Int(params.len().try_into().unwrap()));
+                    },
+                    Heap::Map(map) => {
+                        self.ret = Some(RuntimeData::Int(map.len().try_into().unwrap()));
+                    },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::GetType(local) => {
+                let r = proj_type!(self, local, ref)?;
+                match self.heap_cell(r)? { 
+                    Heap::Cons { name, .. } => {
+                        self.ret = Some(RuntimeData::Symbol(Rc::clone(name)));
+                    },
+                    Heap::Array(_) => {
+                        self.ret = Some(RuntimeData::Symbol("array".into()));
+                    },
+                    Heap::Map(_) => {
+                        self.ret = Some(RuntimeData::Symbol("map".into()));
+                    },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::GetSlot { local, index } => {
+                self.get_slot(local, index as i64)?;
+                self.current.ip += 1;
+            },
+
+            Op::DynGetSlot { local, index } => {
+                let index = proj_type!(self, index, int)?;
+                self.get_slot(local, index)?;
+                self.current.ip += 1;
+            },
+
+            Op::Closure { proc_id, .. } if proc_id >= self.procs.len() => {
+                return Err(VmError::ProcDoesNotExist(self.current.proc_id, self.stack_trace()));
+            },
+            Op::Closure { proc_id, ref env } => {
+                let env = self.clone_locals(env)?;
+                self.ret = Some(RuntimeData::Closure (Closure { proc_id, env }));
+                self.current.ip += 1;
+            },
+
+            Op::Coroutine { proc_id, .. } if proc_id >= self.procs.len() => {
+                return Err(VmError::ProcDoesNotExist(self.current.proc_id, self.stack_trace()));
+            },
+            Op::Coroutine { proc_id, ref params } => {
+                let params = self.clone_locals(params)?;
+                self.ret = Some(RuntimeData::Coroutine(Coroutine::Start{ proc_id, params }));
+                self.current.ip += 1;
+            },
+
+            Op::DynCoroutine { local, ref params } => {
+                let closure = proj_type!(self, local, closure)?.clone();
+                let params = self.clone_locals(params)?;
+                self.ret = Some(RuntimeData::Coroutine(Coroutine::DynStart { closure, params }));
+                self.current.ip += 1;
+            },
+
+            Op::Resume(local) if local >= self.current.locals.len() => { 
+                return Err(VmError::AccessMissingLocal(local, self.stack_trace()));
+            },
+            Op::Resume(local) if !matches!(self.current.locals[local], RuntimeData::Coroutine(_)) => {
+                return self.local_unexpected_type(local, "coroutine");
+            },
+            Op::Resume(local) => {
+                let coroutine = std::mem::replace(&mut self.current.locals[local], RuntimeData::Coroutine(Coroutine::Running));
+                let coroutine = proj!(coroutine, RuntimeData::Coroutine(x), x);
+                
+                match coroutine {
+                    Coroutine::Active(frame) => {
+                        self.current.ip += 1;
+                        let current = std::mem::replace(&mut self.current, frame);
+                        self.frames.push(current);
+                    },
+                    Coroutine::Start { proc_id, params } => {
+                        let params_len = params.len();
+                        let mut new_locals = params;
+                        self.current.ip += 1;
+                        new_locals.append(&mut std::iter::repeat(RuntimeData::Nil).take(self.procs[proc_id].stack_size - params_len).collect());
+                        let current = std::mem::replace(&mut self.current, Frame { proc_id: proc_id, ip: 0, locals: new_locals, handlers: vec![] });
+                        self.frames.push(current);
+                    },
+                    Coroutine::DynStart { closure, mut params } => {
+                        let Some(rest) = self.procs[closure.proc_id].stack_size.checked_sub(closure.env.len() + params.len()) else {
+                            let proc_id = closure.proc_id;
+                            self.current.locals[local] = RuntimeData::Coroutine(Coroutine::DynStart { closure, params });
+                            return Err(VmError::TooManyArgs(proc_id, self.stack_trace()));
+                        };
+                        let Closure { proc_id, env } = closure; 
+                        let mut new_locals = env;
+                        new_locals.append(&mut params);
+                        self.current.ip += 1;
+
+                        new_locals.append(&mut std::iter::repeat(RuntimeData::Nil).take(rest).collect());
+                        let current = std::mem::replace(&mut self.current, Frame { proc_id: proc_id, ip: 0, locals: new_locals, handlers: vec![] });
+                        self.frames.push(current);
+                    },
+                    Coroutine::Ended => {
+                        self.ret = Some(RuntimeData::Nil);
+                        self.current.ip += 1;
+                    },
+                    Coroutine::Running => unreachable!("Swapped a running coroutine"),
+                }
+            },
+
+            Op::Yield(local) => {
+                self.ret = Some(self.get_local(local)?.clone());
+
+                match self.frames.pop() {
+                    None => {
+                        return Err(VmError::TopLevelYield(self.current.ip));
+                    },
+                    Some(frame) => {
+                        self.current.ip += 1;
+                        let coroutine = std::mem::replace(&mut self.current, frame);
+                        let index = self.current.locals.iter().position(|x| match x { RuntimeData::Coroutine(Coroutine::Running) => true, _ => false })
+                                    .expect("Could not find Coroutine::Running placeholder");
+                        self.current.locals[index] = RuntimeData::Coroutine(Coroutine::Active(coroutine));
+                    },
+                }
+            },
+
+            Op::Break => {
+                self.ret = Some(RuntimeData::Nil);
+
+                match self.frames.pop() {
+                    None => {
+                        return Err(VmError::TopLevelYield(self.current.ip));
+                    },
+                    Some(frame) => {
+                        self.current = frame;
+                        let index = self.current.locals.iter().position(|x| match x { RuntimeData::Coroutine(Coroutine::Running) => true, _ => false })
+                                    .expect("Could not find Coroutine::Running placeholder");
+                        self.current.locals[index] = RuntimeData::Coroutine(Coroutine::Ended);
+                    },
+                }
+            }
+
+            Op::Try { label, local } => {
+                self.current.handlers.push(Handler { label, local });
+                self.current.ip += 1;
+            },
+
+            Op::EndTry => {
+                self.current.handlers.pop();
+                self.current.ip += 1;
+            },
+
+            Op::Throw(local) => {
+                let value = self.get_local(local)?.clone();
+                if self.current.handlers.is_empty() && self.frames.iter().all(|x| x.handlers.is_empty()) {
+                    return Err(VmError::Uncaught { value, stack_trace: self.stack_trace() });
+                }
+
+                while self.current.handlers.is_empty() {
+                    let frame = self.frames.pop().expect("Could not find handler frame");
+                    self.current = frame;
+                    // Note:  A Running placeholder means the unwound frame was a resumed coroutine.
+                    // It can't be resumed again, so it ends the same way as a break.
+                    if let Some(index) = self.current.locals.iter().position(|x| matches!(x, RuntimeData::Coroutine(Coroutine::Running))) {
+                        self.current.locals[index] = RuntimeData::Coroutine(Coroutine::Ended);
+                    }
+                }
+
+                let Handler { label, local } = self.current.handlers.pop().unwrap();
+                *self.mut_local(local)? = value;
+                self.current.ip = label;
+            },
+
+            Op::IsNil(local) => {
+                let result = match self.get_local(local)? {
+                    RuntimeData::Nil => true,
+                    _ => false,
+                };
+                self.ret = Some(RuntimeData::Bool(result));
+                self.current.ip += 1;
+            },
+
+            Op::ToString(local) => {
+                let result : Rc<str> = match self.get_local(local)? {
+                    RuntimeData::Bool(x) => format!("{}", x).into(),
+                    RuntimeData::Int(x) => format!("{}", x).into(),
+                    RuntimeData::Float(x) => format!("{}", x).into(),
+                    RuntimeData::Symbol(x) => Rc::clone(x),
+                    RuntimeData::String(x) => Rc::clone(x),
+                    RuntimeData::Ref(r) => format!("ref({})", r.addr).into(),
+                    RuntimeData::Closure(_) => "closure".into(),
+                    RuntimeData::Coroutine(_) => "coroutine".into(),
+                    RuntimeData::Nil => "nil".into(),
+                };
+                self.ret = Some(RuntimeData::String(result));
+                self.current.ip += 1;
+            },
+
+            Op::Concat(a, b) => {
+                let a = proj_type!(self, a, string)?;
+                let b = proj_type!(self, b, string)?;
+                self.ret = Some(RuntimeData::String(format!("{}{}", a, b).into()));
+                self.current.ip += 1;
+            },
+
+            Op::StrLength(local) => {
+                let s = proj_type!(self, local, string)?;
+                self.ret = Some(RuntimeData::Int(s.chars().count() as i64));
+                self.current.ip += 1;
+            },
+
+            Op::Substring { local, start, end } => {
+                let s = proj_type!(self, local, string)?;
+                let length = s.chars().count();
+                // Note:  end is exclusive, so both may be one past the last char.
+                let start = self.string_index(proj_type!(self, start, int)?, length, true)?;
+                let end = self.string_index(proj_type!(self, end, int)?, length, true)?;
+                if start > end {
+                    return Err(VmError::StringIndexOutOfRange { index: start as i64, length, stack_trace: self.stack_trace() });
+                }
+                self.ret = Some(RuntimeData::String(s.chars().skip(start).take(end - start).collect::<String>().into()));
+                self.current.ip += 1;
+            },
+
+            Op::CharAt(local, index) => {
+                let s = proj_type!(self, local, string)?;
+                let index = self.string_index(proj_type!(self, index, int)?, s.chars().count(), false)?;
+                self.ret = Some(RuntimeData::String(s.chars().nth(index).unwrap().to_string().into()));
+                self.current.ip += 1;
+            },
+
+            Op::IndexOf(local, needle) => {
+                let s = proj_type!(self, local, string)?;
+                let needle = proj_type!(self, needle, string)?;
+                let result = match s.find(&*needle) {
+                    Some(x) => s[..x].chars().count() as i64,
+                    None => -1,
+                };
+                self.ret = Some(RuntimeData::Int(result));
+                self.current.ip += 1;
+            },
+
+            Op::Split(local, sep) => {
+                let s = proj_type!(self, local, string)?;
+                let sep = proj_type!(self, sep, string)?;
+                let parts : Vec<Rc<str>> = if sep.is_empty() {
+                    s.chars().map(|x| x.to_string().into()).collect()
+                }
+                else {
+                    s.split(&*sep).map(|x| x.into()).collect()
+                };
+                // Note:  The list is built from ~cons cells holding (part, rest) and ends with an empty ~nil cell.
+                let mut list = self.alloc(Heap::Cons { name: "nil".into(), params: vec![] });
+                for part in parts.into_iter().rev() {
+                    list = self.alloc(Heap::Cons { name: "cons".into(), params: vec![RuntimeData::String(part), RuntimeData::Ref(list)] });
+                }
+                self.ret = Some(RuntimeData::Ref(list));
+                self.current.ip += 1;
+            },
+
+            Op::Upper(local) => {
+                let s = proj_type!(self, local, string)?;
+                self.ret = Some(RuntimeData::String(s.to_uppercase().into()));
+                self.current.ip += 1;
+            },
+
+            Op::Lower(local) => {
+                let s = proj_type!(self, local, string)?;
+                self.ret = Some(RuntimeData::String(s.to_lowercase().into()));
+                self.current.ip += 1;
+            },
+
+            Op::Trim(local) => {
+                let s = proj_type!(self, local, string)?;
+                self.ret = Some(RuntimeData::String(s.trim().into()));
+                self.current.ip += 1;
+            },
+
+            Op::ParseInt(local) => {
+                let s = proj_type!(self, local, string)?;
+                self.ret = Some(s.parse::<i64>().map(RuntimeData::Int).unwrap_or(RuntimeData::Nil));
+                self.current.ip += 1;
+            },
+
+            Op::ParseFloat(local) => {
+                let s = proj_type!(self, local, string)?;
+                self.ret = Some(s.parse::<f64>().map(RuntimeData::Float).unwrap_or(RuntimeData::Nil));
+                self.current.ip += 1;
+            },
+
+            Op::Print(local) => {
+                self.print(local, "")?;
+                self.current.ip += 1;
+            },
+
+            Op::PrintLine(local) => {
+                self.print(local, "\n")?;
+                self.current.ip += 1;
+            },
+
+            Op::ReadLine => {
+                match self.io.read_line() {
+                    Ok(Some(line)) => { self.ret = Some(RuntimeData::String(line.into())); },
+                    Ok(None) => { self.ret = Some(RuntimeData::Nil); },
+                    Err(e) => { return Err(VmError::Io(e.to_string().into(), self.stack_trace())); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::ReadFile(local) => {
+                let path = proj_type!(self, local, string)?;
+                match self.io.read_file(&path) {
+                    Ok(x) => { self.ret = Some(RuntimeData::String(x.into())); },
+                    Err(e) => { return Err(VmError::Io(format!("{path}: {e}").into(), self.stack_trace())); },
+                }
+                self.current.ip += 1;
+            },
+
+            Op::WriteFile { path, contents } => {
+                let path = proj_type!(self, path, string)?;
+                let contents = proj_type!(self, contents, string)?;
+                if let Err(e) = self.io.write_file(&path, &contents) {
+                    return Err(VmError::Io(format!("{path}: {e}").into(), self.stack_trace()));
+                }
+                self.ret = Some(RuntimeData::Int(contents.len() as i64));
+                self.current.ip += 1;
+            },
+
+            Op::Nop => { self.current.ip += 1; },
+        }
+
+        Ok(Status::Running)
+    }
+
+    /// Allocates on the heap, collecting first once the heap has grown to the next threshold.
+    /// Every op that allocates goes through here.
+    fn alloc(&mut self, value : Heap) -> HeapRef {
+        if self.heap.live() >= self.next_gc {
+            self.collect(&value);
+            let live = self.heap.live();
+            self.next_gc = self.gc.threshold.max(live.saturating_mul(self.gc.growth_factor)).max(live + 1);
+        }
+        self.heap.alloc(value)
+    }
+
+    /// The value about to be allocated is treated as a root, because what it refers to may only
+    /// be held by the op that is allocating it.
+    fn collect(&mut self, pending : &Heap) {
+        fn children<'a>(data : &'a RuntimeData, work : &mut Vec<&'a RuntimeData>) {
+            match data {
+                RuntimeData::Closure(Closure { env, .. }) => { work.extend(env.iter()); },
+                RuntimeData::Coroutine(Coroutine::Active(frame)) => { work.extend(frame.locals.iter()); },
+                RuntimeData::Coroutine(Coroutine::Start { params, .. }) => { work.extend(params.iter()); },
+                RuntimeData::Coroutine(Coroutine::DynStart { closure, params }) => {
+                    work.extend(closure.env.iter());
+                    work.extend(params.iter());
+                },
+                _ => { },
+            }
+        }
+
+        fn contents<'a>(heap : &'a Heap, work : &mut Vec<&'a RuntimeData>) {
+            match heap {
+                Heap::Cons { params, .. } | Heap::Array(params) => { work.extend(params.iter()); },
+                Heap::Map(map) => { work.extend(map.values()); },
+            }
+        }
+
+        let mut marks = vec![false; self.heap.len()];
+        let mut work = self.current.locals.iter()
+            .chain(self.frames.iter().flat_map(|x| x.locals.iter()))
+            .chain(self.ret.as_ref())
+            .collect::<Vec<_>>();
+        contents(pending, &mut work);
+
+        while let Some(data) = work.pop() {
+            match data {
+                RuntimeData::Ref(r) if self.heap.get(*r).is_some() && !marks[r.addr] => {
+                    marks[r.addr] = true;
+                    contents(self.heap.get(*r).unwrap(), &mut work);
+                },
+                x => children(x, &mut work),
+            }
+        }
+
+        for (addr, marked) in marks.into_iter().enumerate() {
+            if !marked {
+                self.heap.free(addr);
+            }
+        }
+    }
+
+    fn heap_cell(&mut self, r : HeapRef) -> Result<&mut Heap, VmError> {
+        if self.heap.get(r).is_none() {
+            return Err(VmError::DanglingRef { addr: r.addr, generation: r.generation, stack_trace: self.stack_trace() });
+        }
+        Ok(self.heap.get_mut(r).unwrap())
+    }
+
+    fn cons(&mut self, r : HeapRef) -> Result<&mut Vec<RuntimeData>, VmError> {
+        match self.heap_cell(r)? {
+            Heap::Cons { .. } => { },
+            Heap::Array(_) | Heap::Map(_) => { return Err(VmError::HeapUnexpectedType { addr: r.addr, expected: "cons", stack_trace: self.stack_trace() }); },
+        }
+        match self.heap.get_mut(r) {
+            Some(Heap::Cons { params, .. }) => Ok(params),
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_slot(&mut self, local : usize, index : i64) -> Result<(), VmError> {
+        let r = proj_type!(self, local, ref)?;
+        let length = self.cons(r)?.len();
+        let index = self.slot_index(r.addr, index, length, false)?;
+        self.ret = Some(self.cons(r)?[index].clone());
+        Ok(())
+    }
+
+    fn insert_slot(&mut self, dest : usize, src : usize, index : i64) -> Result<(), VmError> {
+        let r = proj_type!(self, dest, ref)?;
+        let input = self.get_local(src)?.clone();
+        let length = self.cons(r)?.len();
+        let index = self.slot_index(r.addr, index, length, true)?;
+        self.cons(r)?.insert(index, input);
+        Ok(())
+    }
+
+    fn remove_slot(&mut self, local : usize, index : i64) -> Result<(), VmError> {
+        let r = proj_type!(self, local, ref)?;
+        let length = self.cons(r)?.len();
+        let index = self.slot_index(r.addr, index, length, false)?;
+        self.cons(r)?.remove(index);
+        Ok(())
+    }
+
+    fn array(&mut self, r : HeapRef) -> Result<&mut Vec<RuntimeData>, VmError> {
+        match self.heap_cell(r)? {
+            Heap::Array(_) => { },
+            Heap::Cons { .. } | Heap::Map(_) => { return Err(VmError::HeapUnexpectedType { addr: r.addr, expected: "array", stack_trace: self.stack_trace() }); },
+        }
+        match self.heap.get_mut(r) {
+            Some(Heap::Array(values)) => Ok(values),
+            _ => unreachable!(),
+        }
+    }
+
+    fn map(&mut self, r : HeapRef) -> Result<&mut HashMap<Key, RuntimeData>, VmError> {
+        match self.heap_cell(r)? {
+            Heap::Map(_) => { },
+            Heap::Cons { .. } | Heap::Array(_) => { return Err(VmError::HeapUnexpectedType { addr: r.addr, expected: "map", stack_trace: self.stack_trace() }); },
+        }
+        match self.heap.get_mut(r) {
+            Some(Heap::Map(map)) => Ok(map),
+            _ => unreachable!(),
+        }
+    }
+
+    fn key(&self, local : usize) -> Result<Key, VmError> {
+        match Key::from_data(self.get_local(local)?) {
+            Some(key) => Ok(key),
+            None => self.local_unexpected_type(local, "int, bool, symbol or string"),
+        }
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    pub fn heap_cells(&self) -> impl Iterator<Item = (HeapRef, &Heap)> {
+        self.heap.iter()
+    }
+
+    /// False once the entry proc has returned, or before start is called.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// The frame of the proc that the next step will execute in.
+    pub fn current(&self) -> &Frame {
+        &self.current
+    }
+
+    /// Suspended caller frames, outermost first.  Their ip is the return address.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn procs(&self) -> &[Proc] {
+        &self.procs
+    }
+
+    fn stack_trace(&self) -> StackTrace {
+        struct RetAddr { proc: usize, instr : usize }
+
+        let mut stack = self.frames.iter().map(|x| RetAddr { proc: x.proc_id, instr: x.ip }).collect::<Vec<_>>();
+        stack.push(RetAddr { proc: self.current.proc_id, instr: self.current.ip + 1});
+
+        let mut trace = vec![];
+        for addr in stack {
+            // Note:  if the procedure was already pushed into the stack, then
+            // that means that it already resolved to a known procedure. Don't
+            // have to check again that the proc map has it.
+            let proc = &self.procs[addr.proc];
+            let index = addr.instr - 1;
+            let location = match (&proc.debug.source, proc.debug.spans.get(index)) {
+                (Some(source), Some(span)) => Some(Location { source: Rc::clone(source), span: *span }),
+                _ => None,
+            };
+            trace.push((Rc::clone(&proc.name), index, location));
+        }
+
+        // Note:  An inlined primitive never got a frame of its own, so report it as if it had.
+        if let Some(inlined) = self.inlined() {
+            if inlined.tail {
+                trace.pop();
+            }
+            trace.push((Rc::clone(&self.procs[inlined.proc_id].name), 0, None));
+        }
+        trace
+    }
+
+    fn inlined(&self) -> Option<&Inlined> {
+        self.procs[self.current.proc_id].debug.inlined_at(self.current.ip)
+    }
+
+    fn local_unexpected_type<T>(&self, local : usize, expected : &'static str) -> Result<T, VmError> {
+        let found = format!("{:?}", self.current.locals[local]).into();
+        // Note:  Inside an inlined primitive the local is reported as the primitive's param.
+        let local = match self.inlined() {
+            Some(_) => self.procs[self.current.proc_id].instrs[self.current.ip].locals().iter().position(|x| *x == local).unwrap_or(local),
+            None => local,
+        };
+        return Err(VmError::LocalUnexpectedType { local, stack_trace: self.stack_trace(), expected, found });
+    }
+
+    fn get_local(&self, local: usize) -> Result<&RuntimeData, VmError> {
+        if local >= self.current.locals.len() {
+            return Err(VmError::AccessMissingLocal(local, self.stack_trace()));
+        }
+        Ok(&self.current.locals[local])
+    }
+
+    fn mut_local(&mut self, local: usize) -> Result<&mut RuntimeData, VmError> {
+        if local >= self.current.locals.len() {
+            return Err(VmError::AccessMissingLocal(local, self.stack_trace()));
+        }
+        Ok(&mut self.current.locals[local])
+    }
+
+    fn print(&mut self, local : usize, end : &str) -> Result<(), VmError> {
+        let text = format!("{}{}", proj_type!(self, local, string)?, end);
+        if let Err(e) = self.io.print(&text) {
+            return Err(VmError::Io(e.to_string().into(), self.stack_trace()));
+        }
+        self.ret = Some(RuntimeData::Int(text.len() as i64));
+        Ok(())
+    }
+
+    /// Checks that a string index is in 0..length, or 0..=length when allow_end is set, and 
+    /// converts it to usize.
+    fn string_index(&self, index : i64, length : usize, allow_end : bool) -> Result<usize, VmError> {
+        match usize::try_from(index) {
+            Ok(x) if x < length || (allow_end && x == length) => Ok(x),
+            _ => Err(VmError::StringIndexOutOfRange { index, length, stack_trace: self.stack_trace() }),
+        }
+    }
+
+    /// Checks that a slot index is in 0..length, or 0..=length when allow_end is set, and 
+    /// converts it to usize.
+    fn slot_index(&self, addr : usize, index : i64, length : usize, allow_end : bool) -> Result<usize, VmError> {
+        match usize::try_from(index) {
+            Ok(x) if x < length || (allow_end && x == length) => Ok(x),
+            _ => Err(VmError::AccessMissingSlotIndex { addr, index, stack_trace: self.stack_trace() }),
+        }
+    }
+
+    fn array_index(&self, addr : usize, index : i64, length : usize) -> Result<usize, VmError> {
+        match usize::try_from(index) {
+            Ok(x) if x < length => Ok(x),
+            _ => Err(VmError::ArrayIndexOutOfRange { addr, index, length, stack_trace: self.stack_trace() }),
+        }
+    }
+
+    /// Reuses the current frame's locals for the callee of a tail call.
+    fn replace_frame(&mut self, proc_id : usize, args : Vec<RuntimeData>) {
+        let stack_size = self.procs[proc_id].stack_size;
+        let locals = &mut self.current.locals;
+        locals.clear();
+        locals.extend(args);
+        locals.resize(stack_size, RuntimeData::Nil);
+        self.current.handlers.clear();
+        self.current.proc_id = proc_id;
+        self.current.ip = 0;
+    }
+
+    fn clone_locals(&self, locals: &[usize]) -> Result<Vec<RuntimeData>, VmError> {
+        let mut ret = vec![];
+        for local in locals {
+            let local = *local;
+            if local >= self.current.locals.len() {
+                return Err(VmError::AccessMissingLocal(local, self.stack_trace()));
+            }
+            else {
+                ret.push(self.current.locals[local].clone());
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::ir_parser::{ parse, parse_file };
+    use crate::compiling::ir_compiler::compile;
+
+    fn load(input : &str) -> (Vm, usize) {
+        let procs = compile(&parse(input).unwrap(), &[]).unwrap();
+        let main = procs.iter().position(|x| *"main" == *x.name).unwrap();
+        (Vm::new(procs, vec![], GcConfig::default()), main)
+    }
+
+    #[test]
+    fn should_run_out_of_fuel_in_infinite_loop() {
+        let (mut vm, main) = load(r"
+proc main() -> Int {
+    label loop;
+    jump loop;
+}
+");
+        assert!(matches!(vm.run_with_fuel(main, 1000), Err(VmError::OutOfFuel(_))));
+        assert!(matches!(vm.continue_with_fuel(1000), Err(VmError::OutOfFuel(_))));
+    }
+
+    #[test]
+    fn should_continue_where_fuel_ran_out() {
+        let input = r"
+proc main() -> Int {
+    set one : Int = 1;
+    set max : Int = 10;
+    set i : Int = 0;
+    label loop;
+    set i : Int = call add_int(i, one);
+    set done : Bool = call eq_int(i, max);
+    branch_true exit done;
+    jump loop;
+    label exit;
+    return i;
+}
+";
+        let (mut vm, main) = load(input);
+        let mut output = vm.run_with_fuel(main, 7);
+        let mut refuels = 0;
+        while let Err(VmError::OutOfFuel(_)) = output {
+            refuels += 1;
+            output = vm.continue_with_fuel(7);
+        }
+        assert!(refuels > 1);
+        assert_eq!(proj!(output.unwrap().unwrap(), RuntimeData::Int(x), x), 10);
+        assert!(matches!(vm.continue_with_fuel(7), Err(VmError::NotRunning)));
+
+        let (mut vm, main) = load(input);
+        assert!(vm.run_with_fuel(main, 1000).is_ok());
+    }
+
+    #[test]
+    fn should_reuse_frame_for_tail_calls() {
+        let (mut vm, main) = load(r"
+proc count(n : Int) -> Int {
+    set zero : Int = 0;
+    set one : Int = 1;
+    set done : Bool = call eq_int(n, zero);
+    branch_true end done;
+    set n : Int = call sub_int(n, one);
+    set n : Int = call count(n);
+    return n;
+    label end;
+    return n;
+}
+proc main() -> Int {
+    set n : Int = 1000;
+    set n : Int = call count(n);
+    return n;
+}
+");
+        vm.start(main, vec![]).unwrap();
+        let mut depth = 0;
+        while let Status::Running = vm.step().unwrap() {
+            depth = depth.max(vm.frames().len());
+        }
+        assert!(depth <= 2);
+    }
+
+    #[test]
+    fn should_bound_heap_growth_with_collection() {
+        let input = r"
+proc main() -> Int {
+    set name : Symbol = ~garbage;
+    set one : Int = 1;
+    set max : Int = 1000;
+    set i : Int = 0;
+    label loop;
+    set cell : Ref = cons name (i);
+    set i : Int = call add_int(i, one);
+    set done : Bool = call eq_int(i, max);
+    branch_true exit done;
+    jump loop;
+    label exit;
+    return i;
+}
+";
+        let procs = compile(&parse(input).unwrap(), &[]).unwrap();
+        let main = procs.iter().position(|x| *"main" == *x.name).unwrap();
+        let mut vm = Vm::new(procs, vec![], GcConfig { threshold: 8, growth_factor: 2 });
+        vm.run(main).unwrap();
+        assert!(vm.heap_stats().peak <= 8);
+    }
+
+    #[test]
+    fn should_report_source_location_in_stack_trace() {
+        let input = r"proc main() -> Int {
+    set name : Symbol = ~blah;
+    label start;
+    set cell : Ref = cons name ();
+    set x : Int = call get(cell);
+    set y : Int = x;
+    return y;
+}
+proc get(r : Ref) -> Int {
+    delete r;
+    set x : Int = slot r 0;
+    return x;
+}
+";
+        let procs = compile(&parse_file("test.ir", input).unwrap(), &[]).unwrap();
+        let main = procs.iter().position(|x| *"main" == *x.name).unwrap();
+        let mut vm = Vm::new(procs, vec![], GcConfig::default());
+        let output = vm.run(main).unwrap_err().to_string();
+        assert!(output.contains("main at test.ir:5:5\n"));
+        assert!(output.contains("get at test.ir:11:5\n"));
+        assert!(output.contains("    set x : Int = slot r 0;\n    ----------------------"));
+    }
+
+    #[test]
+    fn should_report_inlined_primitive_in_stack_trace() {
+        let input = r"proc main() -> Int {
+    set m : Ref = map_new;
+    set k : Int = 1;
+    set one : Int = 1;
+    set x : Int = map_get m k;
+    set y : Int = call add_int(one, x);
+    set z : Int = y;
+    return z;
+}
+";
+        let procs = compile(&parse_file("test.ir", input).unwrap(), &[]).unwrap();
+        let main = procs.iter().position(|x| *"main" == *x.name).unwrap();
+        let mut vm = Vm::new(procs, vec![], GcConfig::default());
+        let output = vm.run(main).unwrap_err();
+        let (local, stack_trace) = proj!(output, VmError::LocalUnexpectedType { local, stack_trace, .. }, (local, stack_trace));
+        assert_eq!(local, 1);
+        let names = stack_trace.iter().map(|(name, _, _)| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(names, ["main", "add_int"]);
+        assert!(stack_trace[0].2.is_some());
+    }
+
+    #[test]
+    fn should_fail_when_closure_env_is_larger_than_stack_size() {
+        let other = || Proc { name: "other".into(), instrs: vec![Op::ReturnLocal(0)], stack_size: 1, debug: DebugInfo::default() };
+        let main = |call : Op| Proc { name: "main".into(), instrs: vec![
+            Op::SetLocalData(0, RuntimeData::Int(1)),
+            Op::Closure { proc_id: 0, env: vec![0, 0] },
+            Op::SetLocalReturn(1),
+            call,
+            Op::SetLocalReturn(2),
+            Op::Resume(2),
+            Op::SetLocalReturn(0),
+            Op::ReturnLocal(0),
+        ], stack_size: 3, debug: DebugInfo::default() };
+
+        let mut vm = Vm::new(vec![other(), main(Op::DynCall(1, vec![]))], vec![], GcConfig::default());
+        assert!(matches!(vm.run(1), Err(VmError::TooManyArgs(0, _))));
+
+        let mut vm = Vm::new(vec![other(), main(Op::DynCoroutine { local: 1, params: vec![] })], vec![], GcConfig::default());
+        assert!(matches!(vm.run(1), Err(VmError::TooManyArgs(0, _))));
+    }
+
+    #[test]
+    fn should_fail_with_too_many_args_in_tail_position() {
+        let input = |tail : &str| format!(r"
+proc one(a : Int) -> Int {{
+    return a;
+}}
+proc main() -> Int {{
+    set x : Int = 1;
+    set c : Closure = closure one(x);
+    set r : Int = dyn_call c(x);
+    {tail}
+}}
+");
+        let (mut vm, main) = load(&input("return r;"));
+        assert!(matches!(vm.procs()[main].instrs[..], [.., Op::DynTailCall(..), _, Op::ReturnLocal(_)]));
+        assert!(matches!(vm.run(main), Err(VmError::TooManyArgs(_, _))));
+
+        let (mut vm, main) = load(&input("set s : Int = r; return s;"));
+        assert!(matches!(vm.run(main), Err(VmError::TooManyArgs(_, _))));
+    }
+}
@@ -0,0 +1,223 @@
+
+use crate::util::proj;
+use crate::eval::data::RuntimeData;
+use crate::eval::error::VmError;
+use crate::eval::vm::GcConfig;
+
+use super::util::{ test, test_fails, test_with_gc };
+
+#[test]
+fn should_create_and_get() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 10;
+    set b : Int = 20;
+    set c : Int = 30;
+    set arr : Ref = array (a, b, c);
+
+    set i : Int = 2;
+    set x : Int = array_get arr i;
+    set i : Int = 0;
+    set y : Int = array_get arr i;
+    set ret : Int = call add_int(x, y);
+
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 40);
+}
+
+#[test]
+fn should_push_and_pop() {
+    let input = r"
+proc main() -> Int {
+    set arr : Ref = array ();
+    set a : Int = 1;
+    set b : Int = 2;
+    set c : Int = 3;
+    array_push arr a;
+    array_push arr b;
+    array_push arr c;
+
+    set x : Int = array_pop arr;
+    set len : Int = length arr;
+    set ret : Int = call mul_int(x, len);
+
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 6);
+}
+
+#[test]
+fn should_set() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 1;
+    set b : Int = 2;
+    set arr : Ref = array (a, b);
+
+    set i : Int = 1;
+    set v : Int = 7;
+    array_set arr i v;
+    set ret : Int = array_get arr i;
+
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 7);
+}
+
+#[test]
+fn should_get_array_type() {
+    let input = r"
+proc main() -> Symbol {
+    set arr : Ref = array ();
+    set ret : Symbol = type arr;
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Symbol(x), x);
+    assert_eq!(&*output, "array");
+}
+
+#[test]
+fn should_fail_get_out_of_range() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 1;
+    set arr : Ref = array (a);
+    set i : Int = 1;
+    set ret : Int = array_get arr i;
+    return ret;
+}
+";
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::ArrayIndexOutOfRange { index: 1, length: 1, .. }));
+}
+
+#[test]
+fn should_fail_set_negative_index() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 1;
+    set arr : Ref = array (a);
+    set i : Int = -1;
+    array_set arr i a;
+    return a;
+}
+";
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::ArrayIndexOutOfRange { index: -1, length: 1, .. }));
+}
+
+#[test]
+fn should_fail_pop_empty() {
+    let input = r"
+proc main() -> Int {
+    set arr : Ref = array ();
+    set ret : Int = array_pop arr;
+    return ret;
+}
+";
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::PopEmptyArray { .. }));
+}
+
+#[test]
+fn should_fail_slot_on_array() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 1;
+    set arr : Ref = array (a);
+    set ret : Int = slot arr 0;
+    return ret;
+}
+";
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::HeapUnexpectedType { expected: "cons", .. }));
+}
+
+#[test]
+fn should_fail_array_get_on_cons() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 1;
+    set name : Symbol = ~blah;
+    set cell : Ref = cons name (a);
+    set i : Int = 0;
+    set ret : Int = array_get cell i;
+    return ret;
+}
+";
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::HeapUnexpectedType { expected: "array", .. }));
+}
+
+#[test]
+fn should_keep_array_elements_across_collections() {
+    let input = r"
+proc main() -> Int {
+    set one : Int = 1;
+    set max : Int = 50;
+    set name : Symbol = ~node;
+
+    set arr : Ref = array ();
+    set i : Int = 0;
+    label build;
+    set garbage : Ref = cons name (i);
+    set cell : Ref = cons name (i);
+    array_push arr cell;
+    set i : Int = call add_int(i, one);
+    set done : Bool = call eq_int(i, max);
+    branch_true sum done;
+    jump build;
+
+    label sum;
+    set total : Int = 0;
+    label walk;
+    set len : Int = length arr;
+    set zero : Int = 0;
+    set at_end : Bool = call eq_int(len, zero);
+    branch_true exit at_end;
+    set cell : Ref = array_pop arr;
+    set v : Int = slot cell 0;
+    set total : Int = call add_int(total, v);
+    jump walk;
+
+    label exit;
+    return total;
+}
+";
+
+    let output = proj!(test_with_gc(input, GcConfig { threshold: 4, growth_factor: 1 }).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 1225);
+}
+
+#[test]
+fn should_allow_local_named_array() {
+    let input = r"
+proc main() -> Int {
+    set array : Int = 1;
+    set other : Ref = array (array);
+    set index : Int = 0;
+    set array : Int = array_get other index;
+    return array;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 1);
+}
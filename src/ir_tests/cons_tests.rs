@@ -148,6 +148,184 @@ proc main() -> Float {
 "; 
 
     let output = test_fails(input);
-    assert!(matches!(output, VmError::AccessNilHeap(_, _)));
+    assert!(matches!(output, VmError::DanglingRef { .. }));
 }
 
+#[test]
+fn should_detect_stale_ref_after_slot_reuse() {
+    let input = r"
+proc main() -> Int {
+
+    set name : Symbol = ~blah;
+    set p1 : Int = 2;
+
+    set old : Ref = cons name (p1);
+
+    delete old;
+
+    set new : Ref = cons name (p1);
+
+    set ret : Int = slot old 0;
+
+    return ret;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::DanglingRef { addr: 0, generation: 0, .. }));
+}
+
+#[test]
+fn should_detect_double_delete() {
+    let input = r"
+proc main() -> Int {
+
+    set name : Symbol = ~blah;
+    set p1 : Int = 2;
+
+    set cell : Ref = cons name (p1);
+
+    delete cell;
+    delete cell;
+
+    return p1;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::DanglingRef { .. }));
+}
+
+#[test]
+fn should_not_eq_stale_ref_with_reused_slot() {
+    let input = r"
+proc main() -> Bool {
+
+    set name : Symbol = ~blah;
+    set p1 : Int = 2;
+
+    set old : Ref = cons name (p1);
+    set copy : Ref = old;
+
+    delete old;
+
+    set new : Ref = cons name (p1);
+
+    set ret : Bool = call eq_ref(copy, new);
+
+    return ret;
+}
+"; 
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Bool(x), x);
+    assert_eq!(output, false);
+}
+
+#[test]
+fn should_sum_slots_with_dynamic_index() {
+    let input = r"
+proc main() -> Int {
+    set name : Symbol = ~blah;
+    set a : Int = 1;
+    set b : Int = 20;
+    set c : Int = 300;
+    set cell : Ref = cons name (a, b, c);
+
+    set one : Int = 1;
+    set len : Int = length cell;
+    set i : Int = 0;
+    set total : Int = 0;
+    label loop;
+    set done : Bool = call eq_int(i, len);
+    branch_true exit done;
+    set v : Int = slot cell i;
+    set total : Int = call add_int(total, v);
+    set i : Int = call add_int(i, one);
+    jump loop;
+
+    label exit;
+    return total;
+}
+"; 
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 321);
+}
+
+#[test]
+fn should_insert_and_remove_slot_with_dynamic_index() {
+    let input = r"
+proc main() -> Int {
+    set name : Symbol = ~blah;
+    set a : Int = 1;
+    set b : Int = 2;
+    set cell : Ref = cons name (a, b);
+
+    set end : Int = length cell;
+    set c : Int = 30;
+    slot_insert cell c end;
+    set zero : Int = 0;
+    slot_remove cell zero;
+
+    set x : Int = slot cell zero;
+    set one : Int = 1;
+    set y : Int = slot cell one;
+    set ret : Int = call add_int(x, y);
+
+    return ret;
+}
+"; 
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 32);
+}
+
+#[test]
+fn should_fail_dynamic_slot_past_end() {
+    let input = r"
+proc main() -> Int {
+    set name : Symbol = ~blah;
+    set a : Int = 1;
+    set cell : Ref = cons name (a);
+    set i : Int = 1;
+    set ret : Int = slot cell i;
+    return ret;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::AccessMissingSlotIndex { index: 1, .. }));
+}
+
+#[test]
+fn should_fail_dynamic_slot_with_negative_index() {
+    let input = r"
+proc main() -> Int {
+    set name : Symbol = ~blah;
+    set a : Int = 1;
+    set cell : Ref = cons name (a);
+    set i : Int = -1;
+    slot_remove cell i;
+    return a;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::AccessMissingSlotIndex { index: -1, .. }));
+}
+
+#[test]
+fn should_fail_static_slot_past_end() {
+    let input = r"
+proc main() -> Int {
+    set name : Symbol = ~blah;
+    set a : Int = 1;
+    set cell : Ref = cons name (a);
+    set ret : Int = slot cell 1;
+    return ret;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::AccessMissingSlotIndex { index: 1, .. }));
+}
@@ -0,0 +1,177 @@
+
+use crate::util::proj;
+use crate::eval::data::RuntimeData;
+use crate::eval::error::VmError;
+
+use super::util::{ test, test_fails };
+
+#[test]
+fn should_catch_throw_in_same_proc() {
+    let input = r"
+proc main() -> Int {
+    set err : Int = 0;
+    try handler err;
+    set x : Int = 7;
+    throw x;
+    label handler;
+    return err;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 7);
+}
+
+#[test]
+fn should_unwind_calls_to_handler() {
+    let input = r"
+proc inner(x : Int) -> Int {
+    throw x;
+}
+proc outer(x : Int) -> Int {
+    set y : Int = call inner(x);
+    return y;
+}
+proc main() -> Int {
+    set err : Int = 0;
+    try handler err;
+    set x : Int = 5;
+    set y : Int = call outer(x);
+    end_try;
+    return y;
+    label handler;
+    set ten : Int = 10;
+    set ret : Int = call add_int(err, ten);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 15);
+}
+
+#[test]
+fn should_use_innermost_handler() {
+    let input = r"
+proc main() -> Int {
+    set outer_err : Int = 0;
+    set inner_err : Int = 0;
+    try outer inner_err;
+    try inner outer_err;
+    set x : Int = 3;
+    throw x;
+    label inner;
+    set one : Int = 1;
+    set x : Int = call add_int(outer_err, one);
+    throw x;
+    label outer;
+    return inner_err;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 4);
+}
+
+#[test]
+fn should_fail_with_uncaught_after_end_try() {
+    let input = r"
+proc thrower(x : Int) -> Int {
+    throw x;
+}
+proc main() -> Int {
+    set err : Int = 0;
+    try handler err;
+    end_try;
+    set x : Int = 9;
+    set y : Int = call thrower(x);
+    label handler;
+    return err;
+}
+";
+
+    let output = test_fails(input);
+    let (value, stack_trace) = proj!(output, VmError::Uncaught { value, stack_trace }, (value, stack_trace));
+    assert!(matches!(value, RuntimeData::Int(9)));
+    let names = stack_trace.iter().map(|(name, _, _)| name.to_string()).collect::<Vec<_>>();
+    assert_eq!(names, ["main", "thrower"]);
+}
+
+#[test]
+fn should_end_coroutine_that_throws_to_resumer() {
+    let input = r"
+proc target() -> Int {
+    set x : Int = 1;
+    yield x;
+    set x : Int = 2;
+    throw x;
+}
+proc main() -> Bool {
+    set co : Coroutine = coroutine target();
+    set err : Int = 0;
+    set a : Int = resume co;
+    try handler err;
+    set b : Int = resume co;
+    end_try;
+    label handler;
+    set two : Int = 2;
+    set caught : Bool = call eq_int(err, two);
+    set c : Int = resume co;
+    set ended : Bool = is_nil c;
+    set ret : Bool = call and(caught, ended);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Bool(x), x);
+    assert!(output);
+}
+
+#[test]
+fn should_catch_inside_coroutine() {
+    let input = r"
+proc target() -> Int {
+    set err : Int = 0;
+    try handler err;
+    set x : Int = 1;
+    yield x;
+    set x : Int = 20;
+    throw x;
+    label handler;
+    yield err;
+    break;
+}
+proc main() -> Int {
+    set co : Coroutine = coroutine target();
+    set a : Int = resume co;
+    set b : Int = resume co;
+    set ret : Int = call add_int(a, b);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 21);
+}
+
+#[test]
+fn should_not_use_handler_of_suspended_coroutine() {
+    let input = r"
+proc target() -> Int {
+    set err : Int = 0;
+    try handler err;
+    set x : Int = 1;
+    yield x;
+    label handler;
+    break;
+}
+proc main() -> Int {
+    set co : Coroutine = coroutine target();
+    set a : Int = resume co;
+    throw a;
+}
+";
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::Uncaught { value: RuntimeData::Int(1), .. }));
+}
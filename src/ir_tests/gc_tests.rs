@@ -0,0 +1,174 @@
+
+use crate::util::proj;
+use crate::runtime::Runtime;
+use crate::eval::data::RuntimeData;
+use crate::eval::vm::GcConfig;
+
+use super::util::test_with_gc;
+
+const SMALL : GcConfig = GcConfig { threshold: 4, growth_factor: 1 };
+
+#[test]
+fn should_keep_reachable_list_across_collections() {
+    let input = r"
+proc main() -> Int {
+    set zero : Int = 0;
+    set one : Int = 1;
+    set max : Int = 100;
+    set name : Symbol = ~node;
+    set end : Symbol = ~end;
+
+    set list : Ref = cons end ();
+    set i : Int = 0;
+    label build;
+    set garbage : Ref = cons end (i);
+    set list : Ref = cons name (i, list);
+    set i : Int = call add_int(i, one);
+    set done : Bool = call eq_int(i, max);
+    branch_true sum done;
+    jump build;
+
+    label sum;
+    set total : Int = 0;
+    label walk;
+    set t : Symbol = type list;
+    set at_end : Bool = call eq_symbol(t, end);
+    branch_true exit at_end;
+    set v : Int = slot list 0;
+    set total : Int = call add_int(total, v);
+    set list : Ref = slot list 1;
+    jump walk;
+
+    label exit;
+    return total;
+}
+";
+
+    let output = proj!(test_with_gc(input, SMALL).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 4950);
+}
+
+#[test]
+fn should_keep_refs_captured_by_closures_and_coroutines() {
+    let input = r"
+proc get(r : Ref) -> Int {
+    set x : Int = slot r 0;
+    return x;
+}
+proc gen(r : Ref) -> Int {
+    set x : Int = slot r 0;
+    yield x;
+    set x : Int = slot r 0;
+    yield x;
+    break;
+}
+proc main() -> Int {
+    set name : Symbol = ~cell;
+    set seven : Int = 7;
+    set eight : Int = 8;
+
+    set a : Ref = cons name (seven);
+    set f : Closure = closure get(a);
+    set a : Ref = cons name (seven);
+
+    set b : Ref = cons name (eight);
+    set co : Coroutine = coroutine gen(b);
+    set first : Int = resume co;
+    set b : Ref = cons name (eight);
+
+    set i : Int = 0;
+    set one : Int = 1;
+    set max : Int = 50;
+    label churn;
+    set garbage : Ref = cons name (i);
+    set i : Int = call add_int(i, one);
+    set done : Bool = call eq_int(i, max);
+    branch_true exit done;
+    jump churn;
+
+    label exit;
+    set x : Int = dyn_call f();
+    set y : Int = resume co;
+    set ret : Int = call add_int(x, y);
+    set ret : Int = call add_int(ret, first);
+    return ret;
+}
+";
+
+    let output = proj!(test_with_gc(input, SMALL).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 23);
+}
+
+#[test]
+fn should_keep_split_list_built_across_collections() {
+    let input = r#"
+proc main() -> Int {
+    set s : String = "1,2,3,4,5,6,7,8,9,10";
+    set sep : String = ",";
+    set list : Ref = call split(s, sep);
+
+    set total : Int = 0;
+    set nil : Symbol = ~nil;
+    label walk;
+    set t : Symbol = type list;
+    set at_end : Bool = call eq_symbol(t, nil);
+    branch_true exit at_end;
+    set part : String = slot list 0;
+    set v : Int = call parse_int(part);
+    set total : Int = call add_int(total, v);
+    set list : Ref = slot list 1;
+    jump walk;
+
+    label exit;
+    return total;
+}
+"#;
+
+    let output = proj!(test_with_gc(input, SMALL).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 55);
+}
+
+#[test]
+fn should_collect_arrays_from_split_and_map_keys() {
+    let input = r#"
+proc main() -> Int {
+    set one : Int = 1;
+    set max : Int = 200;
+    set max2 : Int = 400;
+    set s : String = "a,b,c";
+    set sep : String = ",";
+    set m : Ref = map_new;
+    map_insert m one one;
+
+    set i : Int = 0;
+    label split;
+    set parts : Ref = call split(s, sep);
+    set i : Int = call add_int(i, one);
+    set done : Bool = call eq_int(i, max);
+    branch_true keys done;
+    jump split;
+
+    label keys;
+    set keys : Coroutine = coroutine map_keys(m);
+    set k : Int = resume keys;
+    set i : Int = call add_int(i, k);
+    set done : Bool = call eq_int(i, max2);
+    branch_true exit done;
+    jump keys;
+
+    label exit;
+    return i;
+}
+"#;
+
+    for optimise in [false, true] {
+        let mut runtime = Runtime::new();
+        runtime.set_gc(SMALL);
+        runtime.set_optimise(optimise);
+        runtime.load("test", input).unwrap();
+        let mut program = runtime.compile().unwrap();
+        let output = program.run("main", vec![]).unwrap().unwrap();
+        assert_eq!(proj!(output, RuntimeData::Int(x), x), 400);
+        assert!(program.vm().heap_stats().peak <= 16, "{:?}", program.vm().heap_stats());
+    }
+}
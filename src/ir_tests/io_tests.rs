@@ -0,0 +1,87 @@
+use crate::util::proj;
+use crate::eval::data::RuntimeData;
+use crate::eval::error::VmError;
+use crate::eval::io::MemoryIo;
+
+use super::util::test_with_io;
+
+#[test]
+fn should_print() {
+    let input = r#"
+proc main() -> Int {
+    set a : String = "hello";
+    set b : String = " world";
+    set x : Int = call print(a);
+    set x : Int = call print_line(b);
+    set one : Int = 1;
+    set s : String = to_string one;
+    set x : Int = call print_line(s);
+    return x;
+}
+"#; 
+
+    let io = MemoryIo::new();
+    let output = proj!(test_with_io(input, &io).unwrap().unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 2);
+    assert_eq!(io.output(), "hello world\n1\n");
+}
+
+#[test]
+fn should_read_lines_until_nil() {
+    let input = r#"
+proc main() -> Int {
+    set count : Int = 0;
+    set one : Int = 1;
+    label loop;
+    set line : String = call read_line();
+    set done : Bool = is_nil line;
+    branch_true exit done;
+    set x : Int = call print(line);
+    set count : Int = call add_int(count, one);
+    jump loop;
+    label exit;
+    return count;
+}
+"#; 
+
+    let io = MemoryIo::new();
+    io.push_line("a");
+    io.push_line("b");
+    let output = proj!(test_with_io(input, &io).unwrap().unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 2);
+    assert_eq!(io.output(), "ab");
+}
+
+#[test]
+fn should_read_and_write_files() {
+    let input = r#"
+proc main() -> Int {
+    set from : String = "in.txt";
+    set to : String = "out.txt";
+    set text : String = call read_file(from);
+    set text : String = call upper(text);
+    set x : Int = call write_file(to, text);
+    return x;
+}
+"#; 
+
+    let io = MemoryIo::new();
+    io.add_file("in.txt", "shout");
+    let output = proj!(test_with_io(input, &io).unwrap().unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 5);
+    assert_eq!(io.file("out.txt").unwrap(), "SHOUT");
+}
+
+#[test]
+fn should_fail_on_missing_file() {
+    let input = r#"
+proc main() -> String {
+    set path : String = "missing.txt";
+    set text : String = call read_file(path);
+    return text;
+}
+"#; 
+
+    let output = test_with_io(input, &MemoryIo::new()).unwrap_err();
+    assert!(matches!(output, VmError::Io(_, _)));
+}
@@ -0,0 +1,237 @@
+
+use crate::util::proj;
+use crate::runtime::{ Runtime, Error };
+use crate::compiling::ir_compiler::CompileError;
+use crate::eval::data::RuntimeData;
+use crate::eval::error::VmError;
+use crate::eval::vm::GcConfig;
+
+use super::util::{ test, test_fails, test_with_gc };
+
+#[test]
+fn should_insert_and_get() {
+    let input = r#"
+proc main() -> Int {
+    set m : Ref = map_new;
+    set k1 : Symbol = ~a;
+    set k2 : String = "a";
+    set one : Int = 1;
+    set two : Int = 2;
+    map_insert m k1 one;
+    map_insert m k2 two;
+
+    set x : Int = map_get m k1;
+    set y : Int = map_get m k2;
+    set ret : Int = call mul_int(x, y);
+    set y : Int = call add_int(y, y);
+    set ret : Int = call add_int(ret, y);
+
+    return ret;
+}
+"#;
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 6);
+}
+
+#[test]
+fn should_overwrite_existing_key() {
+    let input = r"
+proc main() -> Int {
+    set m : Ref = map_new;
+    set k : Int = 5;
+    set one : Int = 1;
+    set two : Int = 2;
+    map_insert m k one;
+    map_insert m k two;
+
+    set x : Int = map_get m k;
+    set len : Int = length m;
+    set ret : Int = call add_int(x, len);
+
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 3);
+}
+
+#[test]
+fn should_get_nil_when_absent() {
+    let input = r"
+proc main() -> Bool {
+    set m : Ref = map_new;
+    set k : Bool = true;
+    set x : Int = map_get m k;
+    set ret : Bool = is_nil x;
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Bool(x), x);
+    assert!(output);
+}
+
+#[test]
+fn should_remove_and_check_contains() {
+    let input = r"
+proc main() -> Bool {
+    set m : Ref = map_new;
+    set k : Int = 1;
+    map_insert m k k;
+    set before : Bool = map_contains m k;
+    map_remove m k;
+    set after : Bool = map_contains m k;
+    set after : Bool = call not(after);
+    set ret : Bool = call and(before, after);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Bool(x), x);
+    assert!(output);
+}
+
+#[test]
+fn should_get_map_type() {
+    let input = r"
+proc main() -> Symbol {
+    set m : Ref = map_new;
+    set ret : Symbol = type m;
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Symbol(x), x);
+    assert_eq!(&*output, "map");
+}
+
+#[test]
+fn should_iterate_keys_with_coroutine() {
+    let input = r"
+proc main() -> Int {
+    set m : Ref = map_new;
+    set k : Int = 1;
+    map_insert m k k;
+    set k : Int = 10;
+    map_insert m k k;
+    set k : Int = 100;
+    map_insert m k k;
+
+    set keys : Coroutine = coroutine map_keys(m);
+    set total : Int = 0;
+    label loop;
+    set k : Int = resume keys;
+    set done : Bool = is_nil k;
+    branch_true exit done;
+    set v : Int = map_get m k;
+    set total : Int = call add_int(total, v);
+    map_remove m k;
+    jump loop;
+
+    label exit;
+    set len : Int = length m;
+    set ret : Int = call add_int(total, len);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 111);
+}
+
+#[test]
+fn should_fail_with_float_key() {
+    let input = r"
+proc main() -> Int {
+    set m : Ref = map_new;
+    set k : Float = 1.0;
+    set ret : Int = map_get m k;
+    return ret;
+}
+";
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::LocalUnexpectedType { .. }));
+}
+
+#[test]
+fn should_fail_map_get_on_array() {
+    let input = r"
+proc main() -> Int {
+    set arr : Ref = array ();
+    set k : Int = 0;
+    set ret : Int = map_get arr k;
+    return ret;
+}
+";
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::HeapUnexpectedType { expected: "map", .. }));
+}
+
+#[test]
+fn should_keep_map_values_across_collections() {
+    let input = r"
+proc main() -> Int {
+    set one : Int = 1;
+    set max : Int = 50;
+    set name : Symbol = ~node;
+
+    set m : Ref = map_new;
+    set i : Int = 0;
+    label build;
+    set garbage : Ref = cons name (i);
+    set cell : Ref = cons name (i);
+    map_insert m i cell;
+    set i : Int = call add_int(i, one);
+    set done : Bool = call eq_int(i, max);
+    branch_true sum done;
+    jump build;
+
+    label sum;
+    set keys : Coroutine = coroutine map_keys(m);
+    set total : Int = 0;
+    label walk;
+    set k : Int = resume keys;
+    set at_end : Bool = is_nil k;
+    branch_true exit at_end;
+    set cell : Ref = map_get m k;
+    set v : Int = slot cell 0;
+    set total : Int = call add_int(total, v);
+    jump walk;
+
+    label exit;
+    return total;
+}
+";
+
+    let output = proj!(test_with_gc(input, GcConfig { threshold: 4, growth_factor: 1 }).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 1225);
+}
+
+#[test]
+fn should_fail_to_compile_map_keys_outside_coroutine() {
+    let call = r"
+proc main() -> Int {
+    set m : Ref = map_new;
+    set k : Ref = call map_keys(m);
+    set x : Int = 0;
+    return x;
+}
+";
+    let closure = r"
+proc main() -> Int {
+    set k : Closure = closure map_keys();
+    set x : Int = 0;
+    return x;
+}
+";
+
+    for input in [call, closure] {
+        let mut runtime = Runtime::new();
+        runtime.load("test.ir", input).unwrap();
+        assert!(matches!(runtime.compile(), Err(Error::Compile(CompileError::CoroutineOnlyProc { .. }))));
+    }
+}
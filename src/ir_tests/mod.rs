@@ -8,4 +8,12 @@ pub mod closure_tests;
 pub mod coroutine_tests;
 pub mod dyn_coroutine_tests;
 pub mod program_tests;
-
+pub mod gc_tests;
+pub mod native_tests;
+pub mod tail_call_tests;
+pub mod string_tests;
+pub mod io_tests;
+pub mod array_tests;
+pub mod map_tests;
+pub mod exception_tests;
+pub mod module_tests;
@@ -0,0 +1,167 @@
+
+use crate::util::proj;
+use crate::runtime::{ Runtime, Error };
+use crate::compiling::ir_compiler::CompileError;
+use crate::eval::data::RuntimeData;
+
+use super::util::test_with_runtime;
+
+const LIST : &str = r"
+pub proc double(x : Int) -> Int {
+    set y : Int = call helper(x);
+    return y;
+}
+proc helper(x : Int) -> Int {
+    set y : Int = call add_int(x, x);
+    return y;
+}
+";
+
+fn compile_error(runtime : Runtime) -> CompileError {
+    match runtime.compile() {
+        Err(Error::Compile(x)) => x,
+        Err(x) => panic!("expected compile error, but found {x}"),
+        Ok(_) => panic!("expected compile error"),
+    }
+}
+
+#[test]
+fn should_call_pub_proc_with_qualified_name() {
+    let input = r#"
+import "list.ir";
+proc double(x : Int) -> Int {
+    return x;
+}
+proc main() -> Int {
+    set x : Int = 3;
+    set x : Int = call list::double(x);
+    set x : Int = call double(x);
+    return x;
+}
+"#;
+
+    let mut runtime = Runtime::new();
+    runtime.load_module("list.ir", LIST).unwrap();
+    let output = proj!(test_with_runtime(runtime, input).unwrap().unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 6);
+}
+
+#[test]
+fn should_name_module_procs_with_namespace() {
+    let mut runtime = Runtime::new();
+    runtime.load_module("lib/list.ir", LIST).unwrap();
+    let mut program = runtime.compile().unwrap();
+    let output = program.run("list::double", vec![RuntimeData::Int(4)]).unwrap().unwrap();
+    assert_eq!(proj!(output, RuntimeData::Int(x), x), 8);
+    assert!(program.proc_id("double").is_none());
+}
+
+#[test]
+fn should_fail_calling_private_proc_of_module() {
+    let input = r#"
+import "list.ir";
+proc main() -> Int {
+    set x : Int = 3;
+    set x : Int = call list::helper(x);
+    return x;
+}
+"#;
+
+    let mut runtime = Runtime::new();
+    runtime.load_module("list.ir", LIST).unwrap();
+    runtime.load("main.ir", input).unwrap();
+    assert!(matches!(compile_error(runtime), CompileError::PrivateProc { .. }));
+}
+
+#[test]
+fn should_fail_calling_private_proc_of_other_file() {
+    let mut runtime = Runtime::new();
+    runtime.load("a.ir", "proc one() -> Int { set x : Int = 1; return x; }").unwrap();
+    runtime.load("b.ir", "proc main() -> Int { set x : Int = call one(); return x; }").unwrap();
+    assert!(matches!(compile_error(runtime), CompileError::PrivateProc { .. }));
+
+    let mut runtime = Runtime::new();
+    runtime.load("a.ir", "pub proc one() -> Int { set x : Int = 1; return x; }").unwrap();
+    runtime.load("b.ir", "proc main() -> Int { set x : Int = call one(); return x; }").unwrap();
+    assert!(runtime.compile().is_ok());
+}
+
+#[test]
+fn should_keep_private_procs_of_root_files_apart() {
+    let a = r"
+pub proc a() -> Int {
+    set x : Int = call helper();
+    return x;
+}
+proc helper() -> Int {
+    set x : Int = 1;
+    return x;
+}
+";
+    let b = r"
+proc main() -> Int {
+    set x : Int = call a();
+    set y : Int = call helper();
+    set x : Int = call add_int(x, y);
+    return x;
+}
+proc helper() -> Int {
+    set x : Int = 10;
+    return x;
+}
+";
+
+    let mut runtime = Runtime::new();
+    runtime.load("a.ir", a).unwrap();
+    runtime.load("b.ir", b).unwrap();
+    let mut program = runtime.compile().unwrap();
+    let output = program.run("main", vec![]).unwrap().unwrap();
+    assert_eq!(proj!(output, RuntimeData::Int(x), x), 11);
+}
+
+#[test]
+fn should_fail_using_namespace_without_import() {
+    let input = r"
+proc main() -> Int {
+    set x : Int = 3;
+    set x : Int = call list::double(x);
+    return x;
+}
+";
+
+    let mut runtime = Runtime::new();
+    runtime.load_module("list.ir", LIST).unwrap();
+    runtime.load("main.ir", input).unwrap();
+    assert!(matches!(compile_error(runtime), CompileError::NamespaceNotImported { .. }));
+}
+
+#[test]
+fn should_fail_with_duplicate_proc() {
+    let mut runtime = Runtime::new();
+    runtime.load("a.ir", "pub proc main() -> Int { set x : Int = 1; return x; }").unwrap();
+    runtime.load("b.ir", "pub proc main() -> Int { set x : Int = 2; return x; }").unwrap();
+    assert!(matches!(compile_error(runtime), CompileError::DuplicateProc { .. }));
+
+    let mut runtime = Runtime::new();
+    runtime.load("a.ir", "proc one() -> Int { set x : Int = 1; return x; }").unwrap();
+    runtime.load("b.ir", "pub proc one() -> Int { set x : Int = 2; return x; }").unwrap();
+    assert!(matches!(compile_error(runtime), CompileError::DuplicateProc { .. }));
+
+    let mut runtime = Runtime::new();
+    runtime.load("a.ir", "proc add_int(a : Int, b : Int) -> Int { return a; }").unwrap();
+    assert!(matches!(compile_error(runtime), CompileError::DuplicateProc { .. }));
+}
+
+#[test]
+fn should_fail_with_missing_import() {
+    let mut runtime = Runtime::new();
+    runtime.load("main.ir", "import \"list.ir\"; proc main() -> Int { set x : Int = 1; return x; }").unwrap();
+    assert!(matches!(runtime.compile(), Err(Error::MissingImport { .. })));
+}
+
+#[test]
+fn should_fail_with_clashing_namespaces() {
+    let mut runtime = Runtime::new();
+    runtime.load_module("a/list.ir", LIST).unwrap();
+    assert!(matches!(runtime.load_module("b/list.ir", LIST), Err(Error::DuplicateNamespace { .. })));
+}
@@ -0,0 +1,92 @@
+
+use crate::util::proj;
+use crate::parsing::ir_parser::Type;
+use crate::compiling::ir_compiler::CompileError;
+use crate::eval::data::RuntimeData;
+use crate::eval::error::VmError;
+use crate::runtime::{ Runtime, Error };
+
+use super::util::test_with_runtime;
+
+fn sum3(args : &mut [RuntimeData]) -> Result<RuntimeData, VmError> {
+    match args {
+        [RuntimeData::Int(a), RuntimeData::Int(b), RuntimeData::Int(c)] => Ok(RuntimeData::Int(*a + *b + *c)),
+        _ => Err(VmError::NativeError("sum3 expects ints".into(), vec![])),
+    }
+}
+
+fn fail(_ : &mut [RuntimeData]) -> Result<RuntimeData, VmError> {
+    Err(VmError::NativeError("failure".into(), vec![]))
+}
+
+fn runtime() -> Runtime {
+    let mut runtime = Runtime::new();
+    runtime.register_native("sum3", vec![Type::Int, Type::Int, Type::Int], Type::Int, sum3);
+    runtime.register_native("fail", vec![], Type::Int, fail);
+    runtime
+}
+
+#[test]
+fn should_call_native() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 1;
+    set b : Int = 2;
+    set c : Int = 3;
+    set x : Int = call sum3(a, b, c);
+    return x;
+}
+"; 
+
+    let output = proj!(test_with_runtime(runtime(), input).unwrap().unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 6);
+}
+
+#[test]
+fn should_dyn_call_native_closure() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 1;
+    set b : Int = 2;
+    set c : Int = 3;
+    set f : Closure = closure sum3(a);
+    set x : Int = dyn_call f(b, c);
+    return x;
+}
+"; 
+
+    let output = proj!(test_with_runtime(runtime(), input).unwrap().unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 6);
+}
+
+#[test]
+fn should_report_native_error_with_stack_trace() {
+    let input = r"
+proc main() -> Int {
+    set x : Int = call fail();
+    set y : Int = x;
+    return y;
+}
+"; 
+
+    let output = test_with_runtime(runtime(), input).unwrap_err();
+    let trace = proj!(output, VmError::NativeError(_, trace), trace);
+    assert_eq!(trace.iter().map(|(n, _, _)| n.to_string()).collect::<Vec<_>>(), vec!["main", "fail"]);
+}
+
+#[test]
+fn should_type_check_native_call() {
+    let input = r"
+proc main() -> Int {
+    set a : Int = 1;
+    set b : Float = 2.0;
+    set x : Int = call sum3(a, b, a);
+    return x;
+}
+"; 
+
+    let mut runtime = runtime();
+    runtime.load("test", input).unwrap();
+    let output = runtime.compile();
+    assert!(matches!(output, Err(Error::Compile(CompileError::TypeMismatch { .. }))));
+}
@@ -1,8 +1,9 @@
 
 use crate::util::proj;
 use crate::eval::data::RuntimeData;
+use crate::eval::error::VmError;
 
-use super::util::test;
+use super::util::{ test, test_fails };
 
 #[test]
 fn should_eq_symbols() {
@@ -406,3 +407,130 @@ proc main() -> String {
     assert_eq!(output, "12.2truesymstrcoroutineclosurenilref(0)".into());
 }
 
+
+#[test]
+fn should_fail_on_add_int_overflow() {
+    let input = r"
+proc main() -> Int {
+    set max : Int = 9223372036854775807;
+    set one : Int = 1;
+    set x : Int = call add_int(max, one);
+    return x;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::IntegerOverflow(_)));
+}
+
+#[test]
+fn should_fail_on_mul_int_overflow() {
+    let input = r"
+proc main() -> Int {
+    set max : Int = 9223372036854775807;
+    set two : Int = 2;
+    set x : Int = call mul_int(max, two);
+    return x;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::IntegerOverflow(_)));
+}
+
+#[test]
+fn should_fail_on_div_int_overflow() {
+    let input = r"
+proc main() -> Int {
+    set max : Int = 9223372036854775807;
+    set one : Int = 1;
+    set min : Int = call neg_int(max);
+    set min : Int = call sub_int(min, one);
+    set neg_one : Int = call neg_int(one);
+    set x : Int = call div_int(min, neg_one);
+    return x;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::IntegerOverflow(_)));
+}
+
+#[test]
+fn should_fail_on_div_int_by_zero() {
+    let input = r"
+proc main() -> Int {
+    set one : Int = 1;
+    set zero : Int = 0;
+    set x : Int = call div_int(one, zero);
+    return x;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::DivideByZero(_)));
+}
+
+#[test]
+fn should_fail_on_mod_int_by_zero() {
+    let input = r"
+proc main() -> Int {
+    set one : Int = 1;
+    set zero : Int = 0;
+    set x : Int = call mod_int(one, zero);
+    return x;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::DivideByZero(_)));
+}
+
+#[test]
+fn should_add_int_wrapping() {
+    let input = r"
+proc main() -> Int {
+    set max : Int = 9223372036854775807;
+    set one : Int = 1;
+    set x : Int = call add_int_wrapping(max, one);
+    return x;
+}
+"; 
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, i64::MIN);
+}
+
+#[test]
+fn should_div_and_neg_int_wrapping() {
+    let input = r"
+proc main() -> Int {
+    set max : Int = 9223372036854775807;
+    set one : Int = 1;
+    set min : Int = call neg_int(max);
+    set min : Int = call sub_int(min, one);
+    set neg_one : Int = call neg_int(one);
+    set x : Int = call div_int_wrapping(min, neg_one);
+    set x : Int = call neg_int_wrapping(x);
+    return x;
+}
+"; 
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, i64::MIN);
+}
+
+#[test]
+fn should_fail_on_wrapping_div_int_by_zero() {
+    let input = r"
+proc main() -> Int {
+    set one : Int = 1;
+    set zero : Int = 0;
+    set x : Int = call div_int_wrapping(one, zero);
+    return x;
+}
+"; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::DivideByZero(_)));
+}
@@ -0,0 +1,216 @@
+use crate::util::proj;
+use crate::eval::data::RuntimeData;
+use crate::eval::error::VmError;
+
+use super::util::{ test, test_fails };
+
+fn string(input : &str) -> String {
+    proj!(test(input).unwrap(), RuntimeData::String(x), x.to_string())
+}
+
+fn int(input : &str) -> i64 {
+    proj!(test(input).unwrap(), RuntimeData::Int(x), x)
+}
+
+fn bool(input : &str) -> bool {
+    proj!(test(input).unwrap(), RuntimeData::Bool(x), x)
+}
+
+#[test]
+fn should_eq_strings() {
+    let input = r#"
+proc main() -> Bool {
+    set a : String = "blah";
+    set b : String = "blah";
+    set x : Bool = call eq_string(a, b);
+    return x;
+}
+"#; 
+
+    assert!(bool(input));
+}
+
+#[test]
+fn should_compare_strings() {
+    let input = r#"
+proc main() -> Bool {
+    set a : String = "apple";
+    set b : String = "banana";
+    set x : Bool = call lt_string(a, b);
+    set y : Bool = call gt_string(a, b);
+    set ret : Bool = call xor(x, y);
+    return ret;
+}
+"#; 
+
+    assert!(bool(input));
+}
+
+#[test]
+fn should_get_length_in_chars() {
+    let input = r#"
+proc main() -> Int {
+    set s : String = "héllo";
+    set x : Int = call length_string(s);
+    return x;
+}
+"#; 
+
+    assert_eq!(int(input), 5);
+}
+
+#[test]
+fn should_substring() {
+    let input = r#"
+proc main() -> String {
+    set s : String = "héllo world";
+    set start : Int = 1;
+    set end : Int = 5;
+    set x : String = call substring(s, start, end);
+    return x;
+}
+"#; 
+
+    assert_eq!(string(input), "éllo");
+}
+
+#[test]
+fn should_fail_on_substring_out_of_range() {
+    let input = r#"
+proc main() -> String {
+    set s : String = "hello";
+    set start : Int = 2;
+    set end : Int = 6;
+    set x : String = call substring(s, start, end);
+    return x;
+}
+"#; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::StringIndexOutOfRange { index: 6, length: 5, .. }));
+}
+
+#[test]
+fn should_char_at() {
+    let input = r#"
+proc main() -> String {
+    set s : String = "héllo";
+    set i : Int = 1;
+    set x : String = call char_at(s, i);
+    return x;
+}
+"#; 
+
+    assert_eq!(string(input), "é");
+}
+
+#[test]
+fn should_fail_on_char_at_end() {
+    let input = r#"
+proc main() -> String {
+    set s : String = "hello";
+    set i : Int = 5;
+    set x : String = call char_at(s, i);
+    return x;
+}
+"#; 
+
+    let output = test_fails(input);
+    assert!(matches!(output, VmError::StringIndexOutOfRange { index: 5, length: 5, .. }));
+}
+
+#[test]
+fn should_index_of() {
+    let input = r#"
+proc main() -> Int {
+    set s : String = "héllo world";
+    set a : String = "world";
+    set b : String = "moon";
+    set x : Int = call index_of(s, a);
+    set y : Int = call index_of(s, b);
+    set ret : Int = call add_int(x, y);
+    return ret;
+}
+"#; 
+
+    assert_eq!(int(input), 5);
+}
+
+#[test]
+fn should_split_into_cons_list() {
+    let input = r#"
+proc main() -> String {
+    set s : String = "a,b,,c";
+    set sep : String = ",";
+    set list : Ref = call split(s, sep);
+    set nil : Symbol = ~nil;
+    set ret : String = "";
+    set bar : String = "|";
+    label walk;
+    set t : Symbol = type list;
+    set at_end : Bool = call eq_symbol(t, nil);
+    branch_true exit at_end;
+    set part : String = slot list 0;
+    set ret : String = concat ret part;
+    set ret : String = concat ret bar;
+    set list : Ref = slot list 1;
+    jump walk;
+    label exit;
+    return ret;
+}
+"#; 
+
+    assert_eq!(string(input), "a|b||c|");
+}
+
+#[test]
+fn should_change_case_and_trim() {
+    let input = r#"
+proc main() -> String {
+    set s : String = "  Hello ";
+    set a : String = call trim(s);
+    set b : String = call upper(a);
+    set c : String = call lower(a);
+    set ret : String = concat b c;
+    return ret;
+}
+"#; 
+
+    assert_eq!(string(input), "HELLOhello");
+}
+
+#[test]
+fn should_parse_numbers() {
+    let input = r#"
+proc main() -> Float {
+    set a : String = "42";
+    set b : String = "0.5";
+    set x : Int = call parse_int(a);
+    set y : Float = call parse_float(b);
+    set s : String = to_string x;
+    set z : Float = call parse_float(s);
+    set ret : Float = call add_float(z, y);
+    return ret;
+}
+"#; 
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Float(x), x);
+    assert_eq!(output, 42.5);
+}
+
+#[test]
+fn should_return_nil_on_parse_failure() {
+    let input = r#"
+proc main() -> Bool {
+    set a : String = "4x2";
+    set x : Int = call parse_int(a);
+    set y : Float = call parse_float(a);
+    set p : Bool = is_nil x;
+    set q : Bool = is_nil y;
+    set ret : Bool = call and(p, q);
+    return ret;
+}
+"#; 
+
+    assert!(bool(input));
+}
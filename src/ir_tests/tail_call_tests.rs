@@ -0,0 +1,167 @@
+use crate::util::proj;
+use crate::runtime::Runtime;
+use crate::eval::data::{ RuntimeData, Op };
+
+use super::util::test;
+
+#[test]
+fn should_tail_call_recursive_proc() {
+    let input = r"
+proc count(n : Int, acc : Int) -> Int {
+    set zero : Int = 0;
+    set one : Int = 1;
+    set done : Bool = call eq_int(n, zero);
+    branch_true end done;
+    set n : Int = call sub_int(n, one);
+    set acc : Int = call add_int(acc, one);
+    set acc : Int = call count(n, acc);
+    return acc;
+    label end;
+    return acc;
+}
+proc main() -> Int {
+    set n : Int = 100000;
+    set acc : Int = 0;
+    set ret : Int = call count(n, acc);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 100000);
+}
+
+#[test]
+fn should_tail_call_closure() {
+    let input = r"
+proc count(f : Closure, n : Int) -> Int {
+    set zero : Int = 0;
+    set one : Int = 1;
+    set done : Bool = call eq_int(n, zero);
+    branch_true end done;
+    set n : Int = call sub_int(n, one);
+    set n : Int = dyn_call f(f, n);
+    return n;
+    label end;
+    return n;
+}
+proc main() -> Int {
+    set f : Closure = closure count();
+    set n : Int = 100000;
+    set ret : Int = dyn_call f(f, n);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 0);
+}
+
+#[test]
+fn should_tail_call_with_env() {
+    let input = r"
+proc add(a : Int, b : Int) -> Int {
+    set ret : Int = call add_int(a, b);
+    return ret;
+}
+proc main() -> Int {
+    set a : Int = 7;
+    set b : Int = 8;
+    set f : Closure = closure add(a);
+    set ret : Int = dyn_call f(b);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 15);
+}
+
+#[test]
+fn should_not_tail_call_from_coroutine() {
+    let input = r"
+proc seven() -> Int {
+    set x : Int = 7;
+    return x;
+}
+proc target() -> Int {
+    set x : Int = call seven();
+    yield x;
+    set x : Int = call seven();
+    return x;
+}
+proc main() -> Int {
+    set co : Coroutine = coroutine target();
+    set a : Int = resume co;
+    set b : Int = resume co;
+    set ret : Int = call add_int(a, b);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 14);
+}
+
+#[test]
+fn should_tail_call_after_try_region() {
+    let input = r"
+proc count(n : Int) -> Int {
+    set zero : Int = 0;
+    set one : Int = 1;
+    set e : Int = 0;
+    try caught e;
+    set n : Int = call sub_int(n, one);
+    end_try;
+    set done : Bool = call lt_int(n, zero);
+    branch_true end done;
+    set n : Int = call count(n);
+    return n;
+    label caught;
+    return e;
+    label end;
+    return n;
+}
+proc main() -> Int {
+    set n : Int = 100000;
+    set ret : Int = call count(n);
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, -1);
+
+    let mut runtime = Runtime::new();
+    runtime.load("test", input).unwrap();
+    let program = runtime.compile().unwrap();
+    let count = program.proc_id("count").unwrap();
+    assert!(program.vm().procs()[count].instrs.iter().any(|x| matches!(x, Op::TailCall(..))));
+}
+
+#[test]
+fn should_not_tail_call_inside_try_region() {
+    let input = r"
+proc fail(x : Int) -> Int {
+    throw x;
+}
+proc target() -> Int {
+    set x : Int = 7;
+    set e : Int = 0;
+    try caught e;
+    set x : Int = call fail(x);
+    return x;
+    label caught;
+    set one : Int = 1;
+    set e : Int = call add_int(e, one);
+    return e;
+}
+proc main() -> Int {
+    set ret : Int = call target();
+    return ret;
+}
+";
+
+    let output = proj!(test(input).unwrap(), RuntimeData::Int(x), x);
+    assert_eq!(output, 8);
+}
@@ -1,22 +1,57 @@
 
-use crate::parsing::ir_parser::parse;
-use crate::compiling::ir_compiler::compile;
+use crate::runtime::{ Runtime, Error };
 use crate::eval::data::RuntimeData;
 use crate::eval::error::VmError;
 use crate::eval::vm::*;
+use crate::eval::io::MemoryIo;
 
 pub fn test(input : &str) -> Option<RuntimeData> {
-    let ir = parse(input).unwrap();
-    let procs = compile(&ir).unwrap();
-    let main = procs.iter().enumerate().find(|(_, x)| *"main" == *x.name ).expect("cannot find main").0;
-    let mut vm = Vm::new(procs);
-    vm.run(main).unwrap()
+    test_with_gc(input, GcConfig::default())
+}
+
+pub fn test_with_gc(input : &str, gc : GcConfig) -> Option<RuntimeData> {
+    let mut runtime = Runtime::new();
+    runtime.set_gc(gc);
+    test_with_runtime(runtime, input).unwrap()
+}
+
+/// Also runs the program with the peephole optimiser on and checks that it gets the same result.
+pub fn test_with_runtime(mut runtime : Runtime, input : &str) -> Result<Option<RuntimeData>, VmError> {
+    runtime.load("test", input).unwrap();
+    let output = run(&runtime);
+
+    runtime.set_optimise(true);
+    let optimised = run(&runtime);
+    match (&output, &optimised) {
+        (Ok(a), Ok(b)) => assert_eq!(format!("{a:?}"), format!("{b:?}")),
+        (Err(a), Err(b)) => assert_eq!(std::mem::discriminant(a), std::mem::discriminant(b)),
+        _ => panic!("optimised result {optimised:?} does not match {output:?}"),
+    }
+
+    output
+}
+
+fn run(runtime : &Runtime) -> Result<Option<RuntimeData>, VmError> {
+    let mut program = runtime.compile().unwrap();
+    match program.run("main", vec![]) {
+        Ok(x) => Ok(x),
+        Err(Error::Vm(x)) => Err(x),
+        Err(x) => panic!("{x}"),
+    }
+}
+
+pub fn test_with_io(input : &str, io : &MemoryIo) -> Result<Option<RuntimeData>, VmError> {
+    let mut runtime = Runtime::new();
+    runtime.load("test", input).unwrap();
+    let mut program = runtime.compile().unwrap();
+    program.set_io(Box::new(io.clone()));
+    match program.run("main", vec![]) {
+        Ok(x) => Ok(x),
+        Err(Error::Vm(x)) => Err(x),
+        Err(x) => panic!("{x}"),
+    }
 }
 
 pub fn test_fails(input : &str) -> VmError {
-    let ir = parse(input).unwrap();
-    let procs = compile(&ir).unwrap();
-    let main = procs.iter().enumerate().find(|(_, x)| *"main" == *x.name ).expect("cannot find main").0;
-    let mut vm = Vm::new(procs);
-    vm.run(main).unwrap_err()
+    test_with_runtime(Runtime::new(), input).unwrap_err()
 }
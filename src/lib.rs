@@ -0,0 +1,12 @@
+pub mod util;
+pub mod parsing;
+pub mod compiling;
+pub mod eval;
+pub mod runtime;
+pub mod debugger;
+pub mod disasm;
+
+#[cfg(test)]
+mod ir_tests;
+
+pub use runtime::{ Runtime, Program, Error };
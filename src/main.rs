@@ -1,59 +1,168 @@
+use dne::{ Runtime, Program, Error };
+use dne::debugger::Debugger;
+use dne::disasm;
+use dne::parsing::ir_printer;
 
-mod util;
-mod parsing;
-mod compiling;
-mod eval;
-
-#[cfg(test)]
-mod ir_tests;
+const USAGE : &str = "usage: dne [-O] file+ | dne build [-O] file+ -o out.dnebc | dne debug file+ | dne disasm [-O] [--all] file+ | dne fmt file+ | dne file.dnebc";
 
 fn main() {
 
-    let args = std::env::args().into_iter().skip(1).collect::<Vec<_>>();
-
-    if args.len() == 0 {
-        println!("usage: dne file+");
-    }
-    else {
-
-        let mut ir = vec![];
-        for path in args {
-            use crate::parsing::ir_parser::ParseError;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
 
-            let contents = match std::fs::read_to_string(&path) {
+    match args.first().map(|x| x.as_str()) {
+        None => { println!("{USAGE}"); },
+        Some("build") => {
+            let (args, optimise) = optimise_flag(&args[1..]);
+            build(&args, optimise);
+        },
+        Some("debug") if args.len() > 1 => { debug(&args[1..]); },
+        Some("disasm") if args.len() > 1 => {
+            let (args, optimise) = optimise_flag(&args[1..]);
+            disasm(&args, optimise);
+        },
+        Some("fmt") if args.len() > 1 => { fmt(&args[1..]); },
+        Some(x) if x.ends_with(".dnebc") && args.len() == 1 => {
+            let bytes = match std::fs::read(x) {
                 Ok(x) => x,
-                Err(x) => {
-                    panic!("error reading {path}:\n\n{x}");
-                },
+                Err(e) => { panic!("error reading {x}:\n\n{e}"); },
             };
-            let mut x = match parsing::ir_parser::parse(&contents) {
+            let program = match Runtime::new().load_bytecode(&bytes) {
                 Ok(x) => x,
-                Err(ParseError::Lex(x)) => {
-                    let x = crate::util::underline(&contents, x, x);
-                    panic!("encountered lexer error:\n\n{x}");
-                },
-                Err(ParseError::Fatal(x, y)) => {
-                    let x = crate::util::underline(&contents, x, y);
-                    panic!("encountered parser error:\n\n{x}");
-                },
                 Err(x) => { panic!("{x}"); },
             };
-            ir.append(&mut x);
-        }
-        
-        let procs = match compiling::ir_compiler::compile(&ir) {
-            Ok(x) => x,
-            Err(x) => { panic!("{x}"); }, 
-        };
+            run(program);
+        },
+        Some(_) => {
+            let (paths, optimise) = optimise_flag(&args);
+            let mut runtime = load(&paths);
+            runtime.set_optimise(optimise);
+            let program = match runtime.compile() {
+                Ok(x) => x,
+                Err(x) => { panic!("{x}"); },
+            };
+            run(program);
+        },
+    }
+}
+
+/// Takes -O out of the args of a command that compiles.  Other commands see it as a path.
+fn optimise_flag(args : &[String]) -> (Vec<String>, bool) {
+    let optimise = args.iter().any(|x| x == "-O");
+    (args.iter().filter(|x| *x != "-O").cloned().collect(), optimise)
+}
+
+fn build(args : &[String], optimise : bool) {
+    let (inputs, output) = match args.iter().position(|x| x == "-o") {
+        Some(i) if i + 1 < args.len() => {
+            let inputs = args[..i].iter().chain(args[i + 2..].iter()).cloned().collect::<Vec<_>>();
+            (inputs, &args[i + 1])
+        },
+        _ => {
+            println!("{USAGE}");
+            return;
+        },
+    };
+
+    if inputs.is_empty() {
+        println!("{USAGE}");
+        return;
+    }
+
+    let mut runtime = load(&inputs);
+    runtime.set_optimise(optimise);
+    let bytes = match runtime.build() {
+        Ok(x) => x,
+        Err(x) => { panic!("{x}"); },
+    };
+
+    if let Err(x) = std::fs::write(output, bytes) {
+        panic!("error writing {output}:\n\n{x}");
+    }
+}
+
+fn debug(paths : &[String]) {
+    let program = match load(paths).compile() {
+        Ok(x) => x,
+        Err(x) => { panic!("{x}"); },
+    };
 
-        let main = procs.iter().enumerate().find(|(_, x)| *"main" == *x.name ).expect("cannot find main").0;
-        let mut vm = eval::vm::Vm::new(procs);  
+    let mut debugger = Debugger::new(program);
+    if let Err(x) = debugger.start("main") {
+        panic!("{x}");
+    }
+
+    if let Err(x) = debugger.repl(std::io::stdin().lock(), std::io::stdout()) {
+        panic!("{x}");
+    }
+}
+
+fn disasm(args : &[String], optimise : bool) {
+    let all = args.iter().any(|x| x == "--all");
+    let paths = args.iter().filter(|x| *x != "--all").cloned().collect::<Vec<_>>();
+
+    let program = match &paths[..] {
+        [x] if x.ends_with(".dnebc") => {
+            let bytes = match std::fs::read(x) {
+                Ok(x) => x,
+                Err(e) => { panic!("error reading {x}:\n\n{e}"); },
+            };
+            Runtime::new().load_bytecode(&bytes)
+        },
+        [] => {
+            println!("{USAGE}");
+            return;
+        },
+        _ => {
+            let mut runtime = load(&paths);
+            runtime.set_optimise(optimise);
+            runtime.compile()
+        },
+    };
+
+    match program {
+        Ok(x) => { print!("{}", disasm::disassemble(x.vm().procs(), all)); },
+        Err(x) => { panic!("{x}"); },
+    }
+}
 
-        let result = match vm.run(main) {
+fn fmt(paths : &[String]) {
+    // Note:  Every file is formatted before any is written, so that a parse error leaves all of
+    // them as they were.
+    let mut outputs = vec![];
+    for path in paths {
+        let text = match std::fs::read_to_string(path) {
             Ok(x) => x,
-            Err(x) => { panic!("{x}"); },
+            Err(e) => { panic!("error reading {path}:\n\n{e}"); },
         };
-         
-        println!("{:?}", result);
+        match ir_printer::format(&text) {
+            Ok(x) if x != text => { outputs.push((path, x)); },
+            Ok(_) => { },
+            Err(error) => { panic!("{}", Error::Parse { file: path.as_str().into(), text: text.into(), error }); },
+        }
+    }
+
+    for (path, output) in outputs {
+        if let Err(x) = std::fs::write(path, output) {
+            panic!("error writing {path}:\n\n{x}");
+        }
     }
 }
+
+fn load(paths : &[String]) -> Runtime {
+    let mut runtime = Runtime::new();
+    for path in paths {
+        if let Err(x) = runtime.load_file(path) {
+            panic!("{x}");
+        }
+    }
+    runtime
+}
+
+fn run(mut program : Program) {
+    let result = match program.run("main", vec![]) {
+        Ok(x) => x,
+        Err(x) => { panic!("{x}"); },
+    };
+
+    println!("{:?}", result);
+}
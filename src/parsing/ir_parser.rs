@@ -1,5 +1,6 @@
 
 use std::rc::Rc;
+use crate::util::{ Span, Source };
 use super::lexer::ir::{self, Token};
 
 type Input = super::parse_input::Input<Token, ParseError>;
@@ -23,26 +24,74 @@ impl std::fmt::Display for ParseError {
 
 impl std::error::Error for ParseError { }
 
+/// Equality only looks at what the proc says, not where it came from, so spans and source are
+/// left out.
 #[derive(Debug)]
 pub struct Proc {
     pub name: Rc<str>, 
     pub params: Vec<(Rc<str>, Type)>, 
     pub return_type : Type, 
     pub body : Vec<Stmt>,
+    /// Source span of each statement in body.  Empty for procs that did not come from text.
+    pub spans : Vec<Span>,
+    pub source : Option<Rc<Source>>,
+    /// Procs in other files can only call this one when it is pub.
+    pub public : bool,
+    /// Namespaces that the proc's file imports.  Qualified calls can only go to these.
+    pub imports : Vec<Rc<str>>,
 }
 
+impl PartialEq for Proc {
+    fn eq(&self, other : &Self) -> bool {
+        self.name == other.name
+        && self.params == other.params
+        && self.return_type == other.return_type
+        && self.body == other.body
+        && self.public == other.public
+        && self.imports == other.imports
+    }
+}
+
+/// Everything in one ir file.  Like Proc, equality leaves out the spans.
 #[derive(Debug)]
+pub struct Module {
+    /// Import paths as written, relative to the importing file.
+    pub imports : Vec<Rc<str>>,
+    pub procs : Vec<Proc>,
+    /// Source span of each import.
+    pub import_spans : Vec<Span>,
+    /// Source span of each proc, from pub or proc to the closing brace.
+    pub proc_spans : Vec<Span>,
+}
+
+impl PartialEq for Module {
+    fn eq(&self, other : &Self) -> bool {
+        self.imports == other.imports && self.procs == other.procs
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Stmt {
     Set { var: Rc<str>, ttype : Type, val: Expr },
     Jump(Rc<str>),
     BranchTrue { label: Rc<str>, var: Rc<str> },
+    /// Until the matching EndTry, a throw jumps to label with the thrown value in var.
+    Try { label: Rc<str>, var: Rc<str> },
+    EndTry,
+    Throw(Rc<str>),
     Return(Rc<str>),
     Yield(Rc<str>),
     Break,
     Label(Rc<str>),
     SlotInsert { var: Rc<str>, input: Rc<str>, index: usize },
     SlotRemove { var: Rc<str>, index: usize },
+    DynSlotInsert { var: Rc<str>, input: Rc<str>, index: Rc<str> },
+    DynSlotRemove { var: Rc<str>, index: Rc<str> },
     Delete(Rc<str>),
+    ArrayPush { var: Rc<str>, input: Rc<str> },
+    ArraySet { var: Rc<str>, index: Rc<str>, input: Rc<str> },
+    MapInsert { var: Rc<str>, key: Rc<str>, input: Rc<str> },
+    MapRemove { var: Rc<str>, key: Rc<str> },
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -57,7 +106,7 @@ pub enum Type {
     Coroutine,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Lit {
     Int(i64),
     Float(f64),
@@ -66,7 +115,7 @@ pub enum Lit {
     String(Rc<str>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Expr { 
     Lit(Lit), 
     Call { name : Rc<str>, params : Vec<Rc<str>> },
@@ -80,36 +129,92 @@ pub enum Expr {
     Type(Rc<str>),
     Var(Rc<str>),
     Slot { var: Rc<str>, index: usize },
+    DynSlot { var: Rc<str>, index: Rc<str> },
     IsNil(Rc<str>),
     ToString(Rc<str>),
     Concat(Rc<str>, Rc<str>),
+    Array(Vec<Rc<str>>),
+    ArrayPop(Rc<str>),
+    ArrayGet { var: Rc<str>, index: Rc<str> },
+    MapNew,
+    MapGet { var: Rc<str>, key: Rc<str> },
+    MapContains { var: Rc<str>, key: Rc<str> },
 }
 
 pub fn parse(input : &str) -> Result<Vec<Proc>, ParseError> {
+    Ok(parse_module(input)?.procs)
+}
+
+pub fn parse_file(file : &str, input : &str) -> Result<Vec<Proc>, ParseError> {
+    Ok(parse_file_module(file, input)?.procs)
+}
+
+pub fn parse_module(input : &str) -> Result<Module, ParseError> {
     let input = match ir::lex(input) {
         Err(i) => { return Err(ParseError::Lex(i)); },
         Ok(ls) => ls,
     };
     let mut input = Input::new(input, ParseError::Eof, |s, e| ParseError::Fatal(s, e));
 
-    parse_procs(&mut input)
+    let module = parse_top_level(&mut input)?;
+    let namespaces = module.imports.iter().map(|x| namespace(x)).collect::<Vec<_>>();
+    let procs = module.procs.into_iter().map(|proc| Proc { imports: namespaces.clone(), ..proc }).collect();
+    Ok(Module { procs, ..module })
 }
 
-fn parse_procs(input : &mut Input) -> Result<Vec<Proc>, ParseError> {
-    let mut ret = vec![];
+pub fn parse_file_module(file : &str, input : &str) -> Result<Module, ParseError> {
+    let source = Rc::new(Source { file: file.into(), text: input.into() });
+    let mut module = parse_module(input)?;
+    for proc in &mut module.procs {
+        proc.source = Some(Rc::clone(&source));
+    }
+    Ok(module)
+}
+
+/// The namespace that an imported file's procs go into, which is its file name without the
+/// extension.
+pub fn namespace(path : &str) -> Rc<str> {
+    match std::path::Path::new(path).file_stem() {
+        Some(x) => x.to_string_lossy().into(),
+        None => path.into(),
+    }
+}
+
+fn parse_top_level(input : &mut Input) -> Result<Module, ParseError> {
+    let mut imports = vec![];
+    let mut procs = vec![];
+    let mut import_spans = vec![];
+    let mut proc_spans = vec![];
     while !input.empty() {
-        if input.check(|x| x.eq(&Token::Proc))? {
-            ret.push(parse_proc(input)?);
+        let (start, _) = input.current()?;
+        if input.check(|x| x.eq(&Token::Import))? {
+            let (s, e) = input.current()?;
+            let path = match input.take()? {
+                Token::String(x) => x,
+                _ => { return Err(ParseError::Fatal(s, e)); },
+            };
+            input.expect(|x| x.eq(&Token::SemiColon))?;
+            imports.push(path);
+            import_spans.push(Span { start, end: input.last_end() });
+        }
+        else if input.check(|x| x.eq(&Token::Pub))? {
+            input.expect(|x| x.eq(&Token::Proc))?;
+            procs.push(parse_proc(input, true)?);
+            proc_spans.push(Span { start, end: input.last_end() });
+        }
+        else if input.check(|x| x.eq(&Token::Proc))? {
+            procs.push(parse_proc(input, false)?);
+            proc_spans.push(Span { start, end: input.last_end() });
         }
         else {
             let (s, e) = input.current()?;
             return Err(ParseError::Fatal(s, e));
         }
     }
-    Ok(ret)
+    Ok(Module { imports, procs, import_spans, proc_spans })
 }
 
-fn parse_proc(input : &mut Input) -> Result<Proc, ParseError> {
+fn parse_proc(input : &mut Input, public : bool) -> Result<Proc, ParseError> {
     let name = expect_sym(input)?;
     input.expect(|x| x.eq(&Token::LParen))?;
     let mut params = vec![];
@@ -135,14 +240,16 @@ fn parse_proc(input : &mut Input) -> Result<Proc, ParseError> {
     input.expect(|x| x.eq(&Token::Arrow))?;
     let return_type = parse_type(input)?;
     input.expect(|x| x.eq(&Token::LCurl))?;
-    let body = parse_stmts(input)?;
+    let (body, spans) = parse_stmts(input)?;
     input.expect(|x| x.eq(&Token::RCurl))?;
-    Ok( Proc{ name, params, return_type, body })
+    Ok( Proc{ name, params, return_type, body, spans, source: None, public, imports: vec![] })
 }
 
-fn parse_stmts(input : &mut Input) -> Result<Vec<Stmt>, ParseError> {
+fn parse_stmts(input : &mut Input) -> Result<(Vec<Stmt>, Vec<Span>), ParseError> {
     let mut ret = vec![];
+    let mut spans = vec![];
     loop {
+        let (start, _) = input.current()?;
         if input.check(|x| x.eq(&Token::Set))? {
             ret.push(parse_set(input)?);
         }
@@ -151,6 +258,21 @@ fn parse_stmts(input : &mut Input) -> Result<Vec<Stmt>, ParseError> {
             input.expect(|x| x.eq(&Token::SemiColon))?;
             ret.push(Stmt::Jump(r));
         }
+        else if input.check(|x| x.eq(&Token::Try))? {
+            let label = expect_sym(input)?;
+            let var = expect_sym(input)?;
+            input.expect(|x| x.eq(&Token::SemiColon))?;
+            ret.push(Stmt::Try { label, var });
+        }
+        else if input.check(|x| x.eq(&Token::EndTry))? {
+            input.expect(|x| x.eq(&Token::SemiColon))?;
+            ret.push(Stmt::EndTry);
+        }
+        else if input.check(|x| x.eq(&Token::Throw))? {
+            let var = expect_sym(input)?;
+            input.expect(|x| x.eq(&Token::SemiColon))?;
+            ret.push(Stmt::Throw(var));
+        }
         else if input.check(|x| x.eq(&Token::BranchTrue))? {
             let label = expect_sym(input)?;
             let var = expect_sym(input)?;
@@ -179,24 +301,65 @@ fn parse_stmts(input : &mut Input) -> Result<Vec<Stmt>, ParseError> {
         else if input.check(|x| x.eq(&Token::SlotInsert))? {
             let var = expect_sym(input)?;
             let var_input = expect_sym(input)?;
-            let index = expect_index(input)?;
-            input.expect(|x| x.eq(&Token::SemiColon))?;
-            ret.push(Stmt::SlotInsert { var, input: var_input, index })
+            if matches!(input.peek()?, Token::Symbol(_)) {
+                let index = expect_sym(input)?;
+                input.expect(|x| x.eq(&Token::SemiColon))?;
+                ret.push(Stmt::DynSlotInsert { var, input: var_input, index })
+            }
+            else {
+                let index = expect_index(input)?;
+                input.expect(|x| x.eq(&Token::SemiColon))?;
+                ret.push(Stmt::SlotInsert { var, input: var_input, index })
+            }
         }
         else if input.check(|x| x.eq(&Token::SlotRemove))? {
             let var = expect_sym(input)?;
-            let index = expect_index(input)?;
-            input.expect(|x| x.eq(&Token::SemiColon))?;
-            ret.push(Stmt::SlotRemove { var, index })
+            if matches!(input.peek()?, Token::Symbol(_)) {
+                let index = expect_sym(input)?;
+                input.expect(|x| x.eq(&Token::SemiColon))?;
+                ret.push(Stmt::DynSlotRemove { var, index })
+            }
+            else {
+                let index = expect_index(input)?;
+                input.expect(|x| x.eq(&Token::SemiColon))?;
+                ret.push(Stmt::SlotRemove { var, index })
+            }
         }
         else if input.check(|x| x.eq(&Token::Delete))? {
             let var = expect_sym(input)?;
             input.expect(|x| x.eq(&Token::SemiColon))?;
             ret.push(Stmt::Delete(var));
         }
+        else if input.check(|x| x.eq(&Token::ArrayPush))? {
+            let var = expect_sym(input)?;
+            let var_input = expect_sym(input)?;
+            input.expect(|x| x.eq(&Token::SemiColon))?;
+            ret.push(Stmt::ArrayPush { var, input: var_input });
+        }
+        else if input.check(|x| x.eq(&Token::ArraySet))? {
+            let var = expect_sym(input)?;
+            let index = expect_sym(input)?;
+            let var_input = expect_sym(input)?;
+            input.expect(|x| x.eq(&Token::SemiColon))?;
+            ret.push(Stmt::ArraySet { var, index, input: var_input });
+        }
+        else if input.check(|x| x.eq(&Token::MapInsert))? {
+            let var = expect_sym(input)?;
+            let key = expect_sym(input)?;
+            let var_input = expect_sym(input)?;
+            input.expect(|x| x.eq(&Token::SemiColon))?;
+            ret.push(Stmt::MapInsert { var, key, input: var_input });
+        }
+        else if input.check(|x| x.eq(&Token::MapRemove))? {
+            let var = expect_sym(input)?;
+            let key = expect_sym(input)?;
+            input.expect(|x| x.eq(&Token::SemiColon))?;
+            ret.push(Stmt::MapRemove { var, key });
+        }
         else {
-            return Ok(ret);
+            return Ok((ret, spans));
         }
+        spans.push(Span { start, end: input.last_end() });
     }
 }
 
@@ -242,7 +405,7 @@ fn parse_expr(input : &mut Input) -> Result<Expr, ParseError> {
         Ok(Expr::Lit(Lit::String(x)))
     }
     else if input.check(|x| x.eq(&Token::Call))? {
-        let name = expect_sym(input)?;
+        let name = expect_proc_name(input)?;
         let params = expect_params(input)?;
         Ok(Expr::Call { name, params })
     }
@@ -252,7 +415,7 @@ fn parse_expr(input : &mut Input) -> Result<Expr, ParseError> {
         Ok(Expr::DynCall { name, params })
     }
     else if input.check(|x| x.eq(&Token::Coroutine))? {
-        let name = expect_sym(input)?;
+        let name = expect_proc_name(input)?;
         let params = expect_params(input)?;
         Ok(Expr::Coroutine { name, params })
     }
@@ -262,7 +425,7 @@ fn parse_expr(input : &mut Input) -> Result<Expr, ParseError> {
         Ok(Expr::DynCoroutine { name, params })
     }
     else if input.check(|x| x.eq(&Token::Closure))? {
-        let name = expect_sym(input)?;
+        let name = expect_proc_name(input)?;
         let env = expect_params(input)?;
         Ok(Expr::Closure { name, env })
     }
@@ -282,8 +445,14 @@ fn parse_expr(input : &mut Input) -> Result<Expr, ParseError> {
     }
     else if input.check(|x| x.eq(&Token::Slot))? {
         let var = expect_sym(input)?;
-        let index = expect_index(input)?;
-        Ok(Expr::Slot { var, index })
+        if matches!(input.peek()?, Token::Symbol(_)) {
+            let index = expect_sym(input)?;
+            Ok(Expr::DynSlot { var, index })
+        }
+        else {
+            let index = expect_index(input)?;
+            Ok(Expr::Slot { var, index })
+        }
     }
     else if input.check(|x| x.eq(&Token::IsNil))? {
         let var = expect_sym(input)?;
@@ -298,6 +467,30 @@ fn parse_expr(input : &mut Input) -> Result<Expr, ParseError> {
         let var2 = expect_sym(input)?;
         Ok(Expr::Concat(var1, var2))
     }
+    else if input.check(|x| x.eq(&Token::Array))? {
+        Ok(Expr::Array(expect_params(input)?))
+    }
+    else if input.check(|x| x.eq(&Token::ArrayPop))? {
+        Ok(Expr::ArrayPop(expect_sym(input)?))
+    }
+    else if input.check(|x| x.eq(&Token::ArrayGet))? {
+        let var = expect_sym(input)?;
+        let index = expect_sym(input)?;
+        Ok(Expr::ArrayGet { var, index })
+    }
+    else if input.check(|x| x.eq(&Token::MapNew))? {
+        Ok(Expr::MapNew)
+    }
+    else if input.check(|x| x.eq(&Token::MapGet))? {
+        let var = expect_sym(input)?;
+        let key = expect_sym(input)?;
+        Ok(Expr::MapGet { var, key })
+    }
+    else if input.check(|x| x.eq(&Token::MapContains))? {
+        let var = expect_sym(input)?;
+        let key = expect_sym(input)?;
+        Ok(Expr::MapContains { var, key })
+    }
     else {
         let (s, e) = input.current()?;
         Err(ParseError::Fatal(s, e))
@@ -334,6 +527,16 @@ fn expect_sym(input : &mut Input) -> Result<Rc<str>, ParseError> {
     }
 }
 
+/// A proc name, which can be qualified with the namespace of an imported file.
+fn expect_proc_name(input : &mut Input) -> Result<Rc<str>, ParseError> {
+    let mut name = expect_sym(input)?.to_string();
+    while input.check(|x| x.eq(&Token::PathSep))? {
+        name.push_str("::");
+        name.push_str(&expect_sym(input)?);
+    }
+    Ok(name.into())
+}
+
 fn expect_params(input : &mut Input) -> Result<Vec<Rc<str>>, ParseError> {
     input.expect(|x| x.eq(&Token::LParen))?;
     let mut ret = vec![];
@@ -468,6 +671,16 @@ mod test {
         assert_eq!(output.len(), 1);
     }
 
+    #[test]
+    fn should_record_statement_spans() {
+        let input = "proc name() -> Int {\n    set x : Int = 0;\n    return x;\n}";
+
+        let output = parse(input).unwrap();
+        assert_eq!(output[0].spans, vec![Span { start: 25, end: 40 }, Span { start: 46, end: 54 }]);
+        assert_eq!(&input[25..=40], "set x : Int = 0;");
+        assert_eq!(&input[46..=54], "return x;");
+    }
+
     #[test]
     fn should_parse_string() {
         let input = r#"proc name() -> String {
@@ -479,6 +692,39 @@ mod test {
         assert_eq!(output.len(), 1);
     }
 
+    #[test]
+    fn should_parse_imports_and_pub() {
+        let input = r#"
+            import "lib/list.ir";
+            pub proc name(x : Int) -> Int { set y : Int = call list::double(x); return y; }
+            proc other(x : Int) -> Int { return x; }
+       "#;
+
+        let output = parse_module(input).unwrap();
+        assert_eq!(output.imports, vec![Rc::from("lib/list.ir")]);
+        assert!(output.procs[0].public);
+        assert!(!output.procs[1].public);
+        assert_eq!(output.procs[1].imports, vec![Rc::from("list")]);
+        assert!(matches!(&output.procs[0].body[0], Stmt::Set { val: Expr::Call { name, .. }, .. } if &**name == "list::double"));
+    }
+
+    #[test]
+    fn should_parse_keywords_as_names() {
+        let input = r#"
+            proc try(array : Int) -> Int {
+                set map_new : Int = call try(array);
+                label throw;
+                jump throw;
+                return map_new;
+            }
+       "#;
+
+        let output = parse(input).unwrap();
+        assert_eq!(&*output[0].name, "try");
+        assert!(matches!(&output[0].body[0], Stmt::Set { var, val: Expr::Call { name, params }, .. }
+                         if &**var == "map_new" && &**name == "try" && &*params[0] == "array"));
+    }
+
     fn d(input : &str, x : Result<Vec<Proc>, ParseError>) {
         match x {
             Err(ParseError::Fatal(s, e)) => {
@@ -0,0 +1,335 @@
+
+use crate::util::Span;
+use super::lexer::ir;
+use super::ir_parser::{ self, Module, Proc, Stmt, Expr, Lit, Type, ParseError };
+
+/// Prints a module as canonical ir.  Parsing the output gives back an equal module.
+pub fn print_module(module : &Module) -> String {
+    Printer::new("", &[]).module(module)
+}
+
+/// Formats ir text, keeping its comments.  Comments on their own lines stay before the import,
+/// proc or statement that follows them, and comments after a statement stay on its line.  Blank
+/// lines between statements are kept, but runs of them become one.  A comment inside a statement
+/// is moved to its own line before the statement that follows.
+pub fn format(text : &str) -> Result<String, ParseError> {
+    let (_, comments) = ir::lex_with_comments(text).map_err(ParseError::Lex)?;
+    let module = ir_parser::parse_module(text)?;
+    Ok(Printer::new(text, &comments).module(&module))
+}
+
+pub fn print_proc(proc : &Proc) -> String {
+    let mut output = proc_header(proc);
+    output.push('\n');
+    for stmt in &proc.body {
+        output.push_str(&format!("    {}\n", print_stmt(stmt)));
+    }
+    output.push('}');
+    output
+}
+
+pub fn print_stmt(stmt : &Stmt) -> String {
+    match stmt {
+        Stmt::Set { var, ttype, val } => format!("set {var} : {} = {};", print_type(ttype), print_expr(val)),
+        Stmt::Jump(label) => format!("jump {label};"),
+        Stmt::BranchTrue { label, var } => format!("branch_true {label} {var};"),
+        Stmt::Try { label, var } => format!("try {label} {var};"),
+        Stmt::EndTry => "end_try;".to_string(),
+        Stmt::Throw(var) => format!("throw {var};"),
+        Stmt::Return(var) => format!("return {var};"),
+        Stmt::Yield(var) => format!("yield {var};"),
+        Stmt::Break => "break;".to_string(),
+        Stmt::Label(label) => format!("label {label};"),
+        Stmt::SlotInsert { var, input, index } => format!("slot_insert {var} {input} {index};"),
+        Stmt::SlotRemove { var, index } => format!("slot_remove {var} {index};"),
+        Stmt::DynSlotInsert { var, input, index } => format!("slot_insert {var} {input} {index};"),
+        Stmt::DynSlotRemove { var, index } => format!("slot_remove {var} {index};"),
+        Stmt::Delete(var) => format!("delete {var};"),
+        Stmt::ArrayPush { var, input } => format!("array_push {var} {input};"),
+        Stmt::ArraySet { var, index, input } => format!("array_set {var} {index} {input};"),
+        Stmt::MapInsert { var, key, input } => format!("map_insert {var} {key} {input};"),
+        Stmt::MapRemove { var, key } => format!("map_remove {var} {key};"),
+    }
+}
+
+pub fn print_expr(expr : &Expr) -> String {
+    match expr {
+        Expr::Lit(Lit::Int(x)) => x.to_string(),
+        // Note:  Debug always writes a . or an exponent, so the lexer reads it back as a float.
+        Expr::Lit(Lit::Float(x)) => format!("{x:?}"),
+        Expr::Lit(Lit::Bool(x)) => x.to_string(),
+        Expr::Lit(Lit::ConsType(x)) => format!("~{x}"),
+        Expr::Lit(Lit::String(x)) => print_string(x),
+        Expr::Call { name, params } => format!("call {name}({})", params.join(", ")),
+        Expr::DynCall { name, params } => format!("dyn_call {name}({})", params.join(", ")),
+        Expr::Coroutine { name, params } => format!("coroutine {name}({})", params.join(", ")),
+        Expr::DynCoroutine { name, params } => format!("dyn_coroutine {name}({})", params.join(", ")),
+        Expr::Closure { name, env } => format!("closure {name}({})", env.join(", ")),
+        Expr::Cons { name, params } => format!("cons {name} ({})", params.join(", ")),
+        Expr::Resume(var) => format!("resume {var}"),
+        Expr::Length(var) => format!("length {var}"),
+        Expr::Type(var) => format!("type {var}"),
+        Expr::Var(var) => var.to_string(),
+        Expr::Slot { var, index } => format!("slot {var} {index}"),
+        Expr::DynSlot { var, index } => format!("slot {var} {index}"),
+        Expr::IsNil(var) => format!("is_nil {var}"),
+        Expr::ToString(var) => format!("to_string {var}"),
+        Expr::Concat(a, b) => format!("concat {a} {b}"),
+        Expr::Array(params) => format!("array ({})", params.join(", ")),
+        Expr::ArrayPop(var) => format!("array_pop {var}"),
+        Expr::ArrayGet { var, index } => format!("array_get {var} {index}"),
+        Expr::MapNew => "map_new".to_string(),
+        Expr::MapGet { var, key } => format!("map_get {var} {key}"),
+        Expr::MapContains { var, key } => format!("map_contains {var} {key}"),
+    }
+}
+
+fn print_type(ttype : &Type) -> &'static str {
+    match ttype {
+        Type::Int => "Int",
+        Type::Float => "Float",
+        Type::String => "String",
+        Type::Bool => "Bool",
+        Type::Symbol => "Symbol",
+        Type::Ref => "Ref",
+        Type::Closure => "Closure",
+        Type::Coroutine => "Coroutine",
+    }
+}
+
+/// Escapes the same characters that the lexer unescapes.
+fn print_string(s : &str) -> String {
+    let mut output = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\0' => output.push_str("\\0"),
+            '\t' => output.push_str("\\t"),
+            '\r' => output.push_str("\\r"),
+            '\n' => output.push_str("\\n"),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+fn proc_header(proc : &Proc) -> String {
+    let params = proc.params.iter().map(|(name, ttype)| format!("{name} : {}", print_type(ttype))).collect::<Vec<_>>();
+    let public = if proc.public { "pub " } else { "" };
+    format!("{public}proc {}({}) -> {} {{", proc.name, params.join(", "), print_type(&proc.return_type))
+}
+
+/// Writes out a module while placing the comments that came with its text.
+struct Printer<'a> {
+    text : &'a str,
+    comments : &'a [ir::Comment],
+    next_comment : usize,
+    /// End of the last thing printed from the text, for finding blank lines.
+    last_end : Option<usize>,
+    output : String,
+}
+
+impl<'a> Printer<'a> {
+    fn new(text : &'a str, comments : &'a [ir::Comment]) -> Self {
+        Printer { text, comments, next_comment: 0, last_end: None, output: String::new() }
+    }
+
+    fn module(mut self, module : &Module) -> String {
+        for (i, import) in module.imports.iter().enumerate() {
+            let span = module.import_spans.get(i);
+            self.line("", &format!("import {};", print_string(import)), span);
+        }
+
+        for (i, proc) in module.procs.iter().enumerate() {
+            if i > 0 || !module.imports.is_empty() {
+                self.output.push('\n');
+                self.last_end = None;
+            }
+            let span = module.proc_spans.get(i);
+            if let Some(span) = span {
+                self.comments_before(span.start, "");
+                self.blank_line(span.start);
+            }
+            self.output.push_str(&proc_header(proc));
+            self.output.push('\n');
+
+            self.last_end = None;
+            for (stmt, stmt_span) in proc.body.iter().enumerate().map(|(i, x)| (x, proc.spans.get(i))) {
+                self.line("    ", &print_stmt(stmt), stmt_span);
+            }
+            if let Some(span) = span {
+                self.comments_before(span.end, "    ");
+            }
+            self.line("", "}", span.map(|x| Span { start: x.end, end: x.end }).as_ref());
+        }
+
+        self.comments_before(usize::MAX, "");
+        self.output
+    }
+
+    /// Prints one item on its own line, along with the comments before it and after it on the
+    /// same line.
+    fn line(&mut self, indent : &str, item : &str, span : Option<&Span>) {
+        if let Some(span) = span {
+            self.comments_before(span.start, indent);
+            self.blank_line(span.start);
+        }
+
+        self.output.push_str(indent);
+        self.output.push_str(item);
+
+        if let Some(span) = span {
+            while let Some((comment_span, text)) = self.comments.get(self.next_comment)
+                && comment_span.start > span.end
+                && !self.text[span.end..comment_span.start].contains('\n') {
+
+                self.output.push(' ');
+                self.output.push_str(text);
+                self.last_end = Some(comment_span.end);
+                self.next_comment += 1;
+            }
+            self.last_end = Some(self.last_end.map_or(span.end, |x| x.max(span.end)));
+        }
+        self.output.push('\n');
+    }
+
+    fn comments_before(&mut self, start : usize, indent : &str) {
+        while let Some((span, text)) = self.comments.get(self.next_comment) && span.start < start {
+            self.blank_line(span.start);
+            self.output.push_str(indent);
+            self.output.push_str(text);
+            self.output.push('\n');
+            self.last_end = Some(span.end);
+            self.next_comment += 1;
+        }
+    }
+
+    fn blank_line(&mut self, start : usize) {
+        if let Some(end) = self.last_end
+            && let Some(gap) = self.text.get(end + 1..start)
+            && gap.matches('\n').count() >= 2 {
+
+            self.output.push('\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::ir_parser::parse_module;
+
+    fn round_trip(input : &str) {
+        let module = parse_module(input).unwrap();
+        let printed = print_module(&module);
+        assert_eq!(parse_module(&printed).unwrap(), module, "{printed}");
+    }
+
+    #[test]
+    fn should_round_trip_every_stmt_and_expr() {
+        round_trip(r#"
+            import "lib/list.ir";
+            pub proc name(x : Int, y : Float) -> Int {
+                set a : Int = -3;
+                set b : Float = -1.5;
+                set c : Float = 0.0000001;
+                set d : Bool = false;
+                set e : Symbol = ~Sym;
+                set f : String = "a \"b\" \\ \n \t \r \0 c";
+                set g : Int = call list::double(x);
+                set h : Int = dyn_call f(x, y);
+                set i : Coroutine = coroutine name(x, y);
+                set j : Coroutine = dyn_coroutine f();
+                set k : Closure = closure name(x);
+                set l : Ref = cons e (x, y);
+                set m : Int = resume i;
+                set n : Int = length l;
+                set o : Symbol = type l;
+                set p : Int = x;
+                set q : Int = slot l 0;
+                set r : Int = slot l x;
+                set s : Bool = is_nil q;
+                set t : String = to_string x;
+                set u : String = concat f t;
+                set v : Ref = array (x, y);
+                set w : Ref = array ();
+                set z : Int = array_pop v;
+                set z : Int = array_get v x;
+                set mm : Ref = map_new;
+                set z : Int = map_get mm x;
+                set s : Bool = map_contains mm x;
+                jump end;
+                branch_true end s;
+                try end z;
+                end_try;
+                throw z;
+                yield z;
+                break;
+                label end;
+                slot_insert l x 1;
+                slot_remove l 0;
+                slot_insert l x y;
+                slot_remove l y;
+                delete l;
+                array_push v x;
+                array_set v x y;
+                map_insert mm x y;
+                map_remove mm x;
+                return z;
+            }
+            proc other() -> Bool { set x : Bool = true; return x; }
+        "#);
+    }
+
+    #[test]
+    fn should_print_canonical_layout() {
+        let module = parse_module("proc  main( x:Int )->Int{set y:Int=call add_int(x,x);return y;}").unwrap();
+        assert_eq!(print_module(&module), "proc main(x : Int) -> Int {\n    set y : Int = call add_int(x, x);\n    return y;\n}\n");
+    }
+
+    #[test]
+    fn should_keep_comments_and_blank_lines() {
+        let input = r#"// header
+import "list.ir"; // why
+
+/* main */
+proc main() -> Int {
+        // leading
+    set x : Int = 1;   // trailing
+
+
+    return x;
+    // before end
+} // after end
+// at end
+"#;
+        let expected = r#"// header
+import "list.ir"; // why
+
+/* main */
+proc main() -> Int {
+    // leading
+    set x : Int = 1; // trailing
+
+    return x;
+    // before end
+} // after end
+// at end
+"#;
+        let output = format(input).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(format(&output).unwrap(), output);
+        assert_eq!(parse_module(&output).unwrap(), parse_module(input).unwrap());
+    }
+
+    #[test]
+    fn should_move_comment_inside_statement_before_next() {
+        let input = "proc main() -> Int {\n    set x /* c */ : Int = 1;\n    return x;\n}\n";
+        let expected = "proc main() -> Int {\n    set x : Int = 1;\n    /* c */\n    return x;\n}\n";
+        let output = format(input).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(format(&output).unwrap(), output);
+    }
+}
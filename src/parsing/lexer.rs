@@ -3,6 +3,8 @@ use std::rc::Rc;
 use std::str::CharIndices;
 use std::iter::Peekable;
 
+use crate::util::Span;
+
 type Input<'a> = Peekable<CharIndices<'a>>;
 
 pub mod ir {
@@ -51,34 +53,74 @@ pub mod ir {
         IsNil,
         ToString,
         Concat,
+        Array,
+        ArrayPush,
+        ArrayPop,
+        ArrayGet,
+        ArraySet,
+        MapNew,
+        MapGet,
+        MapInsert,
+        MapRemove,
+        MapContains,
+        Try,
+        EndTry,
+        Throw,
+        Pub,
+        Import,
+        PathSep,
     }
 
     pub fn lex(input : &str) -> Result<Vec<(Token, usize, usize)>, usize> {
+        Ok(lex_with_comments(input)?.0)
+    }
+
+    /// A comment's span and its text, including the comment markers.
+    pub type Comment = (Span, Rc<str>);
+
+    pub type Lexed = (Vec<(Token, usize, usize)>, Vec<Comment>);
+
+    /// Like lex, but also returns the comments, which lex drops.
+    pub fn lex_with_comments(input : &str) -> Result<Lexed, usize> {
         macro_rules! punct {
             ($input:ident, $ret:ident, $i:ident, $t:expr) => { { let i = *$i; $input.next().unwrap(); $ret.push(($t, i, i)); } }
         }
 
+        let text = input;
         let max = input.len();
         let mut input = input.char_indices().peekable();
         let mut ret : Vec<(Token, usize, usize)> = vec![];
-        let mut comment = false;
+        let mut comments : Vec<(Span, Rc<str>)> = vec![];
+        // Note:  Start of the comment once its first / has been seen.
+        let mut comment : Option<usize> = None;
 
         loop {
             match input.peek() {
-                Some((_, '/')) if comment => { take_while(&mut input, |x| x != '\n' && x != '\r'); comment = false;  }, 
-                Some((_, '*')) if comment => { input.next().unwrap(); block_comment(&mut input, max)?; comment = false; },
-                Some((_, '/')) => { input.next().unwrap(); comment = true; },
+                Some((_, '/')) if comment.is_some() => {
+                    take_while(&mut input, |x| x != '\n' && x != '\r');
+                    comments.push(comment_text(text, comment.take().unwrap(), input.peek().map_or(max, |x| x.0)));
+                },
+                Some((_, '*')) if comment.is_some() => {
+                    input.next().unwrap();
+                    block_comment(&mut input, max)?;
+                    comments.push(comment_text(text, comment.take().unwrap(), input.peek().map_or(max, |x| x.0)));
+                },
+                Some((i, '/')) => { comment = Some(*i); input.next().unwrap(); },
                 // Note:  Incomplete comment
-                Some((i, _)) if comment => { return Err(*i); },
-                None if comment => { return Err(max); },
+                Some((i, _)) if comment.is_some() => { return Err(*i); },
+                None if comment.is_some() => { return Err(max); },
 
-                None => { return Ok(ret); },
+                None => { return Ok((ret, comments)); },
                 Some((_, c)) if c.is_whitespace() => {
                     whitespace(&mut input)?;
                 },
                 Some((s, c)) if c.is_alphabetic() || *c == '_' => {
                     let s = *s;
-                    let (t, l) = symbol(&mut input)?;
+                    // Note:  Words are only keywords where an import, proc, statement or expression
+                    // starts, so that locals, labels and procs can still be named array, try and
+                    // so on.
+                    let keyword = matches!(ret.last(), None | Some((Token::SemiColon | Token::LCurl | Token::RCurl | Token::Equal | Token::Pub, _, _)));
+                    let (t, l) = symbol(&mut input, keyword)?;
                     ret.push((t, s, s + l));
                 },
                 Some((s, c)) if c.is_numeric() || *c == '-' => { 
@@ -108,7 +150,17 @@ pub mod ir {
                 Some((i, '}')) => punct!(input, ret, i, Token::RCurl), 
                 Some((i, ',')) => punct!(input, ret, i, Token::Comma),
                 Some((i, ';')) => punct!(input, ret, i, Token::SemiColon),
-                Some((i, ':')) => punct!(input, ret, i, Token::Colon), 
+                Some((s, ':')) => {
+                    let s = *s;
+                    input.next().unwrap();
+                    match input.peek() {
+                        Some((e, ':')) => {
+                            ret.push((Token::PathSep, s, *e));
+                            input.next().unwrap();
+                        },
+                        _ => { ret.push((Token::Colon, s, s)); },
+                    }
+                },
                 Some((i, '=')) => punct!(input, ret, i, Token::Equal), 
                 Some((i, _)) => { return Err(*i); },
             }
@@ -123,12 +175,15 @@ pub mod ir {
         Ok((Token::ConsType(s.into()), l))
     }
 
-    fn symbol(input : &mut Input) -> Result<(Token, usize), usize> {
+    fn symbol(input : &mut Input, keyword : bool) -> Result<(Token, usize), usize> {
         let s = take_while(input, |c| c.is_alphanumeric() || c == '_');
         let s = s.into_iter().collect::<String>();
         let l = s.len() - 1;
 
         let r = match s.as_str() {
+            "true" => Token::Bool(true),
+            "false" => Token::Bool(false),
+            s if !keyword => Token::Symbol(s.into()),
             "type" => Token::Type,
             "slot" => Token::Slot,
             "slot_set" => Token::SlotSet,
@@ -149,18 +204,35 @@ pub mod ir {
             "call" => Token::Call,
             "dyn_call" => Token::DynCall,
             "closure" => Token::Closure,
-            "true" => Token::Bool(true),
-            "false" => Token::Bool(false),
             "cons" => Token::Cons,
             "delete" => Token::Delete,
             "is_nil" => Token::IsNil,
             "to_string" => Token::ToString,
             "concat" => Token::Concat,
+            "array" => Token::Array,
+            "array_push" => Token::ArrayPush,
+            "array_pop" => Token::ArrayPop,
+            "array_get" => Token::ArrayGet,
+            "array_set" => Token::ArraySet,
+            "map_new" => Token::MapNew,
+            "map_get" => Token::MapGet,
+            "map_insert" => Token::MapInsert,
+            "map_remove" => Token::MapRemove,
+            "map_contains" => Token::MapContains,
+            "try" => Token::Try,
+            "end_try" => Token::EndTry,
+            "throw" => Token::Throw,
+            "pub" => Token::Pub,
+            "import" => Token::Import,
             s => Token::Symbol(s.into()),
         };
 
         Ok((r, l))
     }
+
+    fn comment_text(text : &str, start : usize, next : usize) -> (Span, Rc<str>) {
+        (Span { start, end: next - 1 }, text[start..next].trim_end().into())
+    }
 }
 
 pub mod dne {
@@ -478,5 +550,34 @@ mod test {
         let output = ir::lex(input).unwrap();
         assert_eq!(output.len(), 8);
     }
+
+    #[test]
+    fn should_keep_comment_text() {
+        let input = "proc // line\n/* block /* nested */ */ x";
+        let (tokens, comments) = ir::lex_with_comments(input).unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(comments.len(), 2);
+        assert_eq!(&*comments[0].1, "// line");
+        assert_eq!(&*comments[1].1, "/* block /* nested */ */");
+        assert_eq!(&input[comments[1].0.start..=comments[1].0.end], "/* block /* nested */ */");
+    }
+
+    #[test]
+    fn should_lex_qualified_name() {
+        let input = "list::map x : Int";
+        let output = ir::lex(input).unwrap();
+        assert!(matches!(&output[..], [(Token::Symbol(_), 0, 3), (Token::PathSep, 4, 5), (Token::Symbol(_), 6, 8), (Token::Symbol(_), _, _), (Token::Colon, _, _), (Token::Symbol(_), _, _)]));
+    }
+
+    #[test]
+    fn should_lex_keywords_only_where_they_can_start() {
+        let input = "set array : Int = array (try); return array;";
+        let output = ir::lex(input).unwrap();
+        let tokens = output.into_iter().map(|(t, _, _)| t).collect::<Vec<_>>();
+        assert!(matches!(&tokens[..], [Token::Set, Token::Symbol(a), Token::Colon, Token::Symbol(_), Token::Equal, Token::Array,
+                                       Token::LParen, Token::Symbol(b), Token::RParen, Token::SemiColon,
+                                       Token::Return, Token::Symbol(c), Token::SemiColon]
+                                       if &**a == "array" && &**b == "try" && &**c == "array"));
+    }
 }
 
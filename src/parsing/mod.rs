@@ -2,4 +2,5 @@
 mod lexer;
 mod parse_input;
 pub mod ir_parser;
+pub mod ir_printer;
 pub mod dne_parser;
@@ -5,6 +5,7 @@ pub struct Input<T, E> {
     ls : Peekable<std::vec::IntoIter<(T, usize, usize)>>,
     eof : E, 
     fatal : fn(usize, usize) -> E,
+    last_end : usize,
 }
 
 impl<T, E:Clone> Input<T, E> {
@@ -13,6 +14,7 @@ impl<T, E:Clone> Input<T, E> {
             ls: input.into_iter().peekable(),
             eof,
             fatal,
+            last_end: 0,
         }
     }
     pub fn current(&mut self) -> Result<(usize, usize), E> {
@@ -24,7 +26,8 @@ impl<T, E:Clone> Input<T, E> {
     pub fn check<F:Fn(&T) -> bool>(&mut self, f : F) -> Result<bool, E> {
         match self.ls.peek() {
             Some((l, _, _)) if f(l) => {
-                self.ls.next().unwrap();
+                let (_, _, e) = self.ls.next().unwrap();
+                self.last_end = e;
                 Ok(true)
             },
             Some(_) => Ok(false),
@@ -34,7 +37,8 @@ impl<T, E:Clone> Input<T, E> {
     pub fn expect<F:Fn(&T) -> bool>(&mut self, f : F) -> Result<T, E> {
         match self.ls.peek() {
             Some((l, _, _)) if f(l) => {
-                let (l, _, _) = self.ls.next().unwrap();
+                let (l, _, e) = self.ls.next().unwrap();
+                self.last_end = e;
                 Ok(l)
             },
             Some((_, s, e)) => Err((self.fatal)(*s, *e)),
@@ -49,10 +53,17 @@ impl<T, E:Clone> Input<T, E> {
     }
     pub fn take(&mut self) -> Result<T, E> {
         match self.ls.next() {
-            Some((l, _, _)) => Ok(l),
+            Some((l, _, e)) => {
+                self.last_end = e;
+                Ok(l)
+            },
             None => Err(self.eof.clone()),
         }
     }
+    /// End position of the most recently consumed token.
+    pub fn last_end(&self) -> usize {
+        self.last_end
+    }
     pub fn empty(&mut self) -> bool {
         match self.ls.peek() {
             Some(_) => false,
@@ -0,0 +1,418 @@
+
+use std::rc::Rc;
+use std::collections::HashMap;
+
+use crate::util::underline;
+use crate::parsing::ir_parser::{ self, Type, ParseError, Proc as PProc };
+use crate::compiling::ir_compiler::{ self, CompileError };
+use crate::compiling::{ const_fold, peephole, liveness };
+use crate::eval::data::{ RuntimeData, NativeFn, Op, Proc };
+use crate::eval::error::VmError;
+use crate::eval::bytecode::{ self, BytecodeError };
+use crate::eval::vm::{ Vm, GcConfig };
+use crate::eval::io::Io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io { path: Rc<str>, error: std::io::Error },
+    Parse { file: Rc<str>, text: Rc<str>, error: ParseError },
+    Compile(CompileError),
+    Bytecode(BytecodeError),
+    Vm(VmError),
+    MissingProc(Rc<str>),
+    MissingNative(Rc<str>),
+    MissingImport { file: Rc<str>, path: Rc<str> },
+    DuplicateNamespace { namespace: Rc<str>, files: (Rc<str>, Rc<str>) },
+    ArgumentMismatch { proc: Rc<str>, expected: Vec<Type>, found: Vec<Rc<str>> },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "error reading {path}:\n\n{error}"),
+            Error::Parse { file, text, error: ParseError::Lex(x) } =>
+                write!(f, "encountered lexer error in {file}:\n\n{}", underline(text, *x, *x)),
+            Error::Parse { file, text, error: ParseError::Fatal(x, y) } =>
+                write!(f, "encountered parser error in {file}:\n\n{}", underline(text, *x, *y)),
+            Error::Parse { file, error, .. } => write!(f, "{error} in {file}"),
+            Error::Compile(x) => write!(f, "{x}"),
+            Error::Bytecode(x) => write!(f, "{x}"),
+            Error::Vm(x) => write!(f, "{x}"),
+            Error::MissingProc(name) => write!(f, "cannot find proc {name}"),
+            Error::MissingNative(name) => write!(f, "bytecode calls native {name} which is not registered"),
+            Error::MissingImport { file, path } => write!(f, "{file} imports {path} which is not loaded"),
+            Error::DuplicateNamespace { namespace, files: (a, b) } => write!(f, "{a} and {b} are both imported as namespace {namespace}"),
+            Error::ArgumentMismatch { proc, expected, found } =>
+                write!(f, "Argument mismatch for proc {proc}:  Expected {:?}, but found {:?}", expected, found),
+        }
+    }
+}
+
+impl std::error::Error for Error { }
+
+impl From<CompileError> for Error {
+    fn from(x : CompileError) -> Self { Error::Compile(x) }
+}
+
+impl From<BytecodeError> for Error {
+    fn from(x : BytecodeError) -> Self { Error::Bytecode(x) }
+}
+
+impl From<VmError> for Error {
+    fn from(x : VmError) -> Self { Error::Vm(x) }
+}
+
+/// Collects ir sources and native functions and compiles them into a runnable program.
+pub struct Runtime {
+    native_sigs: Vec<PProc>,
+    native_fns: Vec<NativeFn>,
+    ir: Vec<PProc>,
+    /// Files loaded as imports, with the namespace their procs are in.
+    modules: Vec<(Rc<str>, Rc<str>)>,
+    /// Every import seen so far, with the file that made it.
+    imports: Vec<(Rc<str>, Rc<str>)>,
+    gc: GcConfig,
+    optimise: bool,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime::new()
+    }
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        Runtime { native_sigs: vec![], native_fns: vec![], ir: vec![], modules: vec![], imports: vec![], gc: GcConfig::default(), optimise: false }
+    }
+
+    pub fn set_gc(&mut self, gc : GcConfig) {
+        self.gc = gc;
+    }
+
+    /// Folds constants and runs the peephole optimiser over every compiled proc, and lets ir locals
+    /// share slots.
+    pub fn set_optimise(&mut self, optimise : bool) {
+        self.optimise = optimise;
+    }
+
+    /// Makes a host function callable from ir like any other proc.  The function receives
+    /// copies of the arguments, which the compiler has already checked against param_types.
+    pub fn register_native(&mut self, name : &str, param_types : Vec<Type>, return_type : Type, f : NativeFn) {
+        let params = param_types.into_iter().enumerate().map(|(i, t)| (Rc::from(format!("p{i}")), t)).collect();
+        self.native_sigs.push(PProc { name: name.into(), params, return_type, body: vec![], spans: vec![], source: None, public: true, imports: vec![] });
+        self.native_fns.push(f);
+    }
+
+    /// Parses ir text.  The file name is used for error messages and stack traces, and to find
+    /// the files it imports, which have to be loaded with load_module before compiling.
+    pub fn load(&mut self, file : &str, text : &str) -> Result<(), Error> {
+        self.add(file, text, None)?;
+        Ok(())
+    }
+
+    /// Like load, but for a file that is imported.  Its procs go into a namespace named after
+    /// the file, and are called from other files as namespace::name.
+    pub fn load_module(&mut self, file : &str, text : &str) -> Result<(), Error> {
+        self.add(file, text, Some(ir_parser::namespace(file)))?;
+        Ok(())
+    }
+
+    /// Like load, but reads the file and every file it imports from disk.
+    pub fn load_file(&mut self, path : &str) -> Result<(), Error> {
+        let imports = self.add(path, &read(path)?, None)?;
+        self.load_imports(imports)
+    }
+
+    fn load_imports(&mut self, imports : Vec<Rc<str>>) -> Result<(), Error> {
+        for path in imports {
+            if !self.modules.iter().any(|(file, _)| *file == path) {
+                let more = self.add(&path, &read(&path)?, Some(ir_parser::namespace(&path)))?;
+                self.load_imports(more)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the paths of the file's imports.
+    fn add(&mut self, file : &str, text : &str, namespace : Option<Rc<str>>) -> Result<Vec<Rc<str>>, Error> {
+        let mut module = match ir_parser::parse_file_module(file, text) {
+            Ok(x) => x,
+            Err(error) => { return Err(Error::Parse { file: file.into(), text: text.into(), error }); },
+        };
+
+        if let Some(namespace) = namespace {
+            let file = normalise(std::path::Path::new(file));
+            match self.modules.iter().find(|(_, x)| *x == namespace) {
+                Some((other, _)) if *other == file => { return Ok(vec![]); },
+                Some((other, _)) => { return Err(Error::DuplicateNamespace { namespace, files: (Rc::clone(other), file) }); },
+                None => { },
+            }
+
+            for proc in &mut module.procs {
+                proc.name = format!("{namespace}::{}", proc.name).into();
+            }
+            self.modules.push((file, namespace));
+        }
+
+        let dir = std::path::Path::new(file).parent().unwrap_or(std::path::Path::new(""));
+        let imports = module.imports.iter().map(|x| normalise(&dir.join(&**x))).collect::<Vec<_>>();
+        self.imports.extend(imports.iter().map(|x| (Rc::from(file), Rc::clone(x))));
+        self.ir.append(&mut module.procs);
+        Ok(imports)
+    }
+
+    pub fn compile(&self) -> Result<Program, Error> {
+        let procs = self.compile_procs()?;
+
+        // Note:  The natives and then the ir procs are compiled last, in the order they were given.
+        // Private procs in different files can share a name, and the first of them is kept.
+        let first = procs.len() - self.native_sigs.len() - self.ir.len();
+        let mut entries = HashMap::new();
+        for (index, sig) in self.native_sigs.iter().chain(self.ir.iter()).enumerate() {
+            entries.entry(Rc::clone(&sig.name)).or_insert((first + index, Some(sig.params.iter().map(|(_, t)| *t).collect())));
+        }
+
+        Ok(Program { vm: Vm::new(procs, self.native_fns.clone(), self.gc), entries })
+    }
+
+    /// Compiles the loaded ir into the bytecode file format.
+    pub fn build(&self) -> Result<Vec<u8>, Error> {
+        let procs = self.compile_procs()?;
+        let natives = self.native_sigs.iter().map(|x| Rc::clone(&x.name)).collect::<Vec<_>>();
+        Ok(bytecode::write(&procs, &natives)?)
+    }
+
+    fn compile_procs(&self) -> Result<Vec<Proc>, Error> {
+        if let Some((file, path)) = self.imports.iter().find(|(_, path)| !self.modules.iter().any(|(x, _)| x == path)) {
+            return Err(Error::MissingImport { file: Rc::clone(file), path: Rc::clone(path) });
+        }
+
+        let mut procs = ir_compiler::compile(&self.ir, &self.native_sigs)?;
+        if self.optimise {
+            procs.iter_mut().for_each(const_fold::fold);
+            procs.iter_mut().for_each(peephole::optimise);
+
+            // Note:  The ir procs are compiled last, after the primitives and natives.
+            let first = procs.len() - self.ir.len();
+            for (proc, sig) in procs[first..].iter_mut().zip(&self.ir) {
+                liveness::share_slots(proc, sig.params.len());
+            }
+        }
+        Ok(procs)
+    }
+
+    /// Loads a program from bytecode instead of the loaded ir.  Natives are matched up by name, 
+    /// so they do not need to be registered in the same order as when the bytecode was built.
+    /// Bytecode does not record param types, so runs of these procs only check that they exist.
+    pub fn load_bytecode(&self, bytes : &[u8]) -> Result<Program, Error> {
+        let bytecode::Bytecode { mut procs, natives } = bytecode::read(bytes)?;
+
+        let native_ids = natives.iter().map(|name| match self.native_sigs.iter().position(|x| x.name == *name) {
+            Some(x) => Ok(x),
+            None => Err(Error::MissingNative(Rc::clone(name))),
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        for op in procs.iter_mut().flat_map(|x| x.instrs.iter_mut()) {
+            if let Op::CallNative(native_id, _) = op {
+                *native_id = native_ids[*native_id];
+            }
+        }
+
+        let mut entries = HashMap::new();
+        for (index, proc) in procs.iter().enumerate() {
+            entries.entry(Rc::clone(&proc.name)).or_insert((index, None));
+        }
+
+        Ok(Program { vm: Vm::new(procs, self.native_fns.clone(), self.gc), entries })
+    }
+}
+
+/// A compiled program along with the vm that runs it.  The heap is kept between runs, but refs
+/// returned from an earlier run are not garbage collection roots for later runs.
+pub struct Program {
+    vm: Vm,
+    entries: HashMap<Rc<str>, (usize, Option<Vec<Type>>)>,
+}
+
+impl Program {
+    pub fn proc_id(&self, name : &str) -> Option<usize> {
+        self.entries.get(name).map(|(index, _)| *index)
+    }
+
+    pub fn run(&mut self, name : &str, args : Vec<RuntimeData>) -> Result<Option<RuntimeData>, Error> {
+        let entry = self.entry(name, &args)?;
+        Ok(self.vm.call(entry, args)?)
+    }
+
+    /// Like run, but stops with VmError::OutOfFuel after fuel ops.  Use continue_with_fuel to
+    /// carry on from there.
+    pub fn run_with_fuel(&mut self, name : &str, args : Vec<RuntimeData>, fuel : usize) -> Result<Option<RuntimeData>, Error> {
+        let entry = self.entry(name, &args)?;
+        self.vm.start(entry, args)?;
+        Ok(self.vm.continue_with_fuel(fuel)?)
+    }
+
+    pub fn continue_with_fuel(&mut self, fuel : usize) -> Result<Option<RuntimeData>, Error> {
+        Ok(self.vm.continue_with_fuel(fuel)?)
+    }
+
+    /// Like run, but leaves the proc to be executed one op at a time through vm_mut.
+    pub fn start(&mut self, name : &str, args : Vec<RuntimeData>) -> Result<(), Error> {
+        let entry = self.entry(name, &args)?;
+        Ok(self.vm.start(entry, args)?)
+    }
+
+    pub fn set_io(&mut self, io : Box<dyn Io>) {
+        self.vm.set_io(io);
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+
+    fn entry(&self, name : &str, args : &[RuntimeData]) -> Result<usize, Error> {
+        let (entry, params) = match self.entries.get(name) {
+            Some(x) => x,
+            None => { return Err(Error::MissingProc(name.into())); },
+        };
+
+        if let Some(params) = params && (params.len() != args.len() || !params.iter().zip(args.iter()).all(|(t, a)| has_type(a, t))) {
+            return Err(Error::ArgumentMismatch {
+                proc: name.into(),
+                expected: params.clone(),
+                found: args.iter().map(|x| format!("{:?}", x).into()).collect(),
+            });
+        }
+
+        Ok(*entry)
+    }
+}
+
+fn read(path : &str) -> Result<String, Error> {
+    std::fs::read_to_string(path).map_err(|error| Error::Io { path: path.into(), error })
+}
+
+/// Resolves the . and .. parts of a path so that the same file is found under the same name
+/// however it was imported.
+fn normalise(path : &std::path::Path) -> Rc<str> {
+    use std::path::Component;
+
+    let mut parts : Vec<Component> = vec![];
+    for part in path.components() {
+        match part {
+            Component::CurDir => { },
+            Component::ParentDir if matches!(parts.last(), Some(Component::Normal(_))) => { parts.pop(); },
+            x => { parts.push(x); },
+        }
+    }
+    parts.into_iter().collect::<std::path::PathBuf>().to_string_lossy().into()
+}
+
+fn has_type(data : &RuntimeData, ttype : &Type) -> bool {
+    matches!((data, ttype),
+        (RuntimeData::Int(_), Type::Int)
+        | (RuntimeData::Float(_), Type::Float)
+        | (RuntimeData::String(_), Type::String)
+        | (RuntimeData::Bool(_), Type::Bool)
+        | (RuntimeData::Symbol(_), Type::Symbol)
+        | (RuntimeData::Ref(_), Type::Ref)
+        | (RuntimeData::Closure(_), Type::Closure)
+        | (RuntimeData::Coroutine(_), Type::Coroutine))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::proj;
+
+    fn program(input : &str) -> Program {
+        let mut runtime = Runtime::new();
+        runtime.load("test", input).unwrap();
+        runtime.compile().unwrap()
+    }
+
+    #[test]
+    fn should_run_proc_with_args() {
+        let mut program = program(r"
+proc add(a : Int, b : Int) -> Int {
+    set ret : Int = call add_int(a, b);
+    return ret;
+}
+");
+        let output = program.run("add", vec![RuntimeData::Int(2), RuntimeData::Int(3)]).unwrap().unwrap();
+        assert_eq!(proj!(output, RuntimeData::Int(x), x), 5);
+        let output = program.run("add", vec![RuntimeData::Int(4), RuntimeData::Int(3)]).unwrap().unwrap();
+        assert_eq!(proj!(output, RuntimeData::Int(x), x), 7);
+    }
+
+    #[test]
+    fn should_error_on_missing_proc() {
+        let mut program = program("proc main() -> Int { set x : Int = 1; return x; }");
+        assert!(program.proc_id("main").is_some());
+        assert!(matches!(program.run("other", vec![]), Err(Error::MissingProc(_))));
+    }
+
+    #[test]
+    fn should_error_on_argument_mismatch() {
+        let mut program = program("proc id(x : Int) -> Int { return x; }");
+        assert!(matches!(program.run("id", vec![]), Err(Error::ArgumentMismatch { .. })));
+        assert!(matches!(program.run("id", vec![RuntimeData::Bool(true)]), Err(Error::ArgumentMismatch { .. })));
+    }
+
+    #[test]
+    fn should_run_with_fuel() {
+        let mut program = program(r"
+proc spin(x : Int) -> Int {
+    label loop;
+    jump loop;
+}
+");
+        let output = program.run_with_fuel("spin", vec![RuntimeData::Int(1)], 100);
+        assert!(matches!(output, Err(Error::Vm(VmError::OutOfFuel(_)))));
+        let output = program.continue_with_fuel(100);
+        assert!(matches!(output, Err(Error::Vm(VmError::OutOfFuel(_)))));
+    }
+
+    #[test]
+    fn should_report_parse_error_with_file() {
+        let mut runtime = Runtime::new();
+        let output = runtime.load("bad.ir", "proc main(");
+        assert!(matches!(output, Err(Error::Parse { .. })));
+    }
+
+    #[test]
+    fn should_run_built_bytecode() {
+        fn double(args : &mut [RuntimeData]) -> Result<RuntimeData, VmError> {
+            match args {
+                [RuntimeData::Int(x)] => Ok(RuntimeData::Int(*x * 2)),
+                _ => Err(VmError::NativeError("double expects an int".into(), vec![])),
+            }
+        }
+        fn unused(_ : &mut [RuntimeData]) -> Result<RuntimeData, VmError> { Ok(RuntimeData::Nil) }
+
+        let mut runtime = Runtime::new();
+        runtime.register_native("unused", vec![], Type::Int, unused);
+        runtime.register_native("double", vec![Type::Int], Type::Int, double);
+        runtime.load("test", r"
+proc main() -> Int {
+    set x : Int = 21;
+    set x : Int = call double(x);
+    return x;
+}
+").unwrap();
+        let bytes = runtime.build().unwrap();
+
+        let mut other = Runtime::new();
+        other.register_native("double", vec![Type::Int], Type::Int, double);
+        other.register_native("unused", vec![], Type::Int, unused);
+        let output = other.load_bytecode(&bytes).unwrap().run("main", vec![]).unwrap().unwrap();
+        assert_eq!(proj!(output, RuntimeData::Int(x), x), 42);
+
+        assert!(matches!(Runtime::new().load_bytecode(&bytes), Err(Error::MissingNative(_))));
+    }
+}
@@ -1,4 +1,3 @@
-
 #[macro_export]
 macro_rules! proj {
     ($input:expr, $p:pat, $output:expr) => {
@@ -11,6 +10,29 @@ macro_rules! proj {
 
 pub use proj;
 
+use std::rc::Rc;
+
+/// Byte offsets into a source file.  Like the lexer's token positions, end is inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug)]
+pub struct Source {
+    pub file: Rc<str>,
+    pub text: Rc<str>,
+}
+
+/// Returns the one based line and column of the byte offset.
+pub fn line_col(input : &str, index : usize) -> (usize, usize) {
+    let before = &input[..index.min(input.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = index - before.rfind('\n').map(|x| x + 1).unwrap_or(0) + 1;
+    (line, col)
+}
+
 pub fn underline(input : &str, start : usize, end : usize) -> String {
     let dashes = if start == end {
         "^".to_string()
@@ -39,6 +61,15 @@ pub fn underline(input : &str, start : usize, end : usize) -> String {
 mod test {
     use super::*;
 
+    #[test]
+    fn should_find_line_col() {
+        let input = "one two three four\nfive six seven\neight nine ten";
+        assert_eq!(line_col(input, 0), (1, 1));
+        assert_eq!(line_col(input, 8), (1, 9));
+        assert_eq!(line_col(input, 24), (2, 6));
+        assert_eq!(line_col(input, 34), (3, 1));
+    }
+
     #[test]
     fn should_underline_first_line() {
         let input = "one two three four\nfive six seven";